@@ -4,7 +4,12 @@ in vec2 v_tex_coords;
 out vec4 color;
 
 uniform sampler2D tex;
+uniform float window_width;
+uniform float window_level;
 
 void main() {
-    color = texture(tex, v_tex_coords);
+    // textureには元の画素値が入っているのでwindowで0 - 1にする
+    vec4 value = texture(tex, v_tex_coords);
+    float min_val = window_level - window_width / 2;
+    color = vec4((value.rgb - min_val) / window_width, 1.0);
 }
@@ -1,36 +1,102 @@
 #version 140
 
 in vec2 v_tex_coords;
-in vec2 v_mask_tex_coords;
 out vec4 color;
 
 uniform int axis;
 uniform vec3 current_pos;
 uniform sampler3D tex;
 uniform sampler3D mask;
+uniform sampler1D mask_lut;
+uniform bool use_mask_lut;
+uniform mat4 mask_texture_transform;
+// imageのtexture座標 -> texのtexture座標 (chunkごとに読むvolumeはoverviewの座標)
+uniform mat4 tex_transform;
+// chunkごとに読むvolumeの表示範囲を読んだ領域. imageのtexture座標 -> tileのtexture座標
+uniform sampler3D tile;
+uniform bool use_tile;
+uniform mat4 tile_transform;
+uniform sampler3D mask_tile;
+uniform bool use_mask_tile;
+uniform mat4 mask_tile_transform;
+// 斜めの断面: 直交する断面上のtexture座標 -> 回転した断面上のtexture座標
+uniform mat4 plane_transform;
 uniform float window_width;
 uniform float window_level;
+uniform bool show_crosshair;
 
+// 他の断面の色 (sagittal, coronal, axial)
+const vec3 PLANE_COLORS[3] = vec3[3](vec3(1.0, 1.0, 0.0), vec3(0.0, 1.0, 1.0), vec3(1.0, 0.0, 1.0));
 
-vec4 get_color(sampler3D image, vec2 tex_coords, vec3 cur_pos, int ax) {
-    if (tex_coords.x < 0.0 || tex_coords.x > 1.0 || tex_coords.y < 0.0 || tex_coords.y > 1.0) {
-        return vec4(0.0, 0.0, 0.0, 1.0);
+
+// 表示している断面上の点のtexture座標 (3D)
+vec3 get_tex_coords(vec2 tex_coords, vec3 cur_pos, int ax) {
+    if (ax == 2) {
+        return vec3(tex_coords, cur_pos.z);
+    } else if (ax == 1) {
+        return vec3(tex_coords.x, cur_pos.y, tex_coords.y);
+    } else {
+        return vec3(cur_pos.x, tex_coords);
     }
-    if (axis == 2) {
-        return texture(image, vec3(tex_coords, cur_pos.z));
-    } else if (axis == 1) {
-        return texture(image, vec3(tex_coords.x, cur_pos.y, tex_coords.y));
+}
+
+// 表示している断面の横, 縦の方向の軸
+ivec2 get_plane_axes(int ax) {
+    if (ax == 2) {
+        return ivec2(0, 1);
+    } else if (ax == 1) {
+        return ivec2(0, 2);
     } else {
-        return texture(image, vec3(cur_pos.x, tex_coords));
+        return ivec2(1, 2);
     }
 }
 
+vec4 get_color(sampler3D image, vec3 tex_coords) {
+    if (any(lessThan(tex_coords, vec3(0.0))) || any(greaterThan(tex_coords, vec3(1.0)))) {
+        return vec4(0.0, 0.0, 0.0, 1.0);
+    }
+    return texture(image, tex_coords);
+}
+
+// tileの範囲内であればtileを, 範囲外であればvolume全体のtextureをsampleする
+vec4 get_layer_color(sampler3D image, vec3 tex_coords, bool has_tile, sampler3D tile_image, vec3 tile_coords) {
+    if (has_tile && all(greaterThanEqual(tile_coords, vec3(0.0))) && all(lessThanEqual(tile_coords, vec3(1.0)))) {
+        return texture(tile_image, tile_coords);
+    }
+    return get_color(image, tex_coords);
+}
+
 void main() {
-    color = get_color(tex, v_tex_coords, current_pos, axis);
-    vec4 mask_color = get_color(mask, v_mask_tex_coords, current_pos, axis);
+    vec4 tex_coords = plane_transform * vec4(get_tex_coords(v_tex_coords, current_pos, axis), 1.0);
+    color = get_layer_color(tex, (tex_transform * tex_coords).xyz, use_tile, tile, (tile_transform * tex_coords).xyz);
+    // maskはworld座標を介してimageと同じ位置をsampleする
+    vec4 mask_color = get_layer_color(mask, (mask_texture_transform * tex_coords).xyz,
+                                      use_mask_tile, mask_tile, (mask_tile_transform * tex_coords).xyz);
     float min_val = window_level - window_width / 2;
     float val = (color.r - min_val) / (window_width);
-    color.r = max(val, mask_color.r);
-    color.gb = vec2(val);
+    if (use_mask_lut) {
+        // labelの色を半透明で重ねる
+        int label = int(mask_color.r + 0.5);
+        vec4 label_color = vec4(0.0);
+        if (label > 0 && label < textureSize(mask_lut, 0)) {
+            label_color = texelFetch(mask_lut, label, 0);
+        }
+        color.rgb = mix(vec3(val), label_color.rgb, 0.5 * label_color.a);
+    } else {
+        color.r = max(val, mask_color.r);
+        color.gb = vec2(val);
+    }
+    if (show_crosshair) {
+        // current_posを通る他の2つの断面の位置に1 pixel幅の線を引く
+        ivec2 plane_axes = get_plane_axes(axis);
+        vec2 cross_pos = vec2(current_pos[plane_axes.x], current_pos[plane_axes.y]);
+        vec2 dist = abs(v_tex_coords - cross_pos);
+        vec2 width = fwidth(v_tex_coords);
+        if (dist.x < width.x) {
+            color.rgb = PLANE_COLORS[plane_axes.x];
+        } else if (dist.y < width.y) {
+            color.rgb = PLANE_COLORS[plane_axes.y];
+        }
+    }
     color.a = 1.0;
 }
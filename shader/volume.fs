@@ -0,0 +1,150 @@
+#version 140
+
+in vec2 v_position;
+out vec4 color;
+
+// 正規化デバイス座標 -> volumeの座標 (中心が原点, 最長辺が1)
+uniform mat4 inverse_view_projection;
+uniform vec3 extent;
+// rayを進める間隔 (volumeの座標) と1 voxelの大きさ (texture座標)
+uniform float step_size;
+uniform vec3 voxel_size;
+uniform sampler3D tex;
+uniform sampler1D transfer;
+uniform float transfer_min;
+uniform float transfer_max;
+uniform bool mip;
+uniform float window_width;
+uniform float window_level;
+uniform bool has_mask;
+uniform sampler3D mask;
+uniform sampler1D mask_lut;
+uniform bool use_mask_lut;
+uniform mat4 mask_texture_transform;
+
+const int MAX_STEPS = 4096;
+// maskの境界の面の不透明度
+const float SURFACE_OPACITY = 0.5;
+const float AMBIENT = 0.3;
+
+vec3 to_tex_coords(vec3 p) {
+    return p / extent + 0.5;
+}
+
+int get_label(vec3 tex_coords) {
+    vec3 mask_tex_coords = (mask_texture_transform * vec4(tex_coords, 1.0)).xyz;
+    if (any(lessThan(mask_tex_coords, vec3(0.0))) || any(greaterThan(mask_tex_coords, vec3(1.0)))) {
+        return 0;
+    }
+    return int(texture(mask, mask_tex_coords).r + 0.5);
+}
+
+vec4 get_label_color(int label) {
+    if (!use_mask_lut) {
+        return vec4(1.0, 0.0, 0.0, 1.0);
+    }
+    if (label < textureSize(mask_lut, 0)) {
+        return texelFetch(mask_lut, label, 0);
+    }
+    return vec4(0.0);
+}
+
+vec4 get_transfer_color(float value) {
+    float t = clamp((value - transfer_min) / (transfer_max - transfer_min), 0.0, 1.0);
+    float n = float(textureSize(transfer, 0));
+    return texture(transfer, (t * (n - 1.0) + 0.5) / n);
+}
+
+// texture座標での勾配をvolumeの座標の向きにする
+vec3 to_volume_direction(vec3 gradient) {
+    return gradient / (voxel_size * extent);
+}
+
+// 視線の方向から光を当てたときの明るさ
+float get_shade(vec3 gradient, vec3 dir) {
+    vec3 g = to_volume_direction(gradient);
+    if (length(g) < 1e-6) {
+        return 1.0;
+    }
+    return AMBIENT + (1.0 - AMBIENT) * abs(dot(normalize(g), normalize(dir)));
+}
+
+vec3 get_image_gradient(vec3 p) {
+    vec3 d = voxel_size;
+    return vec3(
+        texture(tex, p + vec3(d.x, 0.0, 0.0)).r - texture(tex, p - vec3(d.x, 0.0, 0.0)).r,
+        texture(tex, p + vec3(0.0, d.y, 0.0)).r - texture(tex, p - vec3(0.0, d.y, 0.0)).r,
+        texture(tex, p + vec3(0.0, 0.0, d.z)).r - texture(tex, p - vec3(0.0, 0.0, d.z)).r
+    );
+}
+
+// `label`の領域の内側を1とした勾配
+vec3 get_label_gradient(vec3 p, int label) {
+    vec3 d = voxel_size;
+    return vec3(
+        float(get_label(p + vec3(d.x, 0.0, 0.0)) == label) - float(get_label(p - vec3(d.x, 0.0, 0.0)) == label),
+        float(get_label(p + vec3(0.0, d.y, 0.0)) == label) - float(get_label(p - vec3(0.0, d.y, 0.0)) == label),
+        float(get_label(p + vec3(0.0, 0.0, d.z)) == label) - float(get_label(p - vec3(0.0, 0.0, d.z)) == label)
+    );
+}
+
+void main() {
+    vec4 near = inverse_view_projection * vec4(v_position, -1.0, 1.0);
+    vec4 far = inverse_view_projection * vec4(v_position, 1.0, 1.0);
+    vec3 origin = near.xyz / near.w;
+    vec3 dir = far.xyz / far.w - origin;
+    // volumeの箱とrayの交差する範囲 (slab method)
+    vec3 safe_dir = mix(dir, vec3(1e-8), lessThan(abs(dir), vec3(1e-8)));
+    vec3 t0 = (-0.5 * extent - origin) / safe_dir;
+    vec3 t1 = (0.5 * extent - origin) / safe_dir;
+    vec3 t_min = min(t0, t1);
+    vec3 t_max = max(t0, t1);
+    float t_enter = max(max(t_min.x, t_min.y), max(t_min.z, 0.0));
+    float t_exit = min(min(t_max.x, t_max.y), min(t_max.z, 1.0));
+    color = vec4(0.0, 0.0, 0.0, 1.0);
+    if (t_enter >= t_exit) {
+        return;
+    }
+
+    // 手前から順に合成する
+    float dt = step_size / length(dir);
+    vec4 acc = vec4(0.0);
+    float max_value = -1e30;
+    int prev_label = 0;
+    for (int i = 0; i < MAX_STEPS; i++) {
+        float t = t_enter + (float(i) + 0.5) * dt;
+        if (t > t_exit || acc.a > 0.98) {
+            break;
+        }
+        vec3 p = to_tex_coords(origin + dir * t);
+        float value = texture(tex, p).r;
+        vec4 sample_color = vec4(0.0);
+        if (has_mask) {
+            // labelが変わる位置に面を描く
+            int label = get_label(p);
+            if (label > 0 && label != prev_label) {
+                vec4 label_color = get_label_color(label);
+                float shade = get_shade(get_label_gradient(p, label), dir);
+                sample_color = vec4(label_color.rgb * shade, SURFACE_OPACITY * label_color.a);
+            }
+            prev_label = label;
+        }
+        if (mip) {
+            max_value = max(max_value, value);
+        } else if (sample_color.a == 0.0) {
+            sample_color = get_transfer_color(value);
+            if (sample_color.a > 0.0) {
+                sample_color.rgb *= get_shade(get_image_gradient(p), dir);
+            }
+        }
+        acc.rgb += (1.0 - acc.a) * sample_color.a * sample_color.rgb;
+        acc.a += (1.0 - acc.a) * sample_color.a;
+    }
+    if (mip) {
+        // maskの面の奥に最大値を置く
+        float min_val = window_level - window_width / 2;
+        float val = clamp((max_value - min_val) / window_width, 0.0, 1.0);
+        acc.rgb += (1.0 - acc.a) * vec3(val);
+    }
+    color = vec4(acc.rgb, 1.0);
+}
@@ -1,62 +1,541 @@
 use std::fmt;
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::path::Path;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 
+use cgmath::prelude::*;
 use glium::texture::{MipmapsOption, UncompressedFloatFormat};
-use nifti::{IntoNdArray, NiftiObject};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, info};
 
+/// volumeの読み書きで起こるエラー
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    UnsupportedFormat(PathBuf),
+    UnsupportedDataType(String),
+    TruncatedData { expected: usize, actual: usize },
+    Decode(String),
+    ShapeMismatch(String),
+    TextureUpload(String),
+    Cancelled,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error : {}", e),
+            Error::UnsupportedFormat(path) => write!(f, "unsupported file format : {:?}", path),
+            Error::UnsupportedDataType(x) => write!(f, "unsupported data type : {}", x),
+            Error::TruncatedData { expected, actual } => write!(
+                f,
+                "truncated data : expected {} bytes, got {} bytes",
+                expected, actual
+            ),
+            Error::Decode(x) => write!(f, "failed to decode : {}", x),
+            Error::ShapeMismatch(x) => write!(f, "shape mismatch : {}", x),
+            Error::TextureUpload(x) => write!(f, "failed to upload texture : {}", x),
+            Error::Cancelled => write!(f, "loading cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Decode(e.to_string())
+    }
+}
+
+/// NIfTIに格納されているvoxelの型
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DataType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    #[default]
+    F32,
+    F64,
+}
+
+impl DataType {
+    pub fn from_nifti_code(code: i16) -> Option<DataType> {
+        match code {
+            2 => Some(DataType::U8),
+            4 => Some(DataType::I16),
+            8 => Some(DataType::I32),
+            16 => Some(DataType::F32),
+            64 => Some(DataType::F64),
+            256 => Some(DataType::I8),
+            512 => Some(DataType::U16),
+            768 => Some(DataType::U32),
+            1024 => Some(DataType::I64),
+            1280 => Some(DataType::U64),
+            _ => None,
+        }
+    }
+
+    pub fn nifti_code(&self) -> i16 {
+        match self {
+            DataType::U8 => 2,
+            DataType::I16 => 4,
+            DataType::I32 => 8,
+            DataType::F32 => 16,
+            DataType::F64 => 64,
+            DataType::I8 => 256,
+            DataType::U16 => 512,
+            DataType::U32 => 768,
+            DataType::I64 => 1024,
+            DataType::U64 => 1280,
+        }
+    }
+
+    pub fn is_integer(&self) -> bool {
+        !matches!(self, DataType::F32 | DataType::F64)
+    }
+
+    /// 1 voxelあたりのbyte数
+    pub fn size_of(&self) -> usize {
+        match self {
+            DataType::U8 | DataType::I8 => 1,
+            DataType::U16 | DataType::I16 => 2,
+            DataType::U32 | DataType::I32 | DataType::F32 => 4,
+            DataType::U64 | DataType::I64 | DataType::F64 => 8,
+        }
+    }
+}
+
+/// `datatype`のbyte列をf32に変換する
+pub fn decode_values(bytes: &[u8], datatype: DataType, big_endian: bool) -> Vec<f32> {
+    macro_rules! decode {
+        ($typ:ty) => {
+            bytes
+                .chunks_exact(std::mem::size_of::<$typ>())
+                .map(|x| {
+                    let x = x.try_into().unwrap();
+                    if big_endian {
+                        <$typ>::from_be_bytes(x) as f32
+                    } else {
+                        <$typ>::from_le_bytes(x) as f32
+                    }
+                })
+                .collect()
+        };
+    }
+    match datatype {
+        DataType::U8 => decode!(u8),
+        DataType::I8 => decode!(i8),
+        DataType::U16 => decode!(u16),
+        DataType::I16 => decode!(i16),
+        DataType::U32 => decode!(u32),
+        DataType::I32 => decode!(i32),
+        DataType::U64 => decode!(u64),
+        DataType::I64 => decode!(i64),
+        DataType::F32 => decode!(f32),
+        DataType::F64 => decode!(f64),
+    }
+}
+
+/// f32の値を`datatype`のbyte列に変換する. 整数型は四捨五入して型の範囲に丸める
+pub fn encode_values(data: &[f32], datatype: DataType, big_endian: bool) -> Vec<u8> {
+    macro_rules! encode {
+        ($typ:ty, $convert:expr) => {
+            data.iter()
+                .flat_map(|x| {
+                    let val: $typ = $convert(*x);
+                    if big_endian {
+                        val.to_be_bytes()
+                    } else {
+                        val.to_le_bytes()
+                    }
+                })
+                .collect()
+        };
+    }
+    match datatype {
+        DataType::U8 => encode!(u8, |x: f32| x.round() as u8),
+        DataType::I8 => encode!(i8, |x: f32| x.round() as i8),
+        DataType::U16 => encode!(u16, |x: f32| x.round() as u16),
+        DataType::I16 => encode!(i16, |x: f32| x.round() as i16),
+        DataType::U32 => encode!(u32, |x: f32| x.round() as u32),
+        DataType::I32 => encode!(i32, |x: f32| x.round() as i32),
+        DataType::U64 => encode!(u64, |x: f32| x.round() as u64),
+        DataType::I64 => encode!(i64, |x: f32| x.round() as i64),
+        DataType::F32 => encode!(f32, |x: f32| x),
+        DataType::F64 => encode!(f64, |x: f32| x as f64),
+    }
+}
+
+/// `Image3D::serialize`で書き出すjson headerのversion
+/// (1: native endianのf32, 2: dtype, endiannessを記録)
+const RAW_FORMAT_VERSION: u32 = 2;
+
+/// 表示に使う標準の向き. 各voxel軸の正の向きがそれぞれの文字の方向を向くように並べ替える
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    Ras,
+    Lps,
+}
+
+impl Orientation {
+    /// world座標(RAS+)の各軸について、向き直した後のvoxel軸が向くべき符号
+    fn world_signs(&self) -> [f32; 3] {
+        match self {
+            Orientation::Ras => [1.0, 1.0, 1.0],
+            Orientation::Lps => [-1.0, -1.0, 1.0],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    // Noneの場合はファイルに格納されている軸の順番のまま読み込む
+    pub orientation: Option<Orientation>,
+    // DICOM directoryから読むseries. Noneの場合はslice数の最も多いseries
+    pub dicom_series: Option<String>,
+    // .npzから読む配列の名前. Noneの場合は最初の3次元か4次元の配列
+    pub npz_array: Option<String>,
+    // slice画像 (directory, multi-page TIFF) から作るvolumeのspacing. Noneの場合は`<name>.json`のspacingか1mm
+    pub slice_spacing: Option<(f32, f32, f32)>,
+    // RGBのsliceから読むchannel (0: R, 1: G, 2: B). Noneの場合は輝度
+    pub rgb_channel: Option<usize>,
+    // 読み込みの進捗の通知とキャンセル
+    pub progress: Progress,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Image3D {
     #[serde(skip)]
     pub data: Vec<f32>,
     pub shape: (u32, u32, u32),
     pub spacing: (f32, f32, f32),
+    // 時系列のframe数. dataはframeごとのvolumeを順に並べたもの
+    #[serde(default = "default_frames")]
+    pub frames: u32,
+    // frame間の時間(秒). 0は不明
+    #[serde(default)]
+    pub frame_interval: f32,
+    // rescale前のファイルに格納されていた型
+    #[serde(default)]
+    pub datatype: DataType,
+    // dataに適用済みのscl_slope, scl_inter (slope == 0はscalingなし)
+    #[serde(default)]
+    pub scl_slope: f32,
+    #[serde(default)]
+    pub scl_inter: f32,
+    // voxel index (i, j, k) -> world座標(mm)への変換行列. NIfTIのsrowと同じくrow-major
+    #[serde(default)]
+    pub affine: [[f32; 4]; 4],
+    #[serde(default)]
+    pub qform_code: i16,
+    #[serde(default)]
+    pub sform_code: i16,
     #[serde(skip)]
     pub format: Option<UncompressedFloatFormat>,
     #[serde(skip)]
     pub mipmaps: Option<MipmapsOption>,
+    // maskのlabelごとの色
+    #[serde(skip)]
+    pub lut: Option<ColorLut>,
+    // dataを持たずchunkごとに読むvolume (OME-Zarr). 表示する断面はworker threadで読む
+    #[serde(skip)]
+    pub chunked: Option<std::sync::Arc<ZarrPyramid>>,
     pub is_mask: bool,
 }
 
+fn default_frames() -> u32 {
+    1
+}
+
 impl Image3D {
-    pub fn serialize(&self, path: &Path) {
+    /// 1 frameあたりのvoxel数
+    pub fn frame_len(&self) -> usize {
+        self.shape.0 as usize * self.shape.1 as usize * self.shape.2 as usize
+    }
+
+    /// `index`番目のframeのvoxel値
+    pub fn frame(&self, index: u32) -> &[f32] {
+        let len = self.frame_len();
+        let start = (index.min(self.frames.max(1) - 1) as usize) * len;
+        &self.data[start..start + len]
+    }
+
+    /// 向きの情報がない場合のaffine (spacingのみ)
+    pub fn spacing_affine(spacing: (f32, f32, f32)) -> [[f32; 4]; 4] {
+        [
+            [spacing.0, 0.0, 0.0, 0.0],
+            [0.0, spacing.1, 0.0, 0.0],
+            [0.0, 0.0, spacing.2, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]
+    }
+
+    /// affineをcgmathの行列(column-major)として返す
+    pub fn affine_matrix(&self) -> cgmath::Matrix4<f32> {
+        cgmath::Matrix4::from(self.affine).transpose()
+    }
+
+    pub fn voxel_to_world(&self, voxel: [f32; 3]) -> [f32; 3] {
+        let world = self.affine_matrix() * cgmath::Vector4::new(voxel[0], voxel[1], voxel[2], 1.0);
+        [world.x, world.y, world.z]
+    }
+
+    pub fn world_to_voxel(&self, world: [f32; 3]) -> [f32; 3] {
+        let inverse = self
+            .affine_matrix()
+            .inverse_transform()
+            .unwrap_or_else(cgmath::Matrix4::identity);
+        let voxel = inverse * cgmath::Vector4::new(world[0], world[1], world[2], 1.0);
+        [voxel.x, voxel.y, voxel.z]
+    }
+
+    /// `frame`番目のframeの`voxel`の値. 範囲外またはdataを読んでいない場合はNone
+    pub fn value(&self, voxel: [u32; 3], frame: u32) -> Option<f32> {
+        if voxel[0] >= self.shape.0 || voxel[1] >= self.shape.1 || voxel[2] >= self.shape.2 {
+            return None;
+        }
+        let index = voxel[0] as usize
+            + self.shape.0 as usize
+                * (voxel[1] as usize + self.shape.1 as usize * voxel[2] as usize);
+        let frame = frame.min(self.frames.max(1) - 1) as usize;
+        self.data.get(frame * self.frame_len() + index).copied()
+    }
+
+    /// scl_slope, scl_interを適用する前のファイルに格納する値
+    pub fn stored_values(&self) -> Vec<f32> {
+        if self.scl_slope != 0.0 {
+            self.data
+                .iter()
+                .map(|x| (x - self.scl_inter) / self.scl_slope)
+                .collect()
+        } else {
+            self.data.clone()
+        }
+    }
+
+    /// maskのlabelが全て収まる最も小さい整数型. 元の型より小さくならない場合はNone
+    fn narrowed_mask_datatype(&self) -> Option<DataType> {
+        if !self.is_mask || self.data.iter().any(|x| x.fract() != 0.0) {
+            return None;
+        }
+        let (min, max) = self
+            .data
+            .iter()
+            .fold((0.0f32, 0.0f32), |(lo, hi), x| (lo.min(*x), hi.max(*x)));
+        [
+            (DataType::U8, 0.0, 255.0),
+            (DataType::I8, -128.0, 127.0),
+            (DataType::U16, 0.0, 65535.0),
+            (DataType::I16, -32768.0, 32767.0),
+        ]
+        .into_iter()
+        .find(|(_, lo, hi)| *lo <= min && max <= *hi)
+        .map(|(datatype, ..)| datatype)
+        .filter(|datatype| datatype.size_of() < self.datatype.size_of())
+    }
+
+    pub fn serialize(&self, path: &Path) -> Result<()> {
         // voxelデータはrawファイルに、それ以外の情報はjsonファイルに保存する
         let header_path = path.with_extension("json");
         let raw_path = path.with_extension("raw");
-        // write data
-        let mut writer = BufWriter::new(std::fs::File::create(raw_path).unwrap());
-        for val in &self.data {
-            writer.write_all(&val.to_ne_bytes()).unwrap();
-        }
+        // maskはlabelの値をそのまま小さい整数型で保存する
+        let narrowed = self.narrowed_mask_datatype();
+        let (datatype, values) = match narrowed {
+            Some(datatype) => (datatype, self.data.clone()),
+            None => (self.datatype, self.stored_values()),
+        };
+        // write data (datatypeの型, little endian)
+        let mut writer = BufWriter::new(std::fs::File::create(raw_path)?);
+        writer.write_all(&encode_values(&values, datatype, false))?;
+        writer.flush()?;
         // write header
-        let json = serde_json::to_string_pretty(&self).unwrap();
-        std::fs::write(header_path, json).unwrap();
+        let mut json = serde_json::to_value(self)?;
+        if narrowed.is_some() {
+            json["datatype"] = serde_json::to_value(datatype)?;
+            json["scl_slope"] = 0.0.into();
+            json["scl_inter"] = 0.0.into();
+        }
+        json["version"] = RAW_FORMAT_VERSION.into();
+        json["dtype"] = serde_json::to_value(datatype)?;
+        json["endianness"] = "little".into();
+        std::fs::write(header_path, serde_json::to_string_pretty(&json)?)?;
         info!("Serialized image to {:?}", path);
+        Ok(())
     }
 
-    pub fn deserialize(path: &Path) -> Image3D {
+    pub fn deserialize(path: &Path, progress: &Progress) -> Result<Image3D> {
         let header_path = path.with_extension("json");
         let raw_path = path.with_extension("raw");
         // read header
-        let json = std::fs::read_to_string(header_path).unwrap();
-        let mut image: Image3D = serde_json::from_str(&json).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&header_path)?)?;
+        // versionのないheaderはnative endianのf32 (rescale済み)
+        let version = json["version"].as_u64().unwrap_or(1);
+        if version > RAW_FORMAT_VERSION as u64 {
+            return Err(Error::UnsupportedFormat(header_path));
+        }
+        let dtype: DataType = match json.get("dtype") {
+            Some(dtype) => serde_json::from_value(dtype.clone())?,
+            None => DataType::F32,
+        };
+        let big_endian = match json["endianness"].as_str() {
+            Some("little") => false,
+            Some("big") => true,
+            None => cfg!(target_endian = "big"),
+            Some(x) => return Err(Error::Decode(format!("unknown endianness : {}", x))),
+        };
+        let mut image: Image3D = serde_json::from_value(json)?;
+        if image.affine[3][3] == 0.0 {
+            // affineを持たない古いheader
+            image.affine = Image3D::spacing_affine(image.spacing);
+        }
         image.format = Some(UncompressedFloatFormat::F32);
         image.mipmaps = Some(MipmapsOption::NoMipmap);
         // read data
-        let mut data = Vec::new();
-        let mut reader = BufReader::new(std::fs::File::open(raw_path).unwrap());
-        let mut buffer = [0; 4];
-        while let Ok(n) = reader.read(&mut buffer) {
-            if n == 0 {
-                break;
-            }
-            data.push(f32::from_ne_bytes(buffer));
+        let expected = (image.shape.0 as usize)
+            .checked_mul(image.shape.1 as usize)
+            .and_then(|x| x.checked_mul(image.shape.2 as usize))
+            .and_then(|x| x.checked_mul(image.frames.max(1) as usize))
+            .and_then(|x| x.checked_mul(dtype.size_of()))
+            .ok_or_else(|| Error::ShapeMismatch(format!("invalid shape : {:?}", image.shape)))?;
+        let bytes = read_file(&raw_path, progress)?;
+        if bytes.len() < expected {
+            return Err(Error::TruncatedData {
+                expected,
+                actual: bytes.len(),
+            });
+        } else if bytes.len() > expected {
+            return Err(Error::ShapeMismatch(format!(
+                "raw data is {} bytes but shape {:?} of {:?} needs {} bytes",
+                bytes.len(),
+                image.shape,
+                dtype,
+                expected
+            )));
+        }
+        image.data = decode_values(&bytes, dtype, big_endian);
+        if version >= 2 {
+            rescale(&mut image.data, image.scl_slope, image.scl_inter);
         }
         info!("Deserialized image from {:?}", path);
-        image.data = data;
-        image
+        Ok(image)
+    }
+
+    /// 各voxel軸が最も近いworld軸に対応するように軸を入れ替え・反転する
+    pub fn reorient(self, orientation: Orientation) -> Image3D {
+        // voxel軸iがworld軸axes[i]に対応する (greedyに最も成分の大きい組から決める)
+        let affine = self.affine;
+        let mut axes = [usize::MAX; 3];
+        let mut used = [false; 3];
+        for _ in 0..3 {
+            let mut best = (0, 0, -1.0);
+            for (i, axis) in axes.iter().enumerate() {
+                if *axis != usize::MAX {
+                    continue;
+                }
+                for (w, is_used) in used.iter().enumerate() {
+                    if !is_used && affine[w][i].abs() > best.2 {
+                        best = (i, w, affine[w][i].abs());
+                    }
+                }
+            }
+            axes[best.0] = best.1;
+            used[best.1] = true;
+        }
+        let signs = orientation.world_signs();
+        let flips: Vec<bool> = (0..3)
+            .map(|i| affine[axes[i]][i] * signs[axes[i]] < 0.0)
+            .collect();
+        if (axes == [0, 1, 2] && flips.iter().all(|f| !f))
+            || self.frame_len() == 0
+            || self.chunked.is_some()
+        {
+            return self;
+        }
+        debug!("Reorient axes : {:?}, flips : {:?}", axes, flips);
+
+        let shape = [
+            self.shape.0 as usize,
+            self.shape.1 as usize,
+            self.shape.2 as usize,
+        ];
+        let spacing = [self.spacing.0, self.spacing.1, self.spacing.2];
+        let mut new_shape = [0; 3];
+        let mut new_spacing = [0.0; 3];
+        for i in 0..3 {
+            new_shape[axes[i]] = shape[i];
+            new_spacing[axes[i]] = spacing[i];
+        }
+
+        // 新しいindex -> 元のindexへの変換 (P) をaffineの右から掛ける
+        let mut permutation = [[0.0; 4]; 4];
+        for i in 0..3 {
+            if flips[i] {
+                permutation[i][axes[i]] = -1.0;
+                permutation[i][3] = (shape[i] - 1) as f32;
+            } else {
+                permutation[i][axes[i]] = 1.0;
+            }
+        }
+        permutation[3][3] = 1.0;
+        let mut new_affine = [[0.0; 4]; 4];
+        for (row, new_row) in new_affine.iter_mut().enumerate() {
+            for (col, val) in new_row.iter_mut().enumerate() {
+                *val = (0..4).map(|k| affine[row][k] * permutation[k][col]).sum();
+            }
+        }
+
+        let mut data = vec![0.0; self.data.len()];
+        let mut new_index = [0; 3];
+        // frameごとに同じ並べ替えをする
+        for (src, dst) in self
+            .data
+            .chunks_exact(self.frame_len())
+            .zip(data.chunks_exact_mut(self.frame_len()))
+        {
+            for k in 0..shape[2] {
+                for j in 0..shape[1] {
+                    for i in 0..shape[0] {
+                        for (axis, index) in [i, j, k].into_iter().enumerate() {
+                            new_index[axes[axis]] = if flips[axis] {
+                                shape[axis] - 1 - index
+                            } else {
+                                index
+                            };
+                        }
+                        let dst_index = new_index[0]
+                            + new_shape[0] * (new_index[1] + new_shape[1] * new_index[2]);
+                        dst[dst_index] = src[i + shape[0] * (j + shape[1] * k)];
+                    }
+                }
+            }
+        }
+
+        Image3D {
+            data,
+            shape: (
+                new_shape[0] as u32,
+                new_shape[1] as u32,
+                new_shape[2] as u32,
+            ),
+            spacing: (new_spacing[0], new_spacing[1], new_spacing[2]),
+            affine: new_affine,
+            ..self
+        }
     }
 }
 
@@ -65,66 +544,397 @@ impl fmt::Debug for Image3D {
         f.debug_struct("Image3D")
             .field("shape", &self.shape)
             .field("spacing", &self.spacing)
+            .field("frames", &self.frames)
+            .field("frame_interval", &self.frame_interval)
+            .field("datatype", &self.datatype)
+            .field("scl_slope", &self.scl_slope)
+            .field("scl_inter", &self.scl_inter)
+            .field("affine", &self.affine)
+            .field("qform_code", &self.qform_code)
+            .field("sform_code", &self.sform_code)
             .field("format", &self.format)
             .field("mipmaps", &self.mipmaps)
+            .field("lut", &self.lut.as_ref().map(|x| x.labels.len()))
+            .field("chunked", &self.chunked)
             .field("is_mask", &self.is_mask)
             .finish()
     }
 }
 
-pub fn load_image3d(data_path: &Path) -> Image3D {
-    info!("Loading image from {:?}", data_path);
-    let stem = data_path.file_name().unwrap().to_str().unwrap();
-    if &stem[stem.len() - 4..] == ".nii"
-        || &stem[stem.len() - 6..] == "nii.gz"
-        || &stem[stem.len() - 6..] == "hdr.gz"
-    {
-        debug!("Loading nifti file");
-        let obj = nifti::ReaderOptions::new().read_file(data_path).unwrap();
-        debug!("Loaded nifti file");
-        let header = obj.header();
-        let dim = header.dim;
-        let spacing = header.pixdim;
-
-        match header.datatype {
-            4 => {
-                // i16
-                let data = obj
-                    .into_volume()
-                    .into_ndarray::<i16>()
-                    .unwrap()
-                    .map(|x: &i16| *x as f32)
-                    .into_raw_vec();
-
-                Image3D {
-                    data,
-                    shape: (dim[1] as u32, dim[2] as u32, dim[3] as u32),
-                    spacing: (spacing[1], spacing[2], spacing[3]),
-                    format: Some(UncompressedFloatFormat::F32),
-                    mipmaps: Some(MipmapsOption::NoMipmap),
-                    is_mask: false,
-                }
-            }
-            64 => {
-                // double
-                let data = obj
-                    .into_volume()
-                    .into_ndarray::<f64>()
-                    .unwrap()
-                    .map(|x: &f64| *x as f32)
-                    .into_raw_vec();
-                Image3D {
-                    data,
-                    shape: (dim[1] as u32, dim[2] as u32, dim[3] as u32),
-                    spacing: (spacing[1], spacing[2], spacing[3]),
-                    format: Some(UncompressedFloatFormat::F32),
-                    mipmaps: Some(MipmapsOption::NoMipmap),
-                    is_mask: true,
-                }
-            }
-            _ => panic!("Unsupported data type : {}", header.datatype),
+/// scl_slope, scl_interを適用する. slopeが0(または不正な値)の場合はscalingしない
+fn rescale(data: &mut [f32], slope: f32, inter: f32) -> bool {
+    if slope == 0.0 || !slope.is_finite() || !inter.is_finite() {
+        return false;
+    }
+    if slope == 1.0 && inter == 0.0 {
+        return true;
+    }
+    data.iter_mut().for_each(|x| *x = *x * slope + inter);
+    true
+}
+
+/// `.nii.gz`のような2重の拡張子も取り除いたファイル名
+pub fn file_stem(data_path: &Path) -> String {
+    let name = data_path
+        .file_name()
+        .and_then(|x| x.to_str())
+        .unwrap_or_default();
+    if data_path.is_dir() {
+        return name.to_string();
+    }
+    let name = name.strip_suffix(".gz").unwrap_or(name);
+    match name.rfind('.') {
+        Some(pos) if pos > 0 => name[..pos].to_string(),
+        _ => name.to_string(),
+    }
+}
+
+/// 読み込めるvolumeの形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VolumeFormat {
+    Dicom,
+    Raw,
+    Nifti,
+    Nrrd,
+    MetaImage,
+    Mgh,
+    VtkLegacy,
+    VtkXml,
+    Numpy,
+    Zarr,
+    ImageStack,
+    Tiff,
+}
+
+/// 拡張子(directoryの場合はDICOM)から形式を判定する
+fn volume_format(data_path: &Path) -> Option<VolumeFormat> {
+    if data_path.is_dir() {
+        if zarr::is_zarr_dir(data_path) {
+            return Some(VolumeFormat::Zarr);
+        } else if stack::is_slice_dir(data_path) {
+            return Some(VolumeFormat::ImageStack);
         }
+        return Some(VolumeFormat::Dicom);
+    }
+    let name = file_name(data_path);
+    if name.ends_with(".raw")
+        && !data_path.with_extension("json").exists()
+        && data_path.with_extension("mhd").exists()
+    {
+        // MetaImageの.mhdと対になる.raw
+        Some(VolumeFormat::MetaImage)
+    } else if name.ends_with(".raw") || name.ends_with(".json") {
+        Some(VolumeFormat::Raw)
+    } else if name.ends_with(".nii") || name.ends_with(".nii.gz") || name.ends_with(".hdr.gz") {
+        Some(VolumeFormat::Nifti)
+    } else if name.ends_with(".nrrd") || name.ends_with(".nhdr") {
+        Some(VolumeFormat::Nrrd)
+    } else if name.ends_with(".mha") || name.ends_with(".mhd") {
+        Some(VolumeFormat::MetaImage)
+    } else if name.ends_with(".mgh") || name.ends_with(".mgz") {
+        Some(VolumeFormat::Mgh)
+    } else if name.ends_with(".vtk") {
+        Some(VolumeFormat::VtkLegacy)
+    } else if name.ends_with(".vti") {
+        Some(VolumeFormat::VtkXml)
+    } else if name.ends_with(".npy") || name.ends_with(".npz") {
+        Some(VolumeFormat::Numpy)
+    } else if name.ends_with(".tif") || name.ends_with(".tiff") {
+        Some(VolumeFormat::Tiff)
     } else {
-        panic!("Unsupported file format");
+        None
+    }
+}
+
+/// `load_image3d`で読める形式のファイルかどうか
+pub fn is_volume_file(data_path: &Path) -> bool {
+    volume_format(data_path).is_some()
+}
+
+/// 2D viewで表示する画像か. TIFFは1 pageのものだけ (複数pageはvolumeとして読む)
+pub fn is_image2d_file(data_path: &Path) -> bool {
+    if data_path.is_dir() {
+        return false;
+    }
+    match data_path
+        .extension()
+        .and_then(|x| x.to_str())
+        .map(|x| x.to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("png" | "jpg" | "jpeg" | "bmp" | "webp") => true,
+        Some("tif" | "tiff") => !stack::is_multi_page_tiff(data_path),
+        _ => false,
+    }
+}
+
+/// 2次元の画像を読む
+pub fn load_image2d(data_path: &Path) -> Result<Image2D> {
+    image2d::load(data_path)
+}
+
+/// 拡張子に応じた形式で保存する (.nii, .nii.gz, .nrrd, .mha, .mhd, .npy, .raw)
+pub fn save_image3d(image: &Image3D, data_path: &Path) -> Result<()> {
+    if image.data.len() < image.frame_len() * image.frames.max(1) as usize {
+        return Err(Error::ShapeMismatch(format!(
+            "voxel data of {:?} is not loaded",
+            image.shape
+        )));
+    }
+    let name = file_name(data_path);
+    if name.ends_with(".nii") || name.ends_with(".nii.gz") {
+        nifti::save(image, data_path)
+    } else if name.ends_with(".nrrd") {
+        nrrd::save(image, data_path)
+    } else if name.ends_with(".mha") || name.ends_with(".mhd") {
+        metaimage::save(image, data_path)
+    } else if name.ends_with(".npy") {
+        npy::save(image, data_path)
+    } else if name.ends_with(".raw") || name.ends_with(".json") {
+        image.serialize(data_path)
+    } else {
+        Err(Error::UnsupportedFormat(data_path.to_path_buf()))
+    }
+}
+
+/// 拡張子の判定に使う小文字のファイル名
+fn file_name(data_path: &Path) -> String {
+    data_path
+        .file_name()
+        .and_then(|x| x.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+/// 進捗を通知しながらファイル全体を読む
+fn read_file(data_path: &Path, progress: &Progress) -> Result<Vec<u8>> {
+    let file = std::fs::File::open(data_path)?;
+    let len = file.metadata()?.len();
+    let mut bytes = Vec::with_capacity(len as usize);
+    progress.reader(file, len).read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+pub fn load_image3d(data_path: &Path, options: &LoadOptions) -> Result<Image3D> {
+    info!("Loading image from {:?}", data_path);
+    let progress = &options.progress;
+    let image = match volume_format(data_path) {
+        Some(VolumeFormat::Dicom) => {
+            dicom::load(data_path, options.dicom_series.as_deref(), progress)
+        }
+        Some(VolumeFormat::Raw) => Image3D::deserialize(data_path, progress),
+        Some(VolumeFormat::Nifti) => nifti::load(data_path, progress),
+        Some(VolumeFormat::Nrrd) => nrrd::load(data_path, progress),
+        Some(VolumeFormat::MetaImage) => metaimage::load(data_path, progress),
+        Some(VolumeFormat::Mgh) => mgh::load(data_path, progress),
+        Some(VolumeFormat::VtkLegacy) => vtk::load_legacy(data_path, progress),
+        Some(VolumeFormat::VtkXml) => vtk::load_xml(data_path, progress),
+        Some(VolumeFormat::Numpy) => npy::load(data_path, options.npz_array.as_deref(), progress),
+        Some(VolumeFormat::Zarr) => zarr::load(data_path, progress),
+        Some(VolumeFormat::ImageStack) => stack::load_dir(data_path, options),
+        Some(VolumeFormat::Tiff) => stack::load_tiff(data_path, options),
+        None => Err(Error::UnsupportedFormat(data_path.to_path_buf())),
+    };
+    // キャンセルによる読み込みの中断はI/Oエラーなどとして返ってくる
+    let image = match image {
+        Err(_) if progress.is_cancelled() => return Err(Error::Cancelled),
+        image => image?,
+    };
+    Ok(finish_loading(image, data_path, options))
+}
+
+/// 読み込んだvolumeを`options`の向きに揃え、maskかどうかを判定する
+pub fn finish_loading(image: Image3D, data_path: &Path, options: &LoadOptions) -> Image3D {
+    let mut image = match options.orientation {
+        Some(orientation) => image.reorient(orientation),
+        None => image,
+    };
+    // 浮動小数点型のvolumeは値が整数でもmaskとみなさない
+    image.is_mask = is_mask_file_name(data_path)
+        || (image.datatype.is_integer() && looks_like_mask(&image.data));
+    debug!("is_mask : {}", image.is_mask);
+    if image.is_mask && image.lut.is_none() && lut::is_freesurfer_label_file(data_path) {
+        info!("Use FreeSurfer colour table");
+        image.lut = Some(ColorLut::freesurfer());
+    }
+    image
+}
+
+/// maskとして扱うファイル名(拡張子を除く)の末尾
+const MASK_NAME_SUFFIXES: [&str; 8] = [
+    ".label", "_label", "_labels", ".seg", "_seg", "-seg", "_mask", "-mask",
+];
+/// 内容からmaskと判定するlabel数の上限
+const MAX_MASK_LABELS: usize = 128;
+
+/// `*.label.nii.gz`, `*_seg.nii.gz` のようなファイル名のものをmaskとみなす
+pub fn is_mask_file_name(data_path: &Path) -> bool {
+    let name = match data_path.file_name().and_then(|x| x.to_str()) {
+        Some(name) => name.to_ascii_lowercase(),
+        None => return false,
+    };
+    let stem = name
+        .trim_end_matches(".gz")
+        .trim_end_matches(".nii")
+        .trim_end_matches(".hdr")
+        .trim_end_matches(".nrrd")
+        .trim_end_matches(".nhdr")
+        .trim_end_matches(".mha")
+        .trim_end_matches(".mhd")
+        .trim_end_matches(".mgh")
+        .trim_end_matches(".mgz")
+        .trim_end_matches(".vtk")
+        .trim_end_matches(".vti")
+        .trim_end_matches(".npy")
+        .trim_end_matches(".npz")
+        .trim_end_matches(".tif")
+        .trim_end_matches(".tiff");
+    MASK_NAME_SUFFIXES
+        .iter()
+        .any(|suffix| stem.ends_with(suffix))
+        || lut::is_freesurfer_label_file(data_path)
+}
+
+/// 0を含む少数の非負整数値だけで構成されているvolumeをmaskとみなす
+pub fn looks_like_mask(data: &[f32]) -> bool {
+    let mut labels = std::collections::BTreeSet::new();
+    for val in data {
+        if *val < 0.0 || val.fract() != 0.0 {
+            return false;
+        }
+        if labels.insert(*val as u32) && labels.len() > MAX_MASK_LABELS {
+            return false;
+        }
+    }
+    labels.contains(&0)
+}
+
+mod blosc;
+pub mod dicom;
+mod image2d;
+pub mod loader;
+pub mod lut;
+mod metaimage;
+mod mgh;
+mod nifti;
+mod npy;
+mod nrrd;
+mod stack;
+mod vtk;
+mod zarr;
+
+pub use image2d::Image2D;
+pub use loader::Progress;
+pub use lut::ColorLut;
+pub use zarr::{TileRequest, ZarrPyramid};
+
+/// io以下のtestで使う一時directoryとvolume
+#[cfg(test)]
+pub(crate) mod test_util {
+    use super::*;
+
+    /// testごとに空の一時directoryを作る
+    pub fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("viewer3d_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// 値がvoxelの通し番号のvolume
+    pub fn volume(shape: (u32, u32, u32), frames: u32, datatype: DataType) -> Image3D {
+        let len = (shape.0 * shape.1 * shape.2 * frames) as usize;
+        Image3D {
+            data: (0..len).map(|x| x as f32).collect(),
+            shape,
+            spacing: (1.0, 1.0, 1.0),
+            frames,
+            frame_interval: 0.0,
+            datatype,
+            scl_slope: 0.0,
+            scl_inter: 0.0,
+            affine: Image3D::spacing_affine((1.0, 1.0, 1.0)),
+            qform_code: 0,
+            sform_code: 0,
+            format: None,
+            mipmaps: None,
+            lut: None,
+            chunked: None,
+            is_mask: false,
+        }
+    }
+
+    /// 斜めでない, 軸の反転を含むaffine
+    pub fn lps_affine(spacing: (f32, f32, f32)) -> [[f32; 4]; 4] {
+        [
+            [-spacing.0, 0.0, 0.0, 12.5],
+            [0.0, -spacing.1, 0.0, -30.0],
+            [0.0, 0.0, spacing.2, 7.25],
+            [0.0, 0.0, 0.0, 1.0],
+        ]
+    }
+
+    pub fn assert_same_affine(actual: &[[f32; 4]; 4], expected: &[[f32; 4]; 4]) {
+        for (a, e) in actual.iter().flatten().zip(expected.iter().flatten()) {
+            assert!((a - e).abs() < 1e-4, "{:?} != {:?}", actual, expected);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_util::{temp_dir, volume};
+    use super::*;
+
+    #[test]
+    fn mask_detection_needs_integer_datatype() {
+        let options = LoadOptions::default();
+        let labels = |datatype| {
+            let mut image = volume((4, 3, 2), 1, datatype);
+            image.data.iter_mut().for_each(|x| *x %= 3.0);
+            image
+        };
+        let path = Path::new("volume.nii.gz");
+        assert!(finish_loading(labels(DataType::U8), path, &options).is_mask);
+        assert!(finish_loading(labels(DataType::I16), path, &options).is_mask);
+        assert!(!finish_loading(labels(DataType::F32), path, &options).is_mask);
+        // ファイル名で指定したものは型によらずmask
+        let path = Path::new("volume_seg.nii.gz");
+        assert!(finish_loading(labels(DataType::F32), path, &options).is_mask);
+    }
+
+    #[test]
+    fn serialize_narrows_mask_datatype() {
+        let dir = temp_dir("serialize_mask");
+        let mut mask = volume((4, 3, 2), 1, DataType::F32);
+        mask.data.iter_mut().for_each(|x| *x = (*x * 20.0) % 300.0);
+        mask.is_mask = true;
+        let path = dir.join("mask.raw");
+        mask.serialize(&path).unwrap();
+        // labelが255を超えるのでu16 (2 bytes/voxel)
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 24 * 2);
+        let loaded = Image3D::deserialize(&path, &Progress::default()).unwrap();
+        assert_eq!(loaded.datatype, DataType::U16);
+        assert_eq!(loaded.data, mask.data);
+
+        // scalingされたu16のmaskはrescale後のlabelをu8で保存する
+        mask.data
+            .iter_mut()
+            .for_each(|x| *x = (*x / 20.0) % 4.0 * 2.0 + 1.0);
+        mask.datatype = DataType::U16;
+        mask.scl_slope = 2.0;
+        mask.scl_inter = 1.0;
+        mask.serialize(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 24);
+        let loaded = Image3D::deserialize(&path, &Progress::default()).unwrap();
+        assert_eq!(loaded.datatype, DataType::U8);
+        assert_eq!(loaded.scl_slope, 0.0);
+        assert_eq!(loaded.data, mask.data);
+
+        // maskでないvolumeは元の型のまま
+        let image = volume((4, 3, 2), 1, DataType::F32);
+        image.serialize(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 24 * 4);
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
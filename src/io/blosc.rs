@@ -0,0 +1,254 @@
+use std::io::Read;
+
+use super::{Error, Result};
+
+const HEADER_SIZE: usize = 16;
+const DO_SHUFFLE: u8 = 0x01;
+const MEMCPYED: u8 = 0x02;
+const DO_BIT_SHUFFLE: u8 = 0x04;
+const DONT_SPLIT: u8 = 0x10;
+const LZ4_FORMAT: u8 = 1;
+const LZ4HC_FORMAT: u8 = 2;
+const ZLIB_FORMAT: u8 = 4;
+// blockをtypesize個のstreamに分けて圧縮する条件 (c-bloscのMAX_SPLITS, MIN_BUFFERSIZE)
+const MAX_SPLITS: usize = 16;
+const MIN_BUFFER_SIZE: usize = 128;
+
+fn read_u32(bytes: &[u8], offset: usize) -> usize {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize
+}
+
+fn truncated(expected: usize, actual: usize) -> Error {
+    Error::TruncatedData { expected, actual }
+}
+
+/// LZ4のblock形式 (frameのheaderなし) を展開して`output`に追加する
+fn decompress_lz4(src: &[u8], output: &mut Vec<u8>) -> Result<()> {
+    let invalid = || Error::Decode("invalid lz4 block".to_string());
+    let start = output.len();
+    let mut i = 0;
+    // 255が続く限り長さを足していく
+    let read_length = |i: &mut usize, mut len: usize| -> Result<usize> {
+        if len == 15 {
+            loop {
+                let byte = *src.get(*i).ok_or_else(invalid)?;
+                *i += 1;
+                len += byte as usize;
+                if byte != 255 {
+                    break;
+                }
+            }
+        }
+        Ok(len)
+    };
+    while i < src.len() {
+        let token = src[i];
+        i += 1;
+        let literal_len = read_length(&mut i, (token >> 4) as usize)?;
+        let literals = src.get(i..i + literal_len).ok_or_else(invalid)?;
+        output.extend_from_slice(literals);
+        i += literal_len;
+        // 最後のsequenceはliteralだけ
+        if i >= src.len() {
+            break;
+        }
+        let offset = src
+            .get(i..i + 2)
+            .map(|x| u16::from_le_bytes([x[0], x[1]]) as usize)
+            .ok_or_else(invalid)?;
+        i += 2;
+        let match_len = read_length(&mut i, (token & 0x0f) as usize)? + 4;
+        if offset == 0 || offset > output.len() - start {
+            return Err(invalid());
+        }
+        // 重なっている場合があるので1 byteずつコピーする
+        let from = output.len() - offset;
+        for k in 0..match_len {
+            output.push(output[from + k]);
+        }
+    }
+    Ok(())
+}
+
+/// Blosc (version 1形式) で圧縮されたbufferを展開する. 圧縮方式はlz4, lz4hc, zlib
+pub fn decompress(src: &[u8]) -> Result<Vec<u8>> {
+    if src.len() < HEADER_SIZE {
+        return Err(truncated(HEADER_SIZE, src.len()));
+    }
+    let flags = src[2];
+    let typesize = (src[3] as usize).max(1);
+    let nbytes = read_u32(src, 4);
+    let blocksize = read_u32(src, 8);
+    let cbytes = read_u32(src, 12);
+    if src.len() < cbytes {
+        return Err(truncated(cbytes, src.len()));
+    }
+    if flags & MEMCPYED != 0 {
+        return src
+            .get(HEADER_SIZE..HEADER_SIZE + nbytes)
+            .map(|x| x.to_vec())
+            .ok_or_else(|| truncated(HEADER_SIZE + nbytes, src.len()));
+    }
+    if flags & DO_BIT_SHUFFLE != 0 {
+        return Err(Error::Decode(
+            "blosc bitshuffle is not supported".to_string(),
+        ));
+    }
+    let compressor = (flags >> 5) & 0x07;
+    if ![LZ4_FORMAT, LZ4HC_FORMAT, ZLIB_FORMAT].contains(&compressor) {
+        return Err(Error::Decode(format!(
+            "unsupported blosc compressor : {}",
+            compressor
+        )));
+    }
+    if blocksize == 0 {
+        return Ok(Vec::new());
+    }
+
+    let n_blocks = nbytes.div_ceil(blocksize);
+    if src.len() < HEADER_SIZE + 4 * n_blocks {
+        return Err(truncated(HEADER_SIZE + 4 * n_blocks, src.len()));
+    }
+    let mut output = Vec::with_capacity(nbytes);
+    let mut block = Vec::with_capacity(blocksize);
+    for j in 0..n_blocks {
+        let is_leftover = j == n_blocks - 1 && !nbytes.is_multiple_of(blocksize);
+        let bsize = if is_leftover {
+            nbytes % blocksize
+        } else {
+            blocksize
+        };
+        let n_splits = if flags & DONT_SPLIT == 0
+            && typesize <= MAX_SPLITS
+            && bsize / typesize >= MIN_BUFFER_SIZE
+            && !is_leftover
+        {
+            typesize
+        } else {
+            1
+        };
+        let split_size = bsize / n_splits;
+        let mut offset = read_u32(src, HEADER_SIZE + 4 * j);
+        block.clear();
+        for _ in 0..n_splits {
+            let size = src
+                .get(offset..offset + 4)
+                .map(|_| read_u32(src, offset))
+                .ok_or_else(|| truncated(offset + 4, src.len()))?;
+            offset += 4;
+            let data = src
+                .get(offset..offset + size)
+                .ok_or_else(|| truncated(offset + size, src.len()))?;
+            offset += size;
+            if size == split_size {
+                // 圧縮しても小さくならないstreamはそのまま格納されている
+                block.extend_from_slice(data);
+            } else if compressor == ZLIB_FORMAT {
+                flate2::read::ZlibDecoder::new(data)
+                    .read_to_end(&mut block)
+                    .map_err(|e| Error::Decode(format!("blosc zlib : {}", e)))?;
+            } else {
+                decompress_lz4(data, &mut block)?;
+            }
+        }
+        if block.len() != bsize {
+            return Err(Error::Decode(format!(
+                "blosc block is {} bytes but {} bytes expected",
+                block.len(),
+                bsize
+            )));
+        }
+        if flags & DO_SHUFFLE != 0 && typesize > 1 && bsize >= typesize {
+            // byte shuffle: 各要素のk番目のbyteがまとめて並んでいる
+            let n_elements = bsize / typesize;
+            let start = output.len();
+            output.resize(start + bsize, 0);
+            for (k, bytes) in block.chunks_exact(n_elements).take(typesize).enumerate() {
+                for (i, byte) in bytes.iter().enumerate() {
+                    output[start + i * typesize + k] = *byte;
+                }
+            }
+            // 要素に満たない端数はそのまま
+            let tail = n_elements * typesize;
+            output[start + tail..].copy_from_slice(&block[tail..]);
+        } else {
+            output.extend_from_slice(&block);
+        }
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// blosc version 1のheader. 圧縮方式はlz4
+    fn header(flags: u8, typesize: u8, nbytes: usize, blocksize: usize, cbytes: usize) -> Vec<u8> {
+        let mut bytes = vec![2, 1, flags | (LZ4_FORMAT << 5), typesize];
+        for x in [nbytes, blocksize, cbytes] {
+            bytes.extend_from_slice(&(x as u32).to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn lz4_overlapping_match() {
+        // "ab"の後にoffset 2, 長さ10のmatch (出力中の自分自身と重なる), 最後にliteralだけのsequence
+        let src = [0x26, b'a', b'b', 2, 0, 0x30, b'x', b'y', b'z'];
+        let mut output = b"prefix".to_vec();
+        decompress_lz4(&src, &mut output).unwrap();
+        assert_eq!(output, b"prefixababababababxyz");
+    }
+
+    #[test]
+    fn lz4_extended_match_length() {
+        // matchの長さ 15 + 255 + 3 + 4
+        let src = [0x1f, b'a', 1, 0, 255, 3];
+        let mut output = Vec::new();
+        decompress_lz4(&src, &mut output).unwrap();
+        assert_eq!(output, vec![b'a'; 1 + 277]);
+    }
+
+    #[test]
+    fn lz4_rejects_offset_before_block() {
+        // offsetが展開済みの長さを超える (前のblockは参照できない)
+        let mut output = b"previous".to_vec();
+        assert!(decompress_lz4(&[0x10, b'a', 2, 0], &mut output).is_err());
+        // offsetの途中で終わる
+        assert!(decompress_lz4(&[0x10, b'a', 1], &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn shuffled_lz4_block() {
+        let values: Vec<u16> = (1..=8).collect();
+        let nbytes = values.len() * 2;
+        // byte shuffle後は [1, 2, ..., 8, 0, 0, ..., 0]. 9 byteのliteralとoffset 1, 長さ7のmatch
+        let mut stream = vec![0x93];
+        stream.extend((1..=8).chain([0]));
+        stream.extend([1, 0]);
+        let mut src = header(DO_SHUFFLE, 2, nbytes, nbytes, 0);
+        src.extend_from_slice(&(HEADER_SIZE as u32 + 4).to_le_bytes());
+        src.extend_from_slice(&(stream.len() as u32).to_le_bytes());
+        src.extend_from_slice(&stream);
+        let cbytes = src.len() as u32;
+        src[12..16].copy_from_slice(&cbytes.to_le_bytes());
+
+        let expected: Vec<u8> = values.iter().flat_map(|x| x.to_le_bytes()).collect();
+        assert_eq!(decompress(&src).unwrap(), expected);
+    }
+
+    #[test]
+    fn memcpyed_buffer() {
+        let data = [5, 6, 7, 8, 9];
+        let mut src = header(
+            MEMCPYED,
+            1,
+            data.len(),
+            data.len(),
+            HEADER_SIZE + data.len(),
+        );
+        src.extend_from_slice(&data);
+        assert_eq!(decompress(&src).unwrap(), data);
+        assert!(decompress(&src[..HEADER_SIZE + 2]).is_err());
+    }
+}
@@ -0,0 +1,670 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use glium::texture::{MipmapsOption, UncompressedFloatFormat};
+use tracing::{debug, info, warn};
+
+use super::{rescale, DataType, Error, Image3D, Progress, Result};
+
+type Tag = (u16, u16);
+
+const TRANSFER_SYNTAX_UID: Tag = (0x0002, 0x0010);
+const MODALITY: Tag = (0x0008, 0x0060);
+const SERIES_DESCRIPTION: Tag = (0x0008, 0x103e);
+const SLICE_THICKNESS: Tag = (0x0018, 0x0050);
+const SERIES_INSTANCE_UID: Tag = (0x0020, 0x000e);
+const INSTANCE_NUMBER: Tag = (0x0020, 0x0013);
+const IMAGE_POSITION_PATIENT: Tag = (0x0020, 0x0032);
+const IMAGE_ORIENTATION_PATIENT: Tag = (0x0020, 0x0037);
+const SAMPLES_PER_PIXEL: Tag = (0x0028, 0x0002);
+const NUMBER_OF_FRAMES: Tag = (0x0028, 0x0008);
+const ROWS: Tag = (0x0028, 0x0010);
+const COLUMNS: Tag = (0x0028, 0x0011);
+const PIXEL_SPACING: Tag = (0x0028, 0x0030);
+const BITS_ALLOCATED: Tag = (0x0028, 0x0100);
+const PIXEL_REPRESENTATION: Tag = (0x0028, 0x0103);
+const RESCALE_INTERCEPT: Tag = (0x0028, 0x1052);
+const RESCALE_SLOPE: Tag = (0x0028, 0x1053);
+const PIXEL_DATA: Tag = (0x7fe0, 0x0010);
+
+const ITEM_DELIMITATION: Tag = (0xfffe, 0xe00d);
+const SEQUENCE_DELIMITATION: Tag = (0xfffe, 0xe0dd);
+const UNDEFINED_LENGTH: u32 = 0xffff_ffff;
+// headerだけを読む場合に最初に読むbyte数. PixelDataまで届かなければ4倍ずつ増やす
+const HEADER_READ_SIZE: u64 = 64 * 1024;
+
+const IMPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2";
+const EXPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2.1";
+const DEFLATED_EXPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2.1.99";
+
+/// 1つのdirectoryに含まれるSeriesInstanceUIDごとのファイルのまとまり
+#[derive(Debug, Clone)]
+pub struct DicomSeries {
+    pub uid: String,
+    pub description: String,
+    pub modality: String,
+    pub files: Vec<PathBuf>,
+}
+
+/// DICOM fileのtop levelのelement (sequenceの中身は読み飛ばす)
+struct DataSet {
+    elements: HashMap<Tag, Vec<u8>>,
+    // headerだけを読んだ場合, PixelDataがあれば空のVec
+    pixel_data: Option<Vec<u8>>,
+}
+
+impl DataSet {
+    fn string(&self, tag: Tag) -> Option<String> {
+        self.elements.get(&tag).map(|value| {
+            String::from_utf8_lossy(value)
+                .trim_end_matches(['\0', ' '])
+                .trim_start()
+                .to_string()
+        })
+    }
+
+    /// DS, ISのような`\`区切りの数値
+    fn numbers(&self, tag: Tag) -> Vec<f64> {
+        match self.string(tag) {
+            Some(value) => value
+                .split('\\')
+                .filter_map(|x| x.trim().parse::<f64>().ok())
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    fn number(&self, tag: Tag) -> Option<f64> {
+        self.numbers(tag).first().copied()
+    }
+
+    fn u16(&self, tag: Tag) -> Option<u16> {
+        self.elements
+            .get(&tag)
+            .filter(|value| value.len() >= 2)
+            .map(|value| u16::from_le_bytes([value[0], value[1]]))
+    }
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    explicit_vr: bool,
+    // PixelDataの値を読まずに止める
+    headers_only: bool,
+}
+
+impl<'a> Parser<'a> {
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        if self.pos + length > self.bytes.len() {
+            return Err(Error::TruncatedData {
+                expected: self.pos + length,
+                actual: self.bytes.len(),
+            });
+        }
+        let bytes = &self.bytes[self.pos..self.pos + length];
+        self.pos += length;
+        Ok(bytes)
+    }
+
+    fn is_end(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    /// elementのtagと値の長さを読む
+    fn header(&mut self) -> Result<(Tag, u32)> {
+        let tag = (self.u16()?, self.u16()?);
+        if tag.0 == 0xfffe || !self.explicit_vr {
+            // item, delimiterはexplicit VRでもVRを持たない
+            return Ok((tag, self.u32()?));
+        }
+        let vr = self.take(2)?;
+        match vr {
+            b"OB" | b"OD" | b"OF" | b"OL" | b"OV" | b"OW" | b"SQ" | b"SV" | b"UC" | b"UN"
+            | b"UR" | b"UT" | b"UV" => {
+                self.take(2)?;
+                Ok((tag, self.u32()?))
+            }
+            _ => Ok((tag, self.u16()? as u32)),
+        }
+    }
+
+    /// 長さ未定義のsequenceまたはitemを、対応するdelimiterまで読み飛ばす
+    fn skip_undefined(&mut self) -> Result<()> {
+        loop {
+            let (tag, length) = self.header()?;
+            match tag {
+                SEQUENCE_DELIMITATION | ITEM_DELIMITATION => return Ok(()),
+                _ if length == UNDEFINED_LENGTH => self.skip_undefined()?,
+                _ => {
+                    self.take(length as usize)?;
+                }
+            }
+        }
+    }
+
+    fn data_set(&mut self, data_set: &mut DataSet, stop_group: Option<u16>) -> Result<()> {
+        while !self.is_end() {
+            if let Some(group) = stop_group {
+                let next = self.bytes.get(self.pos..self.pos + 2);
+                if next.map(|x| u16::from_le_bytes([x[0], x[1]])) != Some(group) {
+                    return Ok(());
+                }
+            }
+            let (tag, length) = self.header()?;
+            if tag == PIXEL_DATA {
+                if length == UNDEFINED_LENGTH {
+                    return Err(Error::UnsupportedDataType(
+                        "compressed (encapsulated) pixel data".to_string(),
+                    ));
+                }
+                data_set.pixel_data = if self.headers_only {
+                    Some(Vec::new())
+                } else {
+                    Some(self.take(length as usize)?.to_vec())
+                };
+                return Ok(());
+            }
+            if length == UNDEFINED_LENGTH {
+                self.skip_undefined()?;
+            } else {
+                let value = self.take(length as usize)?;
+                data_set.elements.insert(tag, value.to_vec());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// DICOM Part 10 fileを読む. `headers_only`の場合はPixelDataの手前までだけをファイルから読む
+fn read_data_set(path: &Path, headers_only: bool) -> Result<DataSet> {
+    if !headers_only {
+        return parse_data_set(&std::fs::read(path)?, false);
+    }
+    let mut file = std::fs::File::open(path)?;
+    let mut bytes = Vec::new();
+    let mut limit = HEADER_READ_SIZE;
+    loop {
+        (&mut file)
+            .take(limit - bytes.len() as u64)
+            .read_to_end(&mut bytes)?;
+        let is_complete = (bytes.len() as u64) < limit;
+        match parse_data_set(&bytes, true) {
+            // PixelDataの前で途切れた (elementの境界で途切れた場合を含む)
+            Err(Error::TruncatedData { .. }) if !is_complete => limit *= 4,
+            Ok(data_set) if data_set.pixel_data.is_none() && !is_complete => limit *= 4,
+            result => return result,
+        }
+    }
+}
+
+/// preambleのないfileはimplicit VR little endianとして読む
+fn parse_data_set(bytes: &[u8], headers_only: bool) -> Result<DataSet> {
+    let mut data_set = DataSet {
+        elements: HashMap::new(),
+        pixel_data: None,
+    };
+    let has_preamble = bytes.len() >= 132 && &bytes[128..132] == b"DICM";
+    if !has_preamble {
+        let mut parser = Parser {
+            bytes,
+            pos: 0,
+            explicit_vr: false,
+            headers_only,
+        };
+        parser.data_set(&mut data_set, None)?;
+        if data_set.string(SERIES_INSTANCE_UID).is_none() {
+            return Err(Error::Decode("not a DICOM file".to_string()));
+        }
+        return Ok(data_set);
+    }
+
+    // file meta informationは常にexplicit VR little endian
+    let mut parser = Parser {
+        bytes,
+        pos: 132,
+        explicit_vr: true,
+        headers_only,
+    };
+    parser.data_set(&mut data_set, Some(0x0002))?;
+    let transfer_syntax = data_set
+        .string(TRANSFER_SYNTAX_UID)
+        .unwrap_or_else(|| EXPLICIT_VR_LITTLE_ENDIAN.to_string());
+    match transfer_syntax.as_str() {
+        IMPLICIT_VR_LITTLE_ENDIAN => parser.explicit_vr = false,
+        EXPLICIT_VR_LITTLE_ENDIAN => (),
+        DEFLATED_EXPLICIT_VR_LITTLE_ENDIAN => {
+            let mut inflated = Vec::new();
+            // headerだけを読んでいる場合は途中で切れたstreamなので, 続きを読んでやり直す
+            flate2::read::DeflateDecoder::new(&bytes[parser.pos..])
+                .read_to_end(&mut inflated)
+                .map_err(|e| match headers_only {
+                    true => Error::TruncatedData {
+                        expected: bytes.len() + 1,
+                        actual: bytes.len(),
+                    },
+                    false => Error::from(e),
+                })?;
+            let mut parser = Parser {
+                bytes: &inflated,
+                pos: 0,
+                explicit_vr: true,
+                headers_only,
+            };
+            parser.data_set(&mut data_set, None)?;
+            return Ok(data_set);
+        }
+        _ => {
+            return Err(Error::UnsupportedDataType(format!(
+                "transfer syntax {}",
+                transfer_syntax
+            )))
+        }
+    }
+    parser.data_set(&mut data_set, None)?;
+    Ok(data_set)
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Failed to read directory {:?} : {}", dir, e);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+/// directory以下のDICOM fileをSeriesInstanceUIDごとにまとめる. slice数の多い順に並べる.
+/// 各fileはPixelDataの手前までのheaderだけを読む
+pub fn scan(dir: &Path, progress: &Progress) -> Result<Vec<DicomSeries>> {
+    let mut files = Vec::new();
+    collect_files(dir, &mut files);
+    files.sort();
+
+    let mut series: Vec<DicomSeries> = Vec::new();
+    let n_files = files.len();
+    for (i, file) in files.into_iter().enumerate() {
+        progress.report(i as f32 / n_files as f32)?;
+        let data_set = match read_data_set(&file, true) {
+            Ok(data_set) => data_set,
+            Err(e) => {
+                debug!("Skip {:?} : {}", file, e);
+                continue;
+            }
+        };
+        if data_set.pixel_data.is_none() {
+            continue;
+        }
+        let uid = data_set.string(SERIES_INSTANCE_UID).unwrap_or_default();
+        match series.iter_mut().find(|x| x.uid == uid) {
+            Some(x) => x.files.push(file),
+            None => series.push(DicomSeries {
+                uid,
+                description: data_set.string(SERIES_DESCRIPTION).unwrap_or_default(),
+                modality: data_set.string(MODALITY).unwrap_or_default(),
+                files: vec![file],
+            }),
+        }
+    }
+    series.sort_by_key(|x| std::cmp::Reverse(x.files.len()));
+    Ok(series)
+}
+
+/// directoryに含まれるseriesのうち、`series_uid`のもの(指定がなければ最もslice数の多いもの)を読む
+pub fn load(dir: &Path, series_uid: Option<&str>, progress: &Progress) -> Result<Image3D> {
+    let series = scan(dir, progress)?;
+    let selected = match series_uid {
+        Some(uid) => series.iter().find(|x| x.uid == uid),
+        None => series.first(),
+    };
+    match selected {
+        Some(selected) => load_series(selected, progress),
+        None => Err(Error::UnsupportedFormat(dir.to_path_buf())),
+    }
+}
+
+struct Slice {
+    position: Option<[f64; 3]>,
+    instance_number: f64,
+    data_set: DataSet,
+}
+
+fn dot(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+pub fn load_series(series: &DicomSeries, progress: &Progress) -> Result<Image3D> {
+    info!(
+        "Loading DICOM series {} ({}, {} files)",
+        series.uid,
+        series.description,
+        series.files.len()
+    );
+    let mut slices: Vec<Slice> = Vec::with_capacity(series.files.len());
+    for (i, file) in series.files.iter().enumerate() {
+        progress.report(i as f32 / series.files.len() as f32)?;
+        let data_set = match read_data_set(file, false) {
+            Ok(data_set) => data_set,
+            Err(e) => {
+                warn!("Skip {:?} : {}", file, e);
+                continue;
+            }
+        };
+        let position = data_set.numbers(IMAGE_POSITION_PATIENT);
+        slices.push(Slice {
+            position: (position.len() == 3).then(|| [position[0], position[1], position[2]]),
+            instance_number: data_set.number(INSTANCE_NUMBER).unwrap_or(0.0),
+            data_set,
+        });
+    }
+    if slices.is_empty() {
+        return Err(Error::Decode(format!(
+            "no readable slice in DICOM series {}",
+            series.uid
+        )));
+    }
+
+    let first = &slices[0].data_set;
+    let rows = first.u16(ROWS).unwrap_or(0) as usize;
+    let columns = first.u16(COLUMNS).unwrap_or(0) as usize;
+    let bits_allocated = first.u16(BITS_ALLOCATED).unwrap_or(16);
+    let signed = first.u16(PIXEL_REPRESENTATION).unwrap_or(0) == 1;
+    if first.u16(SAMPLES_PER_PIXEL).unwrap_or(1) != 1 {
+        return Err(Error::UnsupportedDataType(
+            "DICOM images with multiple samples per pixel".to_string(),
+        ));
+    }
+    if first.number(NUMBER_OF_FRAMES).unwrap_or(1.0) > 1.0 {
+        return Err(Error::UnsupportedDataType(
+            "multi-frame DICOM images".to_string(),
+        ));
+    }
+    let datatype = match (bits_allocated, signed) {
+        (8, false) => DataType::U8,
+        (8, true) => DataType::I8,
+        (16, false) => DataType::U16,
+        (16, true) => DataType::I16,
+        (32, false) => DataType::U32,
+        (32, true) => DataType::I32,
+        _ => {
+            return Err(Error::UnsupportedDataType(format!(
+                "BitsAllocated {}",
+                bits_allocated
+            )))
+        }
+    };
+    let pixel_spacing = first.numbers(PIXEL_SPACING);
+    let (row_spacing, column_spacing) = match pixel_spacing.len() {
+        2 => (pixel_spacing[0], pixel_spacing[1]),
+        _ => (1.0, 1.0),
+    };
+    let orientation = first.numbers(IMAGE_ORIENTATION_PATIENT);
+    let (row_dir, column_dir) = match orientation.len() {
+        6 => (
+            [orientation[0], orientation[1], orientation[2]],
+            [orientation[3], orientation[4], orientation[5]],
+        ),
+        _ => ([1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+    };
+    let normal = [
+        row_dir[1] * column_dir[2] - row_dir[2] * column_dir[1],
+        row_dir[2] * column_dir[0] - row_dir[0] * column_dir[2],
+        row_dir[0] * column_dir[1] - row_dir[1] * column_dir[0],
+    ];
+    let thickness = first.number(SLICE_THICKNESS).unwrap_or(1.0);
+
+    // 法線方向の位置(なければInstanceNumber)で並べる
+    if slices.iter().all(|x| x.position.is_some()) {
+        slices.sort_by(|a, b| {
+            let a = dot(&a.position.unwrap(), &normal);
+            let b = dot(&b.position.unwrap(), &normal);
+            a.total_cmp(&b)
+        });
+    } else {
+        slices.sort_by(|a, b| a.instance_number.total_cmp(&b.instance_number));
+    }
+
+    let origin = slices[0].position.unwrap_or([0.0; 3]);
+    let slice_step = match (slices[0].position, slices[slices.len() - 1].position) {
+        (Some(first), Some(last)) if slices.len() > 1 => {
+            let n = (slices.len() - 1) as f64;
+            [
+                (last[0] - first[0]) / n,
+                (last[1] - first[1]) / n,
+                (last[2] - first[2]) / n,
+            ]
+        }
+        _ => normal.map(|x| x * thickness),
+    };
+    let mut slice_spacing = dot(&slice_step, &slice_step).sqrt();
+    if slice_spacing == 0.0 {
+        slice_spacing = thickness;
+    }
+
+    let slice_len = rows * columns;
+    let bytes_per_pixel = bits_allocated as usize / 8;
+    let mut data = Vec::with_capacity(slice_len * slices.len());
+    // sliceごとのRescaleSlope, RescaleIntercept (適用しなかった場合はNone)
+    let mut scalings = Vec::with_capacity(slices.len());
+    for slice in &slices {
+        let data_set = &slice.data_set;
+        if data_set.u16(ROWS) != Some(rows as u16) || data_set.u16(COLUMNS) != Some(columns as u16)
+        {
+            return Err(Error::ShapeMismatch(format!(
+                "DICOM slices have different sizes in series {}",
+                series.uid
+            )));
+        }
+        let pixel_data = data_set.pixel_data.as_deref().unwrap_or_default();
+        if pixel_data.len() < slice_len * bytes_per_pixel {
+            return Err(Error::TruncatedData {
+                expected: slice_len * bytes_per_pixel,
+                actual: pixel_data.len(),
+            });
+        }
+        let mut values: Vec<f32> = pixel_data[..slice_len * bytes_per_pixel]
+            .chunks_exact(bytes_per_pixel)
+            .map(|x| match datatype {
+                DataType::U8 => x[0] as f32,
+                DataType::I8 => x[0] as i8 as f32,
+                DataType::U16 => u16::from_le_bytes([x[0], x[1]]) as f32,
+                DataType::I16 => i16::from_le_bytes([x[0], x[1]]) as f32,
+                DataType::U32 => u32::from_le_bytes([x[0], x[1], x[2], x[3]]) as f32,
+                _ => i32::from_le_bytes([x[0], x[1], x[2], x[3]]) as f32,
+            })
+            .collect();
+        let slope = data_set.number(RESCALE_SLOPE).unwrap_or(1.0) as f32;
+        let inter = data_set.number(RESCALE_INTERCEPT).unwrap_or(0.0) as f32;
+        scalings.push(rescale(&mut values, slope, inter).then_some((slope, inter)));
+        data.extend(values);
+    }
+    // sliceごとに異なる場合は1つのscl_slope, scl_interで表せないのでrescale後の値を保存する
+    let (datatype, (scl_slope, scl_inter)) = if scalings.iter().all(|x| *x == scalings[0]) {
+        (datatype, scalings[0].unwrap_or((0.0, 0.0)))
+    } else {
+        info!("RescaleSlope/Intercept differ between slices. Store rescaled values as float32");
+        (DataType::F32, (0.0, 0.0))
+    };
+
+    // DICOMのpatient座標系(LPS)からNIfTIと同じRASに変換する
+    let mut affine = [[0.0; 4]; 4];
+    for row in 0..3 {
+        let sign = if row < 2 { -1.0 } else { 1.0 };
+        affine[row][0] = (sign * row_dir[row] * column_spacing) as f32;
+        affine[row][1] = (sign * column_dir[row] * row_spacing) as f32;
+        affine[row][2] = (sign * slice_step[row]) as f32;
+        affine[row][3] = (sign * origin[row]) as f32;
+    }
+    affine[3][3] = 1.0;
+
+    Ok(Image3D {
+        data,
+        shape: (columns as u32, rows as u32, slices.len() as u32),
+        spacing: (
+            column_spacing as f32,
+            row_spacing as f32,
+            slice_spacing as f32,
+        ),
+        frames: 1,
+        frame_interval: 0.0,
+        datatype,
+        scl_slope,
+        scl_inter,
+        affine,
+        qform_code: 0,
+        sform_code: 1,
+        format: Some(UncompressedFloatFormat::F32),
+        mipmaps: Some(MipmapsOption::NoMipmap),
+        lut: None,
+        chunked: None,
+        is_mask: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::test_util::temp_dir;
+
+    /// explicit VR little endianのelement
+    fn element(tag: Tag, vr: &[u8; 2], value: &[u8]) -> Vec<u8> {
+        let mut value = value.to_vec();
+        if value.len() % 2 == 1 {
+            value.push(if vr == b"UI" { 0 } else { b' ' });
+        }
+        let mut bytes = [tag.0.to_le_bytes(), tag.1.to_le_bytes()].concat();
+        bytes.extend_from_slice(vr);
+        if [b"OB", b"OW"].contains(&vr) {
+            bytes.extend_from_slice(&[0, 0]);
+            bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        } else {
+            bytes.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        }
+        bytes.extend(value);
+        bytes
+    }
+
+    /// 2 x 3 pixelのu16のslice. `pixel_len`はPixelDataの長さとして書く値
+    fn slice_file(
+        uid: &str,
+        z: f32,
+        rescale: (f32, f32),
+        values: &[u16],
+        pixel_len: u32,
+    ) -> Vec<u8> {
+        let mut bytes = vec![0; 128];
+        bytes.extend_from_slice(b"DICM");
+        bytes.extend(element(
+            TRANSFER_SYNTAX_UID,
+            b"UI",
+            EXPLICIT_VR_LITTLE_ENDIAN.as_bytes(),
+        ));
+        bytes.extend(element(MODALITY, b"CS", b"CT"));
+        bytes.extend(element(SERIES_INSTANCE_UID, b"UI", uid.as_bytes()));
+        bytes.extend(element(
+            IMAGE_POSITION_PATIENT,
+            b"DS",
+            format!("0\\0\\{}", z).as_bytes(),
+        ));
+        bytes.extend(element(SAMPLES_PER_PIXEL, b"US", &1u16.to_le_bytes()));
+        bytes.extend(element(ROWS, b"US", &3u16.to_le_bytes()));
+        bytes.extend(element(COLUMNS, b"US", &2u16.to_le_bytes()));
+        bytes.extend(element(BITS_ALLOCATED, b"US", &16u16.to_le_bytes()));
+        bytes.extend(element(PIXEL_REPRESENTATION, b"US", &0u16.to_le_bytes()));
+        bytes.extend(element(
+            RESCALE_INTERCEPT,
+            b"DS",
+            rescale.1.to_string().as_bytes(),
+        ));
+        bytes.extend(element(
+            RESCALE_SLOPE,
+            b"DS",
+            rescale.0.to_string().as_bytes(),
+        ));
+        bytes.extend([0xe0, 0x7f, 0x10, 0x00]);
+        bytes.extend_from_slice(b"OW\0\0");
+        bytes.extend_from_slice(&pixel_len.to_le_bytes());
+        bytes.extend(values.iter().flat_map(|x| x.to_le_bytes()));
+        bytes
+    }
+
+    #[test]
+    fn mixed_rescale_is_stored_as_float() {
+        let dir = temp_dir("dicom_rescale");
+        let values = [0, 1, 2, 3, 4, 5];
+        let write = |name: &str, z: f32, rescale: (f32, f32)| {
+            let bytes = slice_file("1.2.3", z, rescale, &values, 12);
+            std::fs::write(dir.join(name), bytes).unwrap();
+        };
+        write("0.dcm", 0.0, (2.0, -10.0));
+        write("1.dcm", 1.0, (0.5, 3.0));
+        let series = scan(&dir, &Progress::default()).unwrap();
+        let image = load_series(&series[0], &Progress::default()).unwrap();
+        assert_eq!(image.datatype, DataType::F32);
+        assert_eq!((image.scl_slope, image.scl_inter), (0.0, 0.0));
+        assert_eq!(image.frame(0)[..3], [-10.0, -8.0, -6.0]);
+        assert_eq!(image.frame(0)[6..9], [3.0, 3.5, 4.0]);
+        // 保存する値もrescale後の値
+        assert_eq!(image.stored_values()[6..9], [3.0, 3.5, 4.0]);
+
+        // 全てのsliceで同じ場合は元のdatatypeとscl_slope, scl_inter
+        write("1.dcm", 1.0, (2.0, -10.0));
+        let image = load_series(&series[0], &Progress::default()).unwrap();
+        assert_eq!(image.datatype, DataType::U16);
+        assert_eq!((image.scl_slope, image.scl_inter), (2.0, -10.0));
+        assert_eq!(image.stored_values()[6..9], [0.0, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn scan_reads_headers_only() {
+        let dir = temp_dir("dicom_scan");
+        let values = [1, 2, 3, 4, 5, 6];
+        for (i, uid) in ["1.2.3", "1.2.3", "1.2.4"].into_iter().enumerate() {
+            let bytes = slice_file(uid, i as f32, (1.0, 0.0), &values, 12);
+            std::fs::write(dir.join(format!("{}.dcm", i)), bytes).unwrap();
+        }
+        // PixelDataの値が途中で切れているfileも, headerだけを読む場合はseriesに含める
+        let mut truncated = slice_file("1.2.5", 0.0, (1.0, 0.0), &values, 1 << 20);
+        truncated.resize(truncated.len() + 200 * 1024, 0);
+        let path = dir.join("truncated.dcm");
+        std::fs::write(&path, truncated).unwrap();
+        assert!(read_data_set(&path, true).unwrap().pixel_data.is_some());
+        assert!(matches!(
+            read_data_set(&path, false),
+            Err(Error::TruncatedData { .. })
+        ));
+        std::fs::write(dir.join("notes.txt"), "not a DICOM file").unwrap();
+
+        let series = scan(&dir, &Progress::default()).unwrap();
+        let uids: Vec<_> = series
+            .iter()
+            .map(|x| (x.uid.as_str(), x.files.len()))
+            .collect();
+        assert_eq!(uids, [("1.2.3", 2), ("1.2.4", 1), ("1.2.5", 1)]);
+        assert_eq!(series[0].modality, "CT");
+
+        let image = load_series(&series[0], &Progress::default()).unwrap();
+        assert_eq!(image.shape, (2, 3, 2));
+        assert_eq!(image.frame(0)[..6], [1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+    }
+}
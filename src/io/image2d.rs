@@ -0,0 +1,164 @@
+use std::path::Path;
+
+use tracing::debug;
+
+use super::{DataType, Error, Result};
+
+/// 1枚の2次元画像. samplesは上の行から順に, 画素ごとにchannels個の値が並ぶ
+pub struct Image2D {
+    pub width: u32,
+    pub height: u32,
+    pub channels: usize,
+    pub samples: Vec<f32>,
+    pub datatype: DataType,
+}
+
+impl Image2D {
+    /// grayscaleの値にする. RGBは`rgb_channel`のchannel, Noneの場合は輝度 (ITU-R BT.601)
+    pub fn into_gray(self, rgb_channel: Option<usize>) -> Result<Vec<f32>> {
+        if self.channels <= 2 {
+            // alphaは捨てる
+            return Ok(self.samples.into_iter().step_by(self.channels).collect());
+        }
+        let is_integer = !matches!(self.datatype, DataType::F32 | DataType::F64);
+        let gray = match rgb_channel {
+            Some(c) if c < 3 => self
+                .samples
+                .chunks_exact(self.channels)
+                .map(|x| x[c])
+                .collect(),
+            Some(c) => return Err(Error::UnsupportedDataType(format!("RGB channel {}", c))),
+            None => self
+                .samples
+                .chunks_exact(self.channels)
+                .map(|x| {
+                    let luma = 0.299 * x[0] + 0.587 * x[1] + 0.114 * x[2];
+                    if is_integer {
+                        luma.round()
+                    } else {
+                        luma
+                    }
+                })
+                .collect(),
+        };
+        Ok(gray)
+    }
+
+    /// alphaを除いた画素値の最小値と最大値
+    pub fn value_range(&self) -> (f32, f32) {
+        let colors = if self.channels <= 2 { 1 } else { 3 };
+        self.samples
+            .chunks_exact(self.channels)
+            .flat_map(|x| &x[..colors])
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &x| {
+                (min.min(x), max.max(x))
+            })
+    }
+}
+
+/// 画像を1枚読む. 形式は拡張子ではなくファイルの内容から判定する
+/// (PNG, JPEG, TIFF, BMP, WebPなど). 8bit, 16bitの値はそのまま保持する
+pub fn load(path: &Path) -> Result<Image2D> {
+    let reader = image::io::Reader::open(path)?.with_guessed_format()?;
+    let format = reader.format();
+    let image = reader
+        .decode()
+        .map_err(|e| Error::Decode(format!("{:?} : {}", path, e)))?;
+    let (width, height) = (image.width(), image.height());
+    macro_rules! image2d {
+        ($buffer:expr, $channels:expr, $datatype:expr) => {
+            Image2D {
+                width,
+                height,
+                channels: $channels,
+                samples: $buffer.into_raw().into_iter().map(|x| x as f32).collect(),
+                datatype: $datatype,
+            }
+        };
+    }
+    let image = match image {
+        image::DynamicImage::ImageLuma8(x) => image2d!(x, 1, DataType::U8),
+        image::DynamicImage::ImageLumaA8(x) => image2d!(x, 2, DataType::U8),
+        image::DynamicImage::ImageRgb8(x) => image2d!(x, 3, DataType::U8),
+        image::DynamicImage::ImageRgba8(x) => image2d!(x, 4, DataType::U8),
+        image::DynamicImage::ImageLuma16(x) => image2d!(x, 1, DataType::U16),
+        image::DynamicImage::ImageLumaA16(x) => image2d!(x, 2, DataType::U16),
+        image::DynamicImage::ImageRgb16(x) => image2d!(x, 3, DataType::U16),
+        image::DynamicImage::ImageRgba16(x) => image2d!(x, 4, DataType::U16),
+        image => image2d!(image.to_rgba32f(), 4, DataType::F32),
+    };
+    debug!(
+        "Image : {:?} {}x{}, {} channels {:?}",
+        format, image.width, image.height, image.channels, image.datatype
+    );
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::test_util::temp_dir;
+
+    #[test]
+    fn format_from_content() {
+        let dir = temp_dir("image2d_jpeg");
+        // 拡張子は.pngだが中身はJPEG
+        let path = dir.join("photo.png");
+        image::GrayImage::from_pixel(8, 4, image::Luma([100]))
+            .save_with_format(&path, image::ImageFormat::Jpeg)
+            .unwrap();
+        let image = load(&path).unwrap();
+        assert_eq!((image.width, image.height, image.channels), (8, 4, 1));
+        assert_eq!(image.datatype, DataType::U8);
+        assert!(image.samples.iter().all(|x| (x - 100.0).abs() <= 1.0));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn gray16_keeps_full_precision() {
+        let dir = temp_dir("image2d_gray16");
+        let path = dir.join("slice.png");
+        let pixels = vec![0u16, 1, 255, 256, 40000, 65535];
+        image::ImageBuffer::<image::Luma<u16>, _>::from_raw(3, 2, pixels.clone())
+            .unwrap()
+            .save(&path)
+            .unwrap();
+        let image = load(&path).unwrap();
+        assert_eq!(image.datatype, DataType::U16);
+        assert_eq!(image.value_range(), (0.0, 65535.0));
+        let gray = image.into_gray(None).unwrap();
+        assert_eq!(gray, pixels.iter().map(|x| *x as f32).collect::<Vec<_>>());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn gray_from_color() {
+        let rgb = |datatype| Image2D {
+            width: 2,
+            height: 1,
+            channels: 3,
+            samples: vec![255.0, 0.0, 0.0, 10.0, 20.0, 31.0],
+            datatype,
+        };
+        // 輝度 (整数の型は丸める)
+        assert_eq!(rgb(DataType::U8).into_gray(None).unwrap(), [76.0, 18.0]);
+        let luma = rgb(DataType::F32).into_gray(None).unwrap();
+        assert!((luma[0] - 76.245).abs() < 1e-3 && (luma[1] - 18.263).abs() < 1e-3);
+        // channelの選択
+        assert_eq!(rgb(DataType::U8).into_gray(Some(0)).unwrap(), [255.0, 10.0]);
+        assert_eq!(rgb(DataType::U8).into_gray(Some(2)).unwrap(), [0.0, 31.0]);
+        assert!(matches!(
+            rgb(DataType::U8).into_gray(Some(3)),
+            Err(Error::UnsupportedDataType(_))
+        ));
+        // grayscaleのalphaは捨てる
+        let gray_alpha = Image2D {
+            width: 2,
+            height: 1,
+            channels: 2,
+            samples: vec![5.0, 255.0, 6.0, 0.0],
+            datatype: DataType::U8,
+        };
+        assert_eq!(gray_alpha.into_gray(Some(1)).unwrap(), [5.0, 6.0]);
+    }
+}
@@ -0,0 +1,237 @@
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tracing::{debug, info};
+
+use super::dicom::{self, DicomSeries};
+use super::{Error, Image3D, LoadOptions, Result, TileRequest, ZarrPyramid};
+
+/// 読み込みの進捗の通知とキャンセルに使う
+#[derive(Clone, Default)]
+pub struct Progress {
+    cancelled: Arc<AtomicBool>,
+    callback: Option<Arc<dyn Fn(f32) + Send + Sync>>,
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Progress")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+impl Progress {
+    pub fn new(callback: impl Fn(f32) + Send + Sync + 'static) -> Self {
+        Progress {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            callback: Some(Arc::new(callback)),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// 進捗(0.0 - 1.0)を通知する. キャンセルされていればErrを返す
+    pub fn report(&self, fraction: f32) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        if let Some(callback) = &self.callback {
+            callback(fraction.clamp(0.0, 1.0));
+        }
+        Ok(())
+    }
+
+    /// `total` byteのうち読んだ量を進捗として通知するreader
+    pub fn reader<R: Read>(&self, inner: R, total: u64) -> ProgressReader<R> {
+        ProgressReader {
+            inner,
+            progress: self.clone(),
+            read: 0,
+            total,
+            percent: 0,
+        }
+    }
+}
+
+pub struct ProgressReader<R> {
+    inner: R,
+    progress: Progress,
+    read: u64,
+    total: u64,
+    percent: u64,
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.progress.is_cancelled() {
+            return Err(std::io::Error::other("loading cancelled"));
+        }
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        // event loopに送りすぎないように1%ごとに通知する
+        let percent = self.read * 100 / self.total.max(1);
+        if percent != self.percent {
+            self.percent = percent;
+            let _ = self.progress.report(percent as f32 / 100.0);
+        }
+        Ok(n)
+    }
+}
+
+/// workerからevent loopに送られる読み込みの状態
+#[derive(Debug)]
+pub enum LoadEvent {
+    /// キャンセルされた読み込みは通知しないのでidを持たない
+    Progress { fraction: f32 },
+    /// DICOM directoryに含まれていたseries
+    Scanned {
+        id: u64,
+        dir: PathBuf,
+        series: Vec<DicomSeries>,
+    },
+    Finished {
+        id: u64,
+        path: PathBuf,
+        result: Result<Image3D>,
+    },
+    /// chunkごとに読むvolumeの`slot`番目の断面に表示する領域
+    Tile {
+        pyramid: Arc<ZarrPyramid>,
+        slot: usize,
+        request: TileRequest,
+        result: Result<Vec<f32>>,
+    },
+}
+
+/// volumeをworker threadで読み込む. 新しい読み込みを始めると前の読み込みはキャンセルされる
+#[derive(Debug, Default)]
+pub struct Loader {
+    next_id: u64,
+    current: Option<(u64, Progress)>,
+}
+
+impl Loader {
+    pub fn spawn<F>(&mut self, path: &Path, options: &LoadOptions, send: F) -> u64
+    where
+        F: Fn(LoadEvent) + Clone + Send + Sync + 'static,
+    {
+        self.spawn_job(path, options, send.clone(), move |id, path, options| {
+            // seriesが指定されていないDICOM directoryはseriesの一覧も通知する
+            let is_dicom = super::volume_format(path) == Some(super::VolumeFormat::Dicom);
+            if is_dicom && options.dicom_series.is_none() {
+                dicom::scan(path, &options.progress).and_then(|series| {
+                    let result = match series.first() {
+                        Some(first) => dicom::load_series(first, &options.progress)
+                            .map(|image| super::finish_loading(image, path, options)),
+                        None => Err(Error::UnsupportedFormat(path.to_path_buf())),
+                    };
+                    send(LoadEvent::Scanned {
+                        id,
+                        dir: path.to_path_buf(),
+                        series,
+                    });
+                    result
+                })
+            } else {
+                super::load_image3d(path, options)
+            }
+        })
+    }
+
+    /// `dir`を走査して見つけたseriesの1つを読む. directoryは走査し直さない
+    pub fn spawn_series<F>(
+        &mut self,
+        dir: &Path,
+        series: DicomSeries,
+        options: &LoadOptions,
+        send: F,
+    ) -> u64
+    where
+        F: Fn(LoadEvent) + Clone + Send + Sync + 'static,
+    {
+        self.spawn_job(dir, options, send, move |_, dir, options| {
+            dicom::load_series(&series, &options.progress)
+                .map(|image| super::finish_loading(image, dir, options))
+        })
+    }
+
+    /// `job`をworker threadで実行し, 結果を`LoadEvent::Finished`で通知する
+    fn spawn_job<F, J>(&mut self, path: &Path, options: &LoadOptions, send: F, job: J) -> u64
+    where
+        F: Fn(LoadEvent) + Clone + Send + Sync + 'static,
+        J: FnOnce(u64, &Path, &LoadOptions) -> Result<Image3D> + Send + 'static,
+    {
+        self.cancel();
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let progress = {
+            let send = send.clone();
+            Progress::new(move |fraction| send(LoadEvent::Progress { fraction }))
+        };
+        let mut options = options.clone();
+        options.progress = progress.clone();
+        self.current = Some((id, progress));
+
+        let path = path.to_path_buf();
+        std::thread::spawn(move || {
+            let result = job(id, &path, &options);
+            debug!("Load {} finished", id);
+            send(LoadEvent::Finished { id, path, result });
+        });
+        id
+    }
+
+    /// chunkごとに読むvolumeの領域をworker threadで読む. volumeの読み込みとは独立に実行する
+    pub fn spawn_tile<F>(
+        &self,
+        pyramid: Arc<ZarrPyramid>,
+        slot: usize,
+        request: TileRequest,
+        send: F,
+    ) where
+        F: Fn(LoadEvent) + Send + 'static,
+    {
+        std::thread::spawn(move || {
+            let result = pyramid.read_tile(&request, &Progress::default());
+            send(LoadEvent::Tile {
+                pyramid,
+                slot,
+                request,
+                result,
+            });
+        });
+    }
+
+    /// 実行中の読み込みをキャンセルする
+    pub fn cancel(&mut self) {
+        if let Some((id, progress)) = self.current.take() {
+            info!("Cancel load {}", id);
+            progress.cancel();
+        }
+    }
+
+    pub fn is_current(&self, id: u64) -> bool {
+        self.current.as_ref().is_some_and(|(x, _)| *x == id)
+    }
+
+    /// 最新の読み込みの完了であればtrueを返す. 古い読み込みの結果は捨てる
+    pub fn finish(&mut self, id: u64) -> bool {
+        if self.is_current(id) {
+            self.current = None;
+            true
+        } else {
+            false
+        }
+    }
+}
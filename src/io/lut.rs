@@ -0,0 +1,108 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use tracing::{info, warn};
+
+/// FreeSurferの標準のcolour lookup table (aseg, aparcのlabelのみ)
+const FREESURFER_LUT: &str = include_str!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/assets/FreeSurferColorLUT.txt"
+));
+
+/// label値 -> (名前, RGBA) の対応
+#[derive(Debug, Clone, Default)]
+pub struct ColorLut {
+    pub labels: BTreeMap<u32, (String, [u8; 4])>,
+}
+
+impl ColorLut {
+    /// FreeSurferColorLUT.txt形式 (`No. Name R G B A`, Aは透明度) を読む
+    pub fn parse(text: &str) -> ColorLut {
+        let mut labels = BTreeMap::new();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let columns: Vec<&str> = line.split_whitespace().collect();
+            if columns.len() < 5 {
+                continue;
+            }
+            let (Ok(label), Ok(r), Ok(g), Ok(b)) = (
+                columns[0].parse::<u32>(),
+                columns[2].parse::<u8>(),
+                columns[3].parse::<u8>(),
+                columns[4].parse::<u8>(),
+            ) else {
+                continue;
+            };
+            let transparency = columns
+                .get(5)
+                .and_then(|x| x.parse::<u8>().ok())
+                .unwrap_or(0);
+            labels.insert(
+                label,
+                (columns[1].to_string(), [r, g, b, 255 - transparency]),
+            );
+        }
+        ColorLut { labels }
+    }
+
+    /// $FREESURFER_HOMEのFreeSurferColorLUT.txt. なければ同梱のaseg用の表
+    pub fn freesurfer() -> ColorLut {
+        if let Some(home) = std::env::var_os("FREESURFER_HOME") {
+            let path = Path::new(&home).join("FreeSurferColorLUT.txt");
+            match std::fs::read_to_string(&path) {
+                Ok(text) => {
+                    info!("Load colour table from {:?}", path);
+                    return ColorLut::parse(&text);
+                }
+                Err(e) => warn!("Failed to read {:?} : {}", path, e),
+            }
+        }
+        ColorLut::parse(FREESURFER_LUT)
+    }
+
+    pub fn color(&self, label: u32) -> Option<[u8; 4]> {
+        self.labels.get(&label).map(|(_, color)| *color)
+    }
+}
+
+/// 同梱の表で色をつけられるFreeSurferのlabel volumeのファイル名か. 拡張子を除いた名前が
+/// aseg, aseg.* (aseg.auto など), aparc+aseg, aparc.DKTatlas+aseg のもの.
+/// wmparc (3000-5002), aparc.a2009s+aseg (11100-12175) のlabelは同梱の表にないので含めない
+pub fn is_freesurfer_label_file(data_path: &Path) -> bool {
+    let stem = super::file_stem(data_path).to_ascii_lowercase();
+    stem == "aseg"
+        || stem.starts_with("aseg.")
+        || stem == "aparc+aseg"
+        || stem == "aparc.dktatlas+aseg"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freesurfer_label_file_names() {
+        for name in [
+            "aseg.mgz",
+            "aseg.auto_noCCseg.mgz",
+            "aparc+aseg.mgz",
+            "aparc.DKTatlas+aseg.mgz",
+        ] {
+            assert!(is_freesurfer_label_file(Path::new(name)), "{}", name);
+        }
+        for name in [
+            "casegroup.nii.gz",
+            "lh.aparc.annot",
+            "aparc_stats.nii",
+            "t1_wmparcels.nii.gz",
+            "brain.mgz",
+            "wmparc.mgz",
+            "aparc.a2009s+aseg.nii.gz",
+        ] {
+            assert!(!is_freesurfer_label_file(Path::new(name)), "{}", name);
+        }
+    }
+}
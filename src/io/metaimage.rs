@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use glium::texture::{MipmapsOption, UncompressedFloatFormat};
+use tracing::{debug, info};
+
+use super::{decode_values, encode_values, DataType, Error, Image3D, Progress, Result};
+
+/// MetaImage(ITK)の座標系はLPS. RASにするための符号
+const LPS_SIGNS: [f32; 3] = [-1.0, -1.0, 1.0];
+
+fn parse_element_type(name: &str) -> Option<DataType> {
+    let datatype = match name {
+        "MET_UCHAR" => DataType::U8,
+        "MET_CHAR" => DataType::I8,
+        "MET_USHORT" => DataType::U16,
+        "MET_SHORT" => DataType::I16,
+        "MET_UINT" | "MET_ULONG" => DataType::U32,
+        "MET_INT" | "MET_LONG" => DataType::I32,
+        "MET_ULONG_LONG" => DataType::U64,
+        "MET_LONG_LONG" => DataType::I64,
+        "MET_FLOAT" => DataType::F32,
+        "MET_DOUBLE" => DataType::F64,
+        _ => return None,
+    };
+    Some(datatype)
+}
+
+fn element_type_name(datatype: DataType) -> &'static str {
+    match datatype {
+        DataType::U8 => "MET_UCHAR",
+        DataType::I8 => "MET_CHAR",
+        DataType::U16 => "MET_USHORT",
+        DataType::I16 => "MET_SHORT",
+        DataType::U32 => "MET_UINT",
+        DataType::I32 => "MET_INT",
+        DataType::U64 => "MET_ULONG_LONG",
+        DataType::I64 => "MET_LONG_LONG",
+        DataType::F32 => "MET_FLOAT",
+        DataType::F64 => "MET_DOUBLE",
+    }
+}
+
+fn parse_numbers(fields: &HashMap<String, String>, key: &str) -> Result<Option<Vec<f64>>> {
+    match fields.get(key) {
+        Some(text) => text
+            .split_whitespace()
+            .map(|x| {
+                x.parse::<f64>()
+                    .map_err(|_| Error::Decode(format!("invalid MetaImage {} : {}", key, text)))
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(Some),
+        None => Ok(None),
+    }
+}
+
+fn is_true(fields: &HashMap<String, String>, key: &str) -> bool {
+    fields
+        .get(key)
+        .is_some_and(|x| x.eq_ignore_ascii_case("true"))
+}
+
+/// .mha (headerとdataが1つのファイル) と .mhd (+ .raw, .zraw) を読む
+pub fn load(data_path: &Path, progress: &Progress) -> Result<Image3D> {
+    debug!("Loading MetaImage file");
+    // .rawが指定された場合は対になる.mhdを読む
+    let header_path = data_path.with_extension("mhd");
+    let data_path = if super::file_name(data_path).ends_with(".raw") {
+        header_path.as_path()
+    } else {
+        data_path
+    };
+    let bytes = super::read_file(data_path, progress)?;
+
+    // "Key = Value" の行が続き, ElementDataFileが最後の行
+    let mut fields = HashMap::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let end = bytes[offset..]
+            .iter()
+            .position(|x| *x == b'\n')
+            .map_or(bytes.len(), |x| offset + x + 1);
+        let line = String::from_utf8_lossy(&bytes[offset..end]);
+        offset = end;
+        if let Some((key, value)) = line.split_once('=') {
+            let key = key.trim().to_string();
+            fields.insert(key.clone(), value.trim().to_string());
+            if key == "ElementDataFile" {
+                break;
+            }
+        }
+    }
+    let field = |key: &str| {
+        fields
+            .get(key)
+            .map(|x| x.as_str())
+            .ok_or_else(|| Error::Decode(format!("MetaImage field \"{}\" is missing", key)))
+    };
+
+    let element_type = field("ElementType")?;
+    let datatype = parse_element_type(element_type)
+        .ok_or_else(|| Error::UnsupportedDataType(format!("MetaImage {}", element_type)))?;
+    let channels = fields
+        .get("ElementNumberOfChannels")
+        .and_then(|x| x.parse::<usize>().ok())
+        .unwrap_or(1);
+    if channels != 1 {
+        return Err(Error::UnsupportedDataType(format!(
+            "MetaImage with {} channels",
+            channels
+        )));
+    }
+    let sizes: Vec<usize> = parse_numbers(&fields, "DimSize")?
+        .unwrap_or_default()
+        .into_iter()
+        .map(|x| x as usize)
+        .collect();
+    if !(3..=4).contains(&sizes.len()) {
+        return Err(Error::ShapeMismatch(format!(
+            "MetaImage with DimSize {:?} is not a 3D or 4D volume",
+            sizes
+        )));
+    }
+    let n_voxels: usize = sizes.iter().product();
+    let expected = n_voxels * datatype.size_of();
+    let big_endian =
+        is_true(&fields, "BinaryDataByteOrderMSB") || is_true(&fields, "ElementByteOrderMSB");
+
+    let data_file = field("ElementDataFile")?;
+    let mut data_bytes = if data_file == "LOCAL" {
+        bytes[offset..].to_vec()
+    } else if data_file.starts_with("LIST") || data_file.contains('%') {
+        return Err(Error::Decode(format!(
+            "unsupported MetaImage ElementDataFile : {}",
+            data_file
+        )));
+    } else {
+        let dir = data_path.parent().unwrap_or_else(|| Path::new("."));
+        super::read_file(&dir.join(data_file), progress)?
+    };
+    if is_true(&fields, "CompressedData") {
+        let mut decoded = Vec::with_capacity(expected);
+        flate2::read::ZlibDecoder::new(data_bytes.as_slice())
+            .read_to_end(&mut decoded)
+            .map_err(|e| Error::Decode(format!("MetaImage zlib : {}", e)))?;
+        data_bytes = decoded;
+    } else {
+        match fields.get("HeaderSize").and_then(|x| x.parse::<i64>().ok()) {
+            // dataはファイルの末尾にある
+            Some(-1) if data_bytes.len() >= expected => {
+                data_bytes.drain(..data_bytes.len() - expected);
+            }
+            Some(skip) if skip > 0 => {
+                data_bytes.drain(..(skip as usize).min(data_bytes.len()));
+            }
+            _ => (),
+        }
+    }
+    if data_bytes.len() < expected {
+        return Err(Error::TruncatedData {
+            expected,
+            actual: data_bytes.len(),
+        });
+    }
+    let data = decode_values(&data_bytes[..expected], datatype, big_endian);
+
+    // affine (LPS -> RAS). TransformMatrixは軸ごとの方向ベクトルを順に並べたもの
+    let n_dims = sizes.len();
+    let spacing = parse_numbers(&fields, "ElementSpacing")?
+        .or(parse_numbers(&fields, "ElementSize")?)
+        .unwrap_or_else(|| vec![1.0; n_dims]);
+    let origin = parse_numbers(&fields, "Offset")?
+        .or(parse_numbers(&fields, "Origin")?)
+        .or(parse_numbers(&fields, "Position")?)
+        .unwrap_or_else(|| vec![0.0; n_dims]);
+    let transform = parse_numbers(&fields, "TransformMatrix")?
+        .or(parse_numbers(&fields, "Rotation")?)
+        .or(parse_numbers(&fields, "Orientation")?)
+        .filter(|x| x.len() == n_dims * n_dims);
+    let mut affine = [[0.0f32; 4]; 4];
+    for col in 0..3 {
+        let spacing = spacing.get(col).copied().unwrap_or(1.0);
+        for row in 0..3 {
+            let direction = match &transform {
+                Some(transform) => transform[col * n_dims + row],
+                None => (row == col) as u8 as f64,
+            };
+            affine[row][col] = (direction * spacing) as f32 * LPS_SIGNS[row];
+        }
+        affine[col][3] = origin.get(col).copied().unwrap_or(0.0) as f32 * LPS_SIGNS[col];
+    }
+    affine[3][3] = 1.0;
+    let spacing = [0, 1, 2].map(|i| spacing.get(i).copied().unwrap_or(1.0).abs() as f32);
+
+    info!("MetaImage : {:?} {:?}", sizes, datatype);
+    Ok(Image3D {
+        data,
+        shape: (sizes[0] as u32, sizes[1] as u32, sizes[2] as u32),
+        spacing: (spacing[0], spacing[1], spacing[2]),
+        frames: sizes.get(3).copied().unwrap_or(1) as u32,
+        frame_interval: 0.0,
+        datatype,
+        scl_slope: 0.0,
+        scl_inter: 0.0,
+        affine,
+        qform_code: 0,
+        sform_code: 1,
+        format: Some(UncompressedFloatFormat::F32),
+        mipmaps: Some(MipmapsOption::NoMipmap),
+        lut: None,
+        chunked: None,
+        is_mask: false,
+    })
+}
+
+fn join(values: &[f32]) -> String {
+    values
+        .iter()
+        .map(|x| (x + 0.0).to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// .mhaはzlib圧縮したdataをheaderに続けて書く. .mhdは同名の.rawに非圧縮で書く
+pub fn save(image: &Image3D, data_path: &Path) -> Result<()> {
+    // MetaImageにはscl_slope, scl_interがないのでrescale済みの値はfloatで書く
+    let datatype = if image.scl_slope != 0.0 {
+        DataType::F32
+    } else {
+        image.datatype
+    };
+    let data = encode_values(&image.data, datatype, false);
+    let frames = image.frames.max(1);
+    let n_dims = if frames > 1 { 4 } else { 3 };
+    let spacing = [image.spacing.0, image.spacing.1, image.spacing.2];
+
+    // RAS -> LPS, 方向ベクトルは長さ1にする
+    let mut transform = vec![0.0f32; n_dims * n_dims];
+    for col in 0..3 {
+        let norm = (0..3)
+            .map(|row| image.affine[row][col].powi(2))
+            .sum::<f32>()
+            .sqrt();
+        for row in 0..3 {
+            transform[col * n_dims + row] = if norm > 0.0 {
+                image.affine[row][col] / norm * LPS_SIGNS[row]
+            } else {
+                (row == col) as u8 as f32
+            };
+        }
+    }
+    let mut offset: Vec<f32> = (0..3)
+        .map(|row| image.affine[row][3] * LPS_SIGNS[row])
+        .collect();
+    let mut dim_size = vec![image.shape.0, image.shape.1, image.shape.2];
+    let mut spacing = spacing.to_vec();
+    if n_dims == 4 {
+        transform[15] = 1.0;
+        offset.push(0.0);
+        dim_size.push(frames);
+        spacing.push(1.0);
+    }
+
+    let is_mha = super::file_name(data_path).ends_with(".mha");
+    let compressed = if is_mha {
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&data)?;
+        Some(encoder.finish()?)
+    } else {
+        None
+    };
+    let raw_path = data_path.with_extension("raw");
+
+    let mut header = String::from("ObjectType = Image\n");
+    header += &format!("NDims = {}\n", n_dims);
+    header += "BinaryData = True\n";
+    header += "BinaryDataByteOrderMSB = False\n";
+    match &compressed {
+        Some(compressed) => {
+            header += "CompressedData = True\n";
+            header += &format!("CompressedDataSize = {}\n", compressed.len());
+        }
+        None => header += "CompressedData = False\n",
+    }
+    header += &format!("TransformMatrix = {}\n", join(&transform));
+    header += &format!("Offset = {}\n", join(&offset));
+    header += &format!("ElementSpacing = {}\n", join(&spacing));
+    header += &format!(
+        "DimSize = {}\n",
+        dim_size
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+    header += &format!("ElementType = {}\n", element_type_name(datatype));
+
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(data_path)?);
+    match &compressed {
+        Some(compressed) => {
+            header += "ElementDataFile = LOCAL\n";
+            writer.write_all(header.as_bytes())?;
+            writer.write_all(compressed)?;
+        }
+        None => {
+            let raw_name = raw_path
+                .file_name()
+                .and_then(|x| x.to_str())
+                .unwrap_or_default();
+            header += &format!("ElementDataFile = {}\n", raw_name);
+            writer.write_all(header.as_bytes())?;
+            std::fs::write(&raw_path, &data)?;
+        }
+    }
+    writer.flush()?;
+    info!("Saved MetaImage file to {:?}", data_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::test_util::{assert_same_affine, temp_dir, volume};
+
+    /// 軸がz軸の周りに90度回転しているvolume
+    fn rotated_volume() -> Image3D {
+        let mut image = volume((5, 4, 3), 2, DataType::U16);
+        image.spacing = (0.8, 0.5, 2.5);
+        image.affine = [
+            [0.0, -0.5, 0.0, 3.0],
+            [0.8, 0.0, 0.0, -4.0],
+            [0.0, 0.0, 2.5, 5.5],
+            [0.0, 0.0, 0.0, 1.0],
+        ];
+        image
+    }
+
+    fn assert_round_trip(file_name: &str) -> std::path::PathBuf {
+        let dir = temp_dir(&format!("metaimage_{}", file_name));
+        let path = dir.join(file_name);
+        let image = rotated_volume();
+        save(&image, &path).unwrap();
+        let loaded = load(&path, &Progress::default()).unwrap();
+        assert_eq!(loaded.shape, image.shape);
+        assert_eq!(loaded.frames, 2);
+        assert_eq!(loaded.datatype, DataType::U16);
+        assert_eq!(loaded.spacing, image.spacing);
+        assert_same_affine(&loaded.affine, &image.affine);
+        assert_eq!(loaded.data, image.data);
+        dir
+    }
+
+    #[test]
+    fn round_trip_mha() {
+        let dir = assert_round_trip("image.mha");
+        // headerとzlib圧縮したdataが1つのファイルにある
+        assert!(!dir.join("image.raw").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn round_trip_mhd() {
+        let dir = assert_round_trip("image.mhd");
+        // 対になる.rawを指定しても.mhdのheaderで読む
+        let loaded = load(&dir.join("image.raw"), &Progress::default()).unwrap();
+        assert_eq!(loaded.data, rotated_volume().data);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn transform_matrix_is_per_axis() {
+        let dir = temp_dir("metaimage_transform");
+        let path = dir.join("inline.mha");
+        // 1番目の軸はLPSの+P, 2番目の軸は-L
+        let mut bytes = b"ObjectType = Image\n\
+            NDims = 3\n\
+            TransformMatrix = 0 1 0 -1 0 0 0 0 1\n\
+            Offset = 1 2 3\n\
+            ElementSpacing = 2 3 4\n\
+            DimSize = 2 1 1\n\
+            ElementType = MET_SHORT\n\
+            ElementDataFile = LOCAL\n"
+            .to_vec();
+        bytes.extend([-5i16, 7].iter().flat_map(|x| x.to_le_bytes()));
+        std::fs::write(&path, bytes).unwrap();
+
+        let image = load(&path, &Progress::default()).unwrap();
+        assert_eq!(image.data, vec![-5.0, 7.0]);
+        assert_same_affine(
+            &image.affine,
+            &[
+                [0.0, 3.0, 0.0, -1.0],
+                [-2.0, 0.0, 0.0, -2.0],
+                [0.0, 0.0, 4.0, 3.0],
+                [0.0, 0.0, 0.0, 1.0],
+            ],
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,262 @@
+use std::io::Read;
+use std::path::Path;
+
+use glium::texture::{MipmapsOption, UncompressedFloatFormat};
+use tracing::{debug, info};
+
+use super::{decode_values, DataType, Error, Image3D, Progress, Result};
+
+// header (big endian) の後にvoxelデータが続く
+const HEADER_SIZE: usize = 284;
+const MRI_UCHAR: i32 = 0;
+const MRI_INT: i32 = 1;
+const MRI_FLOAT: i32 = 3;
+const MRI_SHORT: i32 = 4;
+
+fn read_i32(bytes: &[u8], offset: usize) -> i32 {
+    i32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_f32(bytes: &[u8], offset: usize) -> f32 {
+    f32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// FreeSurfer MGH (.mgh) / gzip圧縮したMGH (.mgz) を読む
+pub fn load(data_path: &Path, progress: &Progress) -> Result<Image3D> {
+    debug!("Loading mgh file");
+    let mut bytes = super::read_file(data_path, progress)?;
+    if bytes.starts_with(&[0x1f, 0x8b]) {
+        let mut decoded = Vec::new();
+        flate2::read::MultiGzDecoder::new(bytes.as_slice())
+            .read_to_end(&mut decoded)
+            .map_err(|e| Error::Decode(format!("MGZ gzip : {}", e)))?;
+        bytes = decoded;
+    }
+    if bytes.len() < HEADER_SIZE {
+        return Err(Error::TruncatedData {
+            expected: HEADER_SIZE,
+            actual: bytes.len(),
+        });
+    }
+    let version = read_i32(&bytes, 0);
+    if version != 1 {
+        return Err(Error::Decode(format!("unknown MGH version : {}", version)));
+    }
+    let dims = [4, 8, 12].map(|offset| read_i32(&bytes, offset).max(0) as usize);
+    let frames = read_i32(&bytes, 16).max(1) as usize;
+    let datatype = match read_i32(&bytes, 20) {
+        MRI_UCHAR => DataType::U8,
+        MRI_INT => DataType::I32,
+        MRI_FLOAT => DataType::F32,
+        MRI_SHORT => DataType::I16,
+        x => return Err(Error::UnsupportedDataType(format!("MGH type {}", x))),
+    };
+    let good_ras_flag = i16::from_be_bytes([bytes[28], bytes[29]]);
+    let spacing = [30, 34, 38].map(|offset| read_f32(&bytes, offset));
+
+    let expected = dims
+        .iter()
+        .try_fold(frames, |n, x| n.checked_mul(*x))
+        .and_then(|x| x.checked_mul(datatype.size_of()))
+        .ok_or_else(|| {
+            Error::ShapeMismatch(format!(
+                "invalid MGH shape : {:?} x {} frames",
+                dims, frames
+            ))
+        })?;
+    let data_bytes = &bytes[HEADER_SIZE..];
+    if data_bytes.len() < expected {
+        return Err(Error::TruncatedData {
+            expected,
+            actual: data_bytes.len(),
+        });
+    }
+    let data = decode_values(&data_bytes[..expected], datatype, true);
+    // dataの後にTR(ms), flip angle, TE, TI, FoVが続く(省略可)
+    let tr = (data_bytes.len() >= expected + 4)
+        .then(|| read_f32(data_bytes, expected))
+        .filter(|x| x.is_finite() && *x > 0.0);
+
+    // vox2ras = [Mdc * D | c_ras - Mdc * D * dims / 2]
+    let (mdc, c_ras) = if good_ras_flag > 0 {
+        let mut mdc = [[0.0f32; 3]; 3];
+        for (col, offset) in [42, 54, 66].into_iter().enumerate() {
+            for (row, val) in mdc.iter_mut().enumerate() {
+                val[col] = read_f32(&bytes, offset + 4 * row);
+            }
+        }
+        let c_ras = [78, 82, 86].map(|offset| read_f32(&bytes, offset));
+        (mdc, c_ras)
+    } else {
+        // 向きの情報がない場合はFreeSurferのconformed空間 (coronal, LIA)
+        (
+            [[-1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, -1.0, 0.0]],
+            [0.0; 3],
+        )
+    };
+    let mut affine = [[0.0f32; 4]; 4];
+    for row in 0..3 {
+        for col in 0..3 {
+            affine[row][col] = mdc[row][col] * spacing[col];
+        }
+        affine[row][3] = c_ras[row]
+            - (0..3)
+                .map(|col| affine[row][col] * dims[col] as f32 / 2.0)
+                .sum::<f32>();
+    }
+    affine[3][3] = 1.0;
+
+    info!("MGH : {:?} x {} frames, {:?}", dims, frames, datatype);
+    Ok(Image3D {
+        data,
+        shape: (dims[0] as u32, dims[1] as u32, dims[2] as u32),
+        spacing: (spacing[0], spacing[1], spacing[2]),
+        frames: frames as u32,
+        frame_interval: match tr {
+            Some(tr) if frames > 1 => tr / 1000.0,
+            _ => 0.0,
+        },
+        datatype,
+        scl_slope: 0.0,
+        scl_inter: 0.0,
+        affine,
+        qform_code: 0,
+        sform_code: if good_ras_flag > 0 { 1 } else { 0 },
+        format: Some(UncompressedFloatFormat::F32),
+        mipmaps: Some(MipmapsOption::NoMipmap),
+        lut: None,
+        chunked: None,
+        is_mask: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::test_util::{assert_same_affine, temp_dir};
+    use std::io::Write;
+
+    const SHAPE: [i32; 3] = [4, 3, 2];
+
+    /// MRI_SHORTのMGHのbyte列. `orientation`は(Mdcの各列, c_ras)
+    fn mgh(
+        frames: i32,
+        spacing: [f32; 3],
+        orientation: Option<([[f32; 3]; 3], [f32; 3])>,
+        tr: Option<f32>,
+    ) -> Vec<u8> {
+        let mut bytes = vec![0; HEADER_SIZE];
+        let mut put = |offset: usize, value: [u8; 4]| {
+            bytes[offset..offset + 4].copy_from_slice(&value);
+        };
+        put(0, 1i32.to_be_bytes());
+        for (i, n) in SHAPE.iter().enumerate() {
+            put(4 + 4 * i, n.to_be_bytes());
+        }
+        put(16, frames.to_be_bytes());
+        put(20, MRI_SHORT.to_be_bytes());
+        for (i, x) in spacing.iter().enumerate() {
+            put(30 + 4 * i, x.to_be_bytes());
+        }
+        if let Some((mdc, c_ras)) = orientation {
+            for (i, x) in mdc.iter().flatten().chain(c_ras.iter()).enumerate() {
+                put(42 + 4 * i, x.to_be_bytes());
+            }
+        }
+        bytes[28..30].copy_from_slice(&(orientation.is_some() as i16).to_be_bytes());
+        let n_voxels = SHAPE.iter().product::<i32>() * frames;
+        bytes.extend((0..n_voxels).flat_map(|x| (x as i16 - 5).to_be_bytes()));
+        if let Some(tr) = tr {
+            bytes.extend(tr.to_be_bytes());
+        }
+        bytes
+    }
+
+    fn write(dir: &Path, name: &str, bytes: &[u8]) -> std::path::PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn vox2ras_from_header() {
+        let dir = temp_dir("mgh_vox2ras");
+        // voxel軸 x -> L, y -> I, z -> A
+        let mdc = [[-1.0, 0.0, 0.0], [0.0, 0.0, -1.0], [0.0, 1.0, 0.0]];
+        let bytes = mgh(1, [1.0, 2.0, 3.0], Some((mdc, [10.0, 20.0, 30.0])), None);
+        let image = load(&write(&dir, "a.mgh", &bytes), &Progress::default()).unwrap();
+        assert_eq!(image.shape, (4, 3, 2));
+        assert_eq!(image.datatype, DataType::I16);
+        assert_eq!(image.data[..3], [-5.0, -4.0, -3.0]);
+        assert_eq!(image.sform_code, 1);
+        let expected = [
+            [-1.0, 0.0, 0.0, 12.0],
+            [0.0, 0.0, 3.0, 17.0],
+            [0.0, -2.0, 0.0, 33.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ];
+        assert_same_affine(&image.affine, &expected);
+        // volumeの中心がc_ras
+        assert_eq!(image.voxel_to_world([2.0, 1.5, 1.0]), [10.0, 20.0, 30.0]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn conformed_orientation_without_ras() {
+        let dir = temp_dir("mgh_lia");
+        let bytes = mgh(1, [1.0, 1.0, 1.0], None, None);
+        let image = load(&write(&dir, "a.mgh", &bytes), &Progress::default()).unwrap();
+        assert_eq!(image.sform_code, 0);
+        let expected = [
+            [-1.0, 0.0, 0.0, 2.0],
+            [0.0, 0.0, 1.0, -1.0],
+            [0.0, -1.0, 0.0, 1.5],
+            [0.0, 0.0, 0.0, 1.0],
+        ];
+        assert_same_affine(&image.affine, &expected);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn frame_interval_from_tr() {
+        let dir = temp_dir("mgh_tr");
+        let bytes = mgh(3, [1.0, 1.0, 1.0], None, Some(2000.0));
+        let image = load(&write(&dir, "a.mgh", &bytes), &Progress::default()).unwrap();
+        assert_eq!(image.frames, 3);
+        assert_eq!(image.frame_interval, 2.0);
+        // 1 frameのvolumeのTRは使わない
+        let bytes = mgh(1, [1.0, 1.0, 1.0], None, Some(2000.0));
+        let image = load(&write(&dir, "b.mgh", &bytes), &Progress::default()).unwrap();
+        assert_eq!(image.frame_interval, 0.0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn gzipped_mgz() {
+        let dir = temp_dir("mgh_mgz");
+        let bytes = mgh(2, [1.0, 1.0, 1.0], None, None);
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(&bytes).unwrap();
+        let path = write(&dir, "a.mgz", &encoder.finish().unwrap());
+        let image = load(&path, &Progress::default()).unwrap();
+        assert_eq!(image.frames, 2);
+        assert_eq!(image.data.len(), 48);
+        assert_eq!(image.data[47], 42.0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn overflowing_shape() {
+        let dir = temp_dir("mgh_overflow");
+        let mut bytes = mgh(1, [1.0, 1.0, 1.0], None, None);
+        for offset in [4, 8, 12] {
+            bytes[offset..offset + 4].copy_from_slice(&i32::MAX.to_be_bytes());
+        }
+        let path = write(&dir, "a.mgh", &bytes);
+        assert!(matches!(
+            load(&path, &Progress::default()),
+            Err(Error::ShapeMismatch(_))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,413 @@
+use std::io::Write;
+use std::path::Path;
+
+use ::nifti::NiftiObject;
+use glium::texture::{MipmapsOption, UncompressedFloatFormat};
+use tracing::{debug, info};
+
+use super::{encode_values, rescale, DataType, Error, Image3D, Progress, Result};
+
+const HEADER_SIZE: usize = 348;
+const VOX_OFFSET: usize = 352;
+const XFORM_SCANNER_ANAT: i16 = 1;
+const UNITS_MM: u8 = 2;
+const UNITS_SEC: u8 = 8;
+const UNITS_MSEC: u8 = 16;
+const UNITS_USEC: u8 = 24;
+
+/// NIfTI volumeを格納されている型のまま(rescaleせずに)読み出し、f32に変換する
+macro_rules! volume_to_f32 {
+    ($volume:expr, $typ:ty) => {
+        $volume
+            .into_nifti_typed_data::<$typ>()
+            .map_err(|e| Error::Decode(e.to_string()))?
+            .into_iter()
+            .map(|x: $typ| x as f32)
+            .collect::<Vec<f32>>()
+    };
+}
+
+/// NIfTI headerのqform/sformからaffineを計算する (sform優先, どちらもなければspacingのみ)
+fn nifti_affine(header: &::nifti::NiftiHeader) -> [[f32; 4]; 4] {
+    if header.sform_code > 0 {
+        return [
+            header.srow_x,
+            header.srow_y,
+            header.srow_z,
+            [0.0, 0.0, 0.0, 1.0],
+        ];
+    }
+    let pixdim = header.pixdim;
+    if header.qform_code <= 0 {
+        return Image3D::spacing_affine((pixdim[1], pixdim[2], pixdim[3]));
+    }
+    let (b, c, d) = (header.quatern_b, header.quatern_c, header.quatern_d);
+    let a = (1.0 - (b * b + c * c + d * d)).max(0.0).sqrt();
+    let qfac = if pixdim[0] < 0.0 { -1.0 } else { 1.0 };
+    let rotation = [
+        [
+            a * a + b * b - c * c - d * d,
+            2.0 * (b * c - a * d),
+            2.0 * (b * d + a * c),
+        ],
+        [
+            2.0 * (b * c + a * d),
+            a * a + c * c - b * b - d * d,
+            2.0 * (c * d - a * b),
+        ],
+        [
+            2.0 * (b * d - a * c),
+            2.0 * (c * d + a * b),
+            a * a + d * d - b * b - c * c,
+        ],
+    ];
+    let scale = [pixdim[1], pixdim[2], pixdim[3] * qfac];
+    let offset = [header.quatern_x, header.quatern_y, header.quatern_z];
+    let mut affine = [[0.0; 4]; 4];
+    for row in 0..3 {
+        for col in 0..3 {
+            affine[row][col] = rotation[row][col] * scale[col];
+        }
+        affine[row][3] = offset[row];
+    }
+    affine[3][3] = 1.0;
+    affine
+}
+
+pub fn load(data_path: &Path, progress: &Progress) -> Result<Image3D> {
+    debug!("Loading nifti file");
+    let name = super::file_name(data_path);
+    let obj = if name.ends_with(".hdr.gz") {
+        // header/volumeが別ファイルのものはnifti crateに任せる
+        ::nifti::ReaderOptions::new().read_file(data_path)
+    } else {
+        let file = std::fs::File::open(data_path)?;
+        let len = file.metadata()?.len();
+        let reader = std::io::BufReader::new(progress.reader(file, len));
+        if name.ends_with(".gz") {
+            ::nifti::InMemNiftiObject::from_reader(flate2::read::GzDecoder::new(reader))
+        } else {
+            ::nifti::InMemNiftiObject::from_reader(reader)
+        }
+    }
+    .map_err(|e| match e {
+        ::nifti::NiftiError::Io(e) => Error::Io(e),
+        ::nifti::NiftiError::IncompatibleLength(actual, expected) => {
+            Error::TruncatedData { expected, actual }
+        }
+        e => Error::Decode(e.to_string()),
+    })?;
+    debug!("Loaded nifti file");
+    let header = obj.header().clone();
+    let dim = header.dim;
+    let spacing = header.pixdim;
+
+    let datatype = match DataType::from_nifti_code(header.datatype) {
+        Some(datatype) => datatype,
+        None => return Err(Error::UnsupportedDataType(header.datatype.to_string())),
+    };
+    let volume = obj.into_volume();
+    let mut data = match datatype {
+        DataType::U8 => volume_to_f32!(volume, u8),
+        DataType::I8 => volume_to_f32!(volume, i8),
+        DataType::U16 => volume_to_f32!(volume, u16),
+        DataType::I16 => volume_to_f32!(volume, i16),
+        DataType::U32 => volume_to_f32!(volume, u32),
+        DataType::I32 => volume_to_f32!(volume, i32),
+        DataType::U64 => volume_to_f32!(volume, u64),
+        DataType::I64 => volume_to_f32!(volume, i64),
+        DataType::F32 => volume_to_f32!(volume, f32),
+        DataType::F64 => volume_to_f32!(volume, f64),
+    };
+    // dim[4]以降(時間, その他)はframeとして並べる
+    let frames = (4..=(dim[0] as usize).min(7))
+        .map(|i| dim[i].max(1) as u32)
+        .product::<u32>();
+    let frame_interval = match header.xyzt_units & 0x38 {
+        UNITS_MSEC => spacing[4] * 1e-3,
+        UNITS_USEC => spacing[4] * 1e-6,
+        _ => spacing[4],
+    };
+    if frames > 1 {
+        info!("{} frames, interval : {} s", frames, frame_interval);
+    }
+    let (scl_slope, scl_inter) = if rescale(&mut data, header.scl_slope, header.scl_inter) {
+        debug!(
+            "Rescaled with slope : {}, inter : {}",
+            header.scl_slope, header.scl_inter
+        );
+        (header.scl_slope, header.scl_inter)
+    } else {
+        (0.0, 0.0)
+    };
+
+    Ok(Image3D {
+        data,
+        shape: (dim[1] as u32, dim[2] as u32, dim[3] as u32),
+        spacing: (spacing[1], spacing[2], spacing[3]),
+        frames,
+        frame_interval: if frames > 1 { frame_interval } else { 0.0 },
+        datatype,
+        scl_slope,
+        scl_inter,
+        affine: nifti_affine(&header),
+        qform_code: header.qform_code,
+        sform_code: header.sform_code,
+        format: Some(UncompressedFloatFormat::F32),
+        mipmaps: Some(MipmapsOption::NoMipmap),
+        lut: None,
+        chunked: None,
+        is_mask: false,
+    })
+}
+
+/// affineの回転部分からqformのquaternion (b, c, d) とqfacを求める
+fn affine_to_quatern(affine: &[[f32; 4]; 4]) -> ([f32; 3], f32) {
+    let mut r = [[0.0f64; 3]; 3];
+    for col in 0..3 {
+        let norm = (0..3)
+            .map(|row| (affine[row][col] as f64).powi(2))
+            .sum::<f64>()
+            .sqrt();
+        for row in 0..3 {
+            r[row][col] = if norm > 0.0 {
+                affine[row][col] as f64 / norm
+            } else if row == col {
+                1.0
+            } else {
+                0.0
+            };
+        }
+    }
+    let det = r[0][0] * (r[1][1] * r[2][2] - r[1][2] * r[2][1])
+        - r[0][1] * (r[1][0] * r[2][2] - r[1][2] * r[2][0])
+        + r[0][2] * (r[1][0] * r[2][1] - r[1][1] * r[2][0]);
+    let qfac = if det < 0.0 {
+        for row in r.iter_mut() {
+            row[2] = -row[2];
+        }
+        -1.0
+    } else {
+        1.0
+    };
+    // nifti1_io.cのnifti_mat44_to_quaternと同じ手順
+    let trace = r[0][0] + r[1][1] + r[2][2] + 1.0;
+    let (a, b, c, d) = if trace > 0.5 {
+        let a = 0.5 * trace.sqrt();
+        (
+            a,
+            0.25 * (r[2][1] - r[1][2]) / a,
+            0.25 * (r[0][2] - r[2][0]) / a,
+            0.25 * (r[1][0] - r[0][1]) / a,
+        )
+    } else {
+        let xd = 1.0 + r[0][0] - (r[1][1] + r[2][2]);
+        let yd = 1.0 + r[1][1] - (r[0][0] + r[2][2]);
+        let zd = 1.0 + r[2][2] - (r[0][0] + r[1][1]);
+        if xd > 1.0 {
+            let b = 0.5 * xd.sqrt();
+            (
+                0.25 * (r[2][1] - r[1][2]) / b,
+                b,
+                0.25 * (r[0][1] + r[1][0]) / b,
+                0.25 * (r[0][2] + r[2][0]) / b,
+            )
+        } else if yd > 1.0 {
+            let c = 0.5 * yd.sqrt();
+            (
+                0.25 * (r[0][2] - r[2][0]) / c,
+                0.25 * (r[0][1] + r[1][0]) / c,
+                c,
+                0.25 * (r[1][2] + r[2][1]) / c,
+            )
+        } else {
+            let d = 0.5 * zd.sqrt();
+            (
+                0.25 * (r[1][0] - r[0][1]) / d,
+                0.25 * (r[0][2] + r[2][0]) / d,
+                0.25 * (r[1][2] + r[2][1]) / d,
+                d,
+            )
+        }
+    };
+    let sign = if a < 0.0 { -1.0 } else { 1.0 };
+    (
+        [(sign * b) as f32, (sign * c) as f32, (sign * d) as f32],
+        qfac,
+    )
+}
+
+/// NIfTI-1 headerを組み立てる (little endian, 単一ファイル形式).
+/// dimはi16なので32767を超える大きさは書けない
+fn header_bytes(image: &Image3D) -> Result<Vec<u8>> {
+    let mut header = vec![0u8; VOX_OFFSET];
+    let mut put = |offset: usize, bytes: &[u8]| {
+        header[offset..offset + bytes.len()].copy_from_slice(bytes);
+    };
+    let sform_code = if image.sform_code > 0 {
+        image.sform_code
+    } else {
+        XFORM_SCANNER_ANAT
+    };
+    let qform_code = if image.qform_code > 0 {
+        image.qform_code
+    } else {
+        sform_code
+    };
+    let (quatern, qfac) = affine_to_quatern(&image.affine);
+    let sizes = [
+        image.shape.0,
+        image.shape.1,
+        image.shape.2,
+        image.frames.max(1),
+    ];
+    let mut dim: [i16; 8] = [if image.frames > 1 { 4 } else { 3 }, 1, 1, 1, 1, 1, 1, 1];
+    for (val, size) in dim[1..].iter_mut().zip(sizes) {
+        *val = i16::try_from(size).map_err(|_| {
+            Error::ShapeMismatch(format!(
+                "NIfTI-1 cannot store shape {:?} with {} frames",
+                image.shape, image.frames
+            ))
+        })?;
+    }
+    let pixdim: [f32; 8] = [
+        qfac,
+        image.spacing.0.abs(),
+        image.spacing.1.abs(),
+        image.spacing.2.abs(),
+        image.frame_interval,
+        0.0,
+        0.0,
+        0.0,
+    ];
+
+    put(0, &(HEADER_SIZE as i32).to_le_bytes());
+    put(38, b"r");
+    for (i, val) in dim.iter().enumerate() {
+        put(40 + 2 * i, &val.to_le_bytes());
+    }
+    put(70, &image.datatype.nifti_code().to_le_bytes());
+    put(72, &((image.datatype.size_of() * 8) as i16).to_le_bytes());
+    for (i, val) in pixdim.iter().enumerate() {
+        put(76 + 4 * i, &val.to_le_bytes());
+    }
+    put(108, &(VOX_OFFSET as f32).to_le_bytes());
+    put(112, &image.scl_slope.to_le_bytes());
+    put(116, &image.scl_inter.to_le_bytes());
+    put(123, &[UNITS_MM | UNITS_SEC]);
+    put(148, b"viewer3d");
+    put(252, &qform_code.to_le_bytes());
+    put(254, &sform_code.to_le_bytes());
+    for (i, val) in quatern.iter().enumerate() {
+        put(256 + 4 * i, &val.to_le_bytes());
+    }
+    for row in 0..3 {
+        put(268 + 4 * row, &image.affine[row][3].to_le_bytes());
+    }
+    for row in 0..3 {
+        for col in 0..4 {
+            put(
+                280 + 16 * row + 4 * col,
+                &image.affine[row][col].to_le_bytes(),
+            );
+        }
+    }
+    put(344, b"n+1\0");
+    Ok(header)
+}
+
+/// NIfTI-1 (.nii, .nii.gz) として保存する. scl_slope, scl_interを戻して元の型で書き込む
+pub fn save(image: &Image3D, data_path: &Path) -> Result<()> {
+    let header = header_bytes(image)?;
+    let data = image.stored_values();
+    let mut file = std::io::BufWriter::new(std::fs::File::create(data_path)?);
+    let data = encode_values(&data, image.datatype, false);
+    if data_path.extension().is_some_and(|x| x == "gz") {
+        // gzipのtrailerはfinishで書かれる (dropでは書き込みのエラーがわからない)
+        let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+        encoder.write_all(&header)?;
+        encoder.write_all(&data)?;
+        encoder.finish()?.flush()?;
+    } else {
+        file.write_all(&header)?;
+        file.write_all(&data)?;
+        file.flush()?;
+    }
+    info!("Saved nifti file to {:?}", data_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::test_util::{assert_same_affine, lps_affine, temp_dir, volume};
+
+    /// i16で格納され, slope, interでrescaleされた2 frameのvolume
+    fn rescaled_volume() -> Image3D {
+        let spacing = (0.8, 0.5, 2.5);
+        let mut image = volume((5, 4, 3), 2, DataType::I16);
+        image
+            .data
+            .iter_mut()
+            .for_each(|x| *x = (*x - 30.0) * 2.0 - 1024.0);
+        image.scl_slope = 2.0;
+        image.scl_inter = -1024.0;
+        image.spacing = spacing;
+        image.affine = lps_affine(spacing);
+        image.sform_code = 2;
+        image.frame_interval = 1.5;
+        image
+    }
+
+    fn assert_round_trip(file_name: &str) {
+        let dir = temp_dir(&format!("nifti_{}", file_name));
+        let path = dir.join(file_name);
+        let image = rescaled_volume();
+        save(&image, &path).unwrap();
+        let loaded = load(&path, &Progress::default()).unwrap();
+        assert_eq!(loaded.shape, image.shape);
+        assert_eq!(loaded.frames, image.frames);
+        assert_eq!(loaded.frame_interval, image.frame_interval);
+        assert_eq!(loaded.spacing, image.spacing);
+        assert_eq!(loaded.datatype, DataType::I16);
+        assert_eq!((loaded.scl_slope, loaded.scl_inter), (2.0, -1024.0));
+        assert_eq!(loaded.sform_code, 2);
+        assert_same_affine(&loaded.affine, &image.affine);
+        assert_eq!(loaded.data, image.data);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn round_trip_nii() {
+        assert_round_trip("image.nii");
+    }
+
+    #[test]
+    fn round_trip_nii_gz() {
+        assert_round_trip("image.nii.gz");
+    }
+
+    #[test]
+    fn qform_matches_affine() {
+        let image = rescaled_volume();
+        let header =
+            ::nifti::NiftiHeader::from_reader(std::io::Cursor::new(header_bytes(&image).unwrap()))
+                .unwrap();
+        // sformがない場合に使われるqformも同じaffineになる
+        let header = ::nifti::NiftiHeader {
+            sform_code: 0,
+            ..header
+        };
+        assert_same_affine(&nifti_affine(&header), &image.affine);
+    }
+
+    #[test]
+    fn reject_too_large_dimension() {
+        let image = volume((40000, 1, 1), 1, DataType::U8);
+        let dir = temp_dir("nifti_large");
+        let path = dir.join("large.nii");
+        assert!(matches!(save(&image, &path), Err(Error::ShapeMismatch(_))));
+        assert!(!path.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
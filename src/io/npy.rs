@@ -0,0 +1,628 @@
+use std::io::{Read, Write};
+use std::path::Path;
+
+use glium::texture::{MipmapsOption, UncompressedFloatFormat};
+use tracing::{debug, info};
+
+use super::{decode_values, encode_values, DataType, Error, Image3D, Progress, Result};
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+/// `descr` (例: `<f4`, `>i2`, `|u1`) を型とendianに変換する.
+/// float16とboolはDataTypeにないのでそれぞれf32, u8として扱う
+pub(super) fn parse_descr(descr: &str) -> Result<(NpyType, bool)> {
+    let big_endian = match descr.chars().next() {
+        Some('>') => true,
+        Some('<') => false,
+        Some('=') => cfg!(target_endian = "big"),
+        _ => false,
+    };
+    let code = descr.trim_start_matches(['<', '>', '=', '|']);
+    let npy_type = match code {
+        "b1" | "?" => NpyType::Bool,
+        "u1" | "B" => NpyType::Plain(DataType::U8),
+        "i1" | "b" => NpyType::Plain(DataType::I8),
+        "u2" => NpyType::Plain(DataType::U16),
+        "i2" => NpyType::Plain(DataType::I16),
+        "u4" => NpyType::Plain(DataType::U32),
+        "i4" => NpyType::Plain(DataType::I32),
+        "u8" => NpyType::Plain(DataType::U64),
+        "i8" => NpyType::Plain(DataType::I64),
+        "f2" | "e" => NpyType::F16,
+        "f4" => NpyType::Plain(DataType::F32),
+        "f8" => NpyType::Plain(DataType::F64),
+        _ => return Err(Error::UnsupportedDataType(format!("NumPy dtype {}", descr))),
+    };
+    Ok((npy_type, big_endian))
+}
+
+fn descr(datatype: DataType) -> &'static str {
+    match datatype {
+        DataType::U8 => "|u1",
+        DataType::I8 => "|i1",
+        DataType::U16 => "<u2",
+        DataType::I16 => "<i2",
+        DataType::U32 => "<u4",
+        DataType::I32 => "<i4",
+        DataType::U64 => "<u8",
+        DataType::I64 => "<i8",
+        DataType::F32 => "<f4",
+        DataType::F64 => "<f8",
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) enum NpyType {
+    Plain(DataType),
+    Bool,
+    F16,
+}
+
+impl NpyType {
+    pub(super) fn size_of(&self) -> usize {
+        match self {
+            NpyType::Plain(datatype) => datatype.size_of(),
+            NpyType::Bool => 1,
+            NpyType::F16 => 2,
+        }
+    }
+
+    pub(super) fn datatype(&self) -> DataType {
+        match self {
+            NpyType::Plain(datatype) => *datatype,
+            NpyType::Bool => DataType::U8,
+            NpyType::F16 => DataType::F32,
+        }
+    }
+
+    pub(super) fn decode(&self, bytes: &[u8], big_endian: bool) -> Vec<f32> {
+        match self {
+            NpyType::Plain(datatype) => decode_values(bytes, *datatype, big_endian),
+            NpyType::Bool => bytes.iter().map(|x| (*x != 0) as u8 as f32).collect(),
+            NpyType::F16 => bytes
+                .chunks_exact(2)
+                .map(|x| {
+                    let x = [x[0], x[1]];
+                    f16_to_f32(if big_endian {
+                        u16::from_be_bytes(x)
+                    } else {
+                        u16::from_le_bytes(x)
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let fraction = (bits & 0x3ff) as f32;
+    match exponent {
+        0 => sign * fraction * 2f32.powi(-24),
+        0x1f if fraction == 0.0 => sign * f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => sign * (1.0 + fraction / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+/// headerのdict (`{'descr': '<f4', 'fortran_order': False, 'shape': (3, 4, 5), }`) の値
+fn header_value<'a>(header: &'a str, key: &str) -> Result<&'a str> {
+    let pattern = format!("'{}':", key);
+    let start = header
+        .find(&pattern)
+        .ok_or_else(|| Error::Decode(format!("NumPy header has no {}", key)))?
+        + pattern.len();
+    let value = header[start..].trim_start();
+    let end = match value.chars().next() {
+        Some('(') => value.find(')').map(|x| x + 1),
+        Some('\'') => value[1..].find('\'').map(|x| x + 2),
+        _ => value.find([',', '}']),
+    }
+    .unwrap_or(value.len());
+    Ok(value[..end].trim())
+}
+
+/// .npyのbyte列を読む
+fn parse(bytes: &[u8], name: &str) -> Result<Image3D> {
+    if !bytes.starts_with(MAGIC) || bytes.len() < 10 {
+        return Err(Error::Decode(format!("{} is not a NumPy array", name)));
+    }
+    // version 1.0はheaderの長さがu16, 2.0以降はu32
+    let (header_start, header_len) = match bytes[6] {
+        1 => (10, u16::from_le_bytes([bytes[8], bytes[9]]) as usize),
+        _ if bytes.len() >= 12 => (
+            12,
+            u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize,
+        ),
+        _ => {
+            return Err(Error::TruncatedData {
+                expected: 12,
+                actual: bytes.len(),
+            })
+        }
+    };
+    let data_start = header_start + header_len;
+    if bytes.len() < data_start {
+        return Err(Error::TruncatedData {
+            expected: data_start,
+            actual: bytes.len(),
+        });
+    }
+    let header = String::from_utf8_lossy(&bytes[header_start..data_start]);
+
+    let descr = header_value(&header, "descr")?.trim_matches('\'');
+    let (npy_type, big_endian) = parse_descr(descr)?;
+    let fortran_order = header_value(&header, "fortran_order")? == "True";
+    let shape: Vec<usize> = header_value(&header, "shape")?
+        .trim_matches(['(', ')'])
+        .split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| {
+            x.parse::<usize>()
+                .map_err(|_| Error::Decode(format!("invalid NumPy shape : {}", header)))
+        })
+        .collect::<Result<_>>()?;
+    if !(3..=4).contains(&shape.len()) {
+        return Err(Error::ShapeMismatch(format!(
+            "NumPy array {} with shape {:?} is not a 3D or 4D volume",
+            name, shape
+        )));
+    }
+
+    let n_voxels: usize = shape.iter().product();
+    let expected = n_voxels * npy_type.size_of();
+    let data_bytes = &bytes[data_start..];
+    if data_bytes.len() < expected {
+        return Err(Error::TruncatedData {
+            expected,
+            actual: data_bytes.len(),
+        });
+    }
+    let values = npy_type.decode(&data_bytes[..expected], big_endian);
+    // 配列のindexを[x, y, z, t]とみなす (nibabelのget_fdata()と同じ). C orderは並べ替える
+    let data = if fortran_order {
+        values
+    } else {
+        let mut c_strides = vec![1; shape.len()];
+        for i in (0..shape.len() - 1).rev() {
+            c_strides[i] = c_strides[i + 1] * shape[i + 1];
+        }
+        let mut data = Vec::with_capacity(n_voxels);
+        let mut index = vec![0; shape.len()];
+        for _ in 0..n_voxels {
+            let offset: usize = index.iter().zip(&c_strides).map(|(i, s)| i * s).sum();
+            data.push(values[offset]);
+            // F orderの順 (先頭の軸が最も速い) にindexを進める
+            for (i, n) in index.iter_mut().zip(&shape) {
+                *i += 1;
+                if *i < *n {
+                    break;
+                }
+                *i = 0;
+            }
+        }
+        data
+    };
+
+    let datatype = npy_type.datatype();
+    info!("NumPy array {} : {:?} {}", name, shape, descr);
+    Ok(Image3D {
+        data,
+        shape: (shape[0] as u32, shape[1] as u32, shape[2] as u32),
+        spacing: (1.0, 1.0, 1.0),
+        frames: shape.get(3).copied().unwrap_or(1) as u32,
+        frame_interval: 0.0,
+        datatype,
+        scl_slope: 0.0,
+        scl_inter: 0.0,
+        affine: Image3D::spacing_affine((1.0, 1.0, 1.0)),
+        qform_code: 0,
+        sform_code: 0,
+        format: Some(UncompressedFloatFormat::F32),
+        mipmaps: Some(MipmapsOption::NoMipmap),
+        lut: None,
+        chunked: None,
+        is_mask: false,
+    })
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> usize {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]]) as usize
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> usize {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> usize {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap()) as usize
+}
+
+/// zipのcentral directoryに記録されたファイル
+struct ZipEntry {
+    name: String,
+    method: usize,
+    compressed_size: usize,
+    header_offset: usize,
+}
+
+/// .npz (zip) に含まれるファイルの一覧
+fn zip_entries(bytes: &[u8]) -> Result<Vec<ZipEntry>> {
+    let invalid = || Error::Decode("invalid npz (zip) file".to_string());
+    // end of central directory recordは末尾のcomment (最大65535 byte) の前にある
+    let search_start = bytes.len().saturating_sub(22 + 65535);
+    let eocd = (search_start..bytes.len().saturating_sub(21))
+        .rev()
+        .find(|&i| bytes[i..i + 4] == [0x50, 0x4b, 0x05, 0x06])
+        .ok_or_else(invalid)?;
+    let mut n_entries = read_u16(bytes, eocd + 10);
+    let mut offset = read_u32(bytes, eocd + 16);
+    if offset == 0xffffffff && eocd >= 20 && bytes[eocd - 20..eocd - 16] == [0x50, 0x4b, 0x06, 0x07]
+    {
+        // zip64 end of central directory
+        let zip64_eocd = read_u64(bytes, eocd - 12);
+        if bytes.len() < zip64_eocd.saturating_add(56) {
+            return Err(invalid());
+        }
+        n_entries = read_u64(bytes, zip64_eocd + 32);
+        offset = read_u64(bytes, zip64_eocd + 48);
+    }
+
+    // central directoryの1 entryは46 byte以上. 壊れた個数で大きな領域を確保しない
+    if offset > bytes.len() || n_entries > (bytes.len() - offset) / 46 {
+        return Err(invalid());
+    }
+    let mut entries = Vec::with_capacity(n_entries);
+    for _ in 0..n_entries {
+        if bytes.len() < offset.saturating_add(46)
+            || bytes[offset..offset + 4] != [0x50, 0x4b, 0x01, 0x02]
+        {
+            return Err(invalid());
+        }
+        let method = read_u16(bytes, offset + 10);
+        let mut compressed_size = read_u32(bytes, offset + 20);
+        let uncompressed_size = read_u32(bytes, offset + 24);
+        let name_len = read_u16(bytes, offset + 28);
+        let extra_len = read_u16(bytes, offset + 30);
+        let comment_len = read_u16(bytes, offset + 32);
+        let mut header_offset = read_u32(bytes, offset + 42);
+        let name_start = offset + 46;
+        let extra_start = name_start + name_len;
+        if bytes.len() < extra_start + extra_len {
+            return Err(invalid());
+        }
+        let name = String::from_utf8_lossy(&bytes[name_start..extra_start]).to_string();
+        // 0xffffffffのsizeとoffsetはzip64 extra fieldに順に入っている
+        let mut extra = extra_start;
+        while extra + 4 <= extra_start + extra_len {
+            let id = read_u16(bytes, extra);
+            let size = read_u16(bytes, extra + 2);
+            if id == 0x0001 {
+                // extra fieldの長さを超えて読まない
+                let field = bytes
+                    .get(extra + 4..extra + 4 + size)
+                    .filter(|_| extra + 4 + size <= extra_start + extra_len)
+                    .ok_or_else(invalid)?;
+                let mut values = field
+                    .chunks_exact(8)
+                    .map(|x| u64::from_le_bytes(x.try_into().unwrap()) as usize);
+                if uncompressed_size == 0xffffffff {
+                    values.next().ok_or_else(invalid)?;
+                }
+                if compressed_size == 0xffffffff {
+                    compressed_size = values.next().ok_or_else(invalid)?;
+                }
+                if header_offset == 0xffffffff {
+                    header_offset = values.next().ok_or_else(invalid)?;
+                }
+            }
+            extra += 4 + size;
+        }
+        entries.push(ZipEntry {
+            name,
+            method,
+            compressed_size,
+            header_offset,
+        });
+        offset = extra_start + extra_len + comment_len;
+    }
+    Ok(entries)
+}
+
+/// zipのentryを展開する (無圧縮とdeflateのみ)
+fn extract(bytes: &[u8], entry: &ZipEntry) -> Result<Vec<u8>> {
+    let offset = entry.header_offset;
+    if bytes.len() < offset.saturating_add(30)
+        || bytes[offset..offset + 4] != [0x50, 0x4b, 0x03, 0x04]
+    {
+        return Err(Error::Decode(format!("invalid npz entry {}", entry.name)));
+    }
+    let data_start = offset + 30 + read_u16(bytes, offset + 26) + read_u16(bytes, offset + 28);
+    let data_end = data_start.saturating_add(entry.compressed_size);
+    let data = bytes
+        .get(data_start..data_end)
+        .ok_or(Error::TruncatedData {
+            expected: data_end,
+            actual: bytes.len(),
+        })?;
+    match entry.method {
+        0 => Ok(data.to_vec()),
+        8 => {
+            let mut decoded = Vec::new();
+            flate2::read::DeflateDecoder::new(data)
+                .read_to_end(&mut decoded)
+                .map_err(|e| Error::Decode(format!("npz deflate : {}", e)))?;
+            Ok(decoded)
+        }
+        x => Err(Error::Decode(format!(
+            "unsupported npz compression : {}",
+            x
+        ))),
+    }
+}
+
+/// `Image3D::serialize`形式のjson (`<stem>.json`) があればspacing, affineなどを読む
+fn apply_sidecar(image: &mut Image3D, data_path: &Path) -> Result<()> {
+    let header_path = data_path.with_extension("json");
+    if !header_path.exists() {
+        return Ok(());
+    }
+    info!("Load header from {:?}", header_path);
+    let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&header_path)?)?;
+    if let Some(spacing) = json.get("spacing") {
+        image.spacing = serde_json::from_value(spacing.clone())?;
+        image.affine = Image3D::spacing_affine(image.spacing);
+    }
+    if let Some(affine) = json.get("affine") {
+        let affine: [[f32; 4]; 4] = serde_json::from_value(affine.clone())?;
+        if affine[3][3] != 0.0 {
+            image.affine = affine;
+            image.sform_code = json["sform_code"].as_i64().unwrap_or(0) as i16;
+            image.qform_code = json["qform_code"].as_i64().unwrap_or(0) as i16;
+        }
+    }
+    if let Some(interval) = json["frame_interval"].as_f64() {
+        image.frame_interval = interval as f32;
+    }
+    Ok(())
+}
+
+/// .npyと.npzを読む. .npzは`array`の名前の配列 (Noneの場合は最初の3次元か4次元の配列)
+pub fn load(data_path: &Path, array: Option<&str>, progress: &Progress) -> Result<Image3D> {
+    debug!("Loading NumPy file");
+    let bytes = super::read_file(data_path, progress)?;
+    let mut image = if bytes.starts_with(MAGIC) {
+        parse(&bytes, &super::file_name(data_path))?
+    } else {
+        let entries = zip_entries(&bytes)?;
+        let names: Vec<&str> = entries
+            .iter()
+            .map(|x| x.name.trim_end_matches(".npy"))
+            .collect();
+        info!("Arrays in npz : {:?}", names);
+        match array {
+            Some(array) => {
+                let entry = entries
+                    .iter()
+                    .find(|x| x.name.trim_end_matches(".npy") == array)
+                    .ok_or_else(|| {
+                        Error::Decode(format!("npz has no array {} (arrays : {:?})", array, names))
+                    })?;
+                parse(&extract(&bytes, entry)?, array)?
+            }
+            None => entries
+                .iter()
+                .find_map(|entry| {
+                    let name = entry.name.trim_end_matches(".npy");
+                    extract(&bytes, entry)
+                        .and_then(|x| parse(&x, name))
+                        .inspect_err(|e| debug!("Skip {} : {}", name, e))
+                        .ok()
+                })
+                .ok_or_else(|| {
+                    Error::ShapeMismatch(format!("npz has no 3D or 4D array : {:?}", names))
+                })?,
+        }
+    };
+    apply_sidecar(&mut image, data_path)?;
+    Ok(image)
+}
+
+/// F orderの.npyと、spacingなどを`Image3D::serialize`と同じ形式で書いた`<stem>.json`を保存する
+pub fn save(image: &Image3D, data_path: &Path) -> Result<()> {
+    // .npyにはscl_slope, scl_interがないのでrescale済みの値はfloatで書く
+    let datatype = if image.scl_slope != 0.0 {
+        DataType::F32
+    } else {
+        image.datatype
+    };
+    let frames = image.frames.max(1);
+    let shape = if frames > 1 {
+        format!(
+            "({}, {}, {}, {})",
+            image.shape.0, image.shape.1, image.shape.2, frames
+        )
+    } else {
+        format!("({}, {}, {})", image.shape.0, image.shape.1, image.shape.2)
+    };
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': True, 'shape': {}, }}",
+        descr(datatype),
+        shape
+    );
+    // magic, version, 長さを含めて64 byteの倍数になるようにspaceで埋めて改行で終える
+    let padding = 63 - (MAGIC.len() + 4 + header.len()) % 64;
+    header.push_str(&" ".repeat(padding));
+    header.push('\n');
+
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(data_path)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[1, 0])?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    writer.write_all(&encode_values(&image.data, datatype, false))?;
+    writer.flush()?;
+
+    let mut json = serde_json::to_value(image)?;
+    json["dtype"] = serde_json::to_value(datatype)?;
+    std::fs::write(
+        data_path.with_extension("json"),
+        serde_json::to_string_pretty(&json)?,
+    )?;
+    info!("Saved NumPy array to {:?}", data_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::test_util::{assert_same_affine, lps_affine, temp_dir, volume};
+
+    /// version 1.0の.npy
+    fn npy(descr: &str, fortran_order: bool, shape: &str, data: &[u8]) -> Vec<u8> {
+        let fortran_order = if fortran_order { "True" } else { "False" };
+        let header = format!(
+            "{{'descr': '{}', 'fortran_order': {}, 'shape': {}, }}\n",
+            descr, fortran_order, shape
+        );
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend([1, 0]);
+        bytes.extend((header.len() as u16).to_le_bytes());
+        bytes.extend(header.as_bytes());
+        bytes.extend(data);
+        bytes
+    }
+
+    /// 無圧縮 (stored) のzip. `zip64`の場合はcompressed sizeをzip64 extra fieldに入れる
+    fn stored_zip(files: &[(&str, Vec<u8>)], zip64: bool) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut central = Vec::new();
+        for (name, data) in files {
+            let offset = bytes.len() as u32;
+            bytes.extend([0x50, 0x4b, 0x03, 0x04, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            bytes.extend([0; 4]); // crc32 (読まない)
+            bytes.extend((data.len() as u32).to_le_bytes());
+            bytes.extend((data.len() as u32).to_le_bytes());
+            bytes.extend((name.len() as u16).to_le_bytes());
+            bytes.extend(0u16.to_le_bytes());
+            bytes.extend(name.as_bytes());
+            bytes.extend(data);
+
+            let (compressed_size, extra) = if zip64 {
+                let mut extra = vec![0x01, 0x00, 8, 0];
+                extra.extend((data.len() as u64).to_le_bytes());
+                (0xffffffff, extra)
+            } else {
+                (data.len() as u32, vec![])
+            };
+            central.extend([0x50, 0x4b, 0x01, 0x02, 20, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            central.extend([0; 4]);
+            central.extend(compressed_size.to_le_bytes());
+            central.extend((data.len() as u32).to_le_bytes());
+            central.extend((name.len() as u16).to_le_bytes());
+            central.extend((extra.len() as u16).to_le_bytes());
+            central.extend([0; 10]); // comment, disk, attributes
+            central.extend(offset.to_le_bytes());
+            central.extend(name.as_bytes());
+            central.extend(extra);
+        }
+        let central_offset = bytes.len() as u32;
+        bytes.extend(&central);
+        bytes.extend([0x50, 0x4b, 0x05, 0x06, 0, 0, 0, 0]);
+        bytes.extend((files.len() as u16).to_le_bytes());
+        bytes.extend((files.len() as u16).to_le_bytes());
+        bytes.extend((central.len() as u32).to_le_bytes());
+        bytes.extend(central_offset.to_le_bytes());
+        bytes.extend(0u16.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn round_trip_npy() {
+        let dir = temp_dir("npy_round_trip");
+        let path = dir.join("image.npy");
+        let mut image = volume((5, 4, 3), 2, DataType::I16);
+        image.spacing = (0.8, 0.5, 2.5);
+        image.affine = lps_affine(image.spacing);
+        image.sform_code = 1;
+        save(&image, &path).unwrap();
+        // spacing, affineは隣の.jsonから読む
+        let loaded = load(&path, None, &Progress::default()).unwrap();
+        assert_eq!(loaded.shape, image.shape);
+        assert_eq!(loaded.frames, 2);
+        assert_eq!(loaded.datatype, DataType::I16);
+        assert_eq!(loaded.spacing, image.spacing);
+        assert_same_affine(&loaded.affine, &image.affine);
+        assert_eq!(loaded.data, image.data);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn c_order_f16_is_reordered() {
+        // [1.0, -2.0, 0.5, 65504, 2^-24, inf] を (2, 3, 1) のC orderで並べたもの
+        let values: [u16; 6] = [0x3c00, 0xc000, 0x3800, 0x7bff, 0x0001, 0x7c00];
+        let data: Vec<u8> = values.iter().flat_map(|x| x.to_le_bytes()).collect();
+        let image = parse(&npy("<f2", false, "(2, 3, 1)", &data), "f16").unwrap();
+        assert_eq!(image.shape, (2, 3, 1));
+        assert_eq!(image.datatype, DataType::F32);
+        // F orderでは[i, j]がi + 2 * jにある
+        assert_eq!(
+            image.data,
+            vec![1.0, 65504.0, -2.0, 2f32.powi(-24), 0.5, f32::INFINITY]
+        );
+    }
+
+    #[test]
+    fn stored_npz() {
+        let dir = temp_dir("npz_stored");
+        let path = dir.join("arrays.npz");
+        let labels = npy("|u1", true, "(2, 2, 1)", &[0, 1, 2, 3]);
+        for zip64 in [false, true] {
+            let files = [
+                ("flat.npy", npy("|u1", true, "(4,)", &[9; 4])),
+                ("labels.npy", labels.clone()),
+            ];
+            std::fs::write(&path, stored_zip(&files, zip64)).unwrap();
+            // 指定がなければ最初の3次元の配列
+            for array in [None, Some("labels")] {
+                let image = load(&path, array, &Progress::default()).unwrap();
+                assert_eq!(image.shape, (2, 2, 1));
+                assert_eq!(image.data, vec![0.0, 1.0, 2.0, 3.0]);
+            }
+            assert!(matches!(
+                load(&path, Some("missing"), &Progress::default()),
+                Err(Error::Decode(_))
+            ));
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_zip64_extra_field() {
+        let data = npy("|u1", true, "(1, 1, 1)", &[1]);
+        let mut bytes = stored_zip(&[("a.npy", data)], true);
+        // zip64 extra fieldの長さを8から4に減らす
+        let extra = bytes.len() - 22 - 12;
+        assert_eq!(bytes[extra..extra + 4], [0x01, 0x00, 8, 0]);
+        bytes[extra + 2] = 4;
+        assert!(matches!(zip_entries(&bytes), Err(Error::Decode(_))));
+
+        // zip64 end of central directoryのentry数が壊れている
+        let mut bytes = stored_zip(&[("a.npy", npy("|u1", true, "(1, 1, 1)", &[1]))], false);
+        let eocd = bytes.len() - 22;
+        let central_offset = read_u32(&bytes, eocd + 16) as u64;
+        let mut zip64 = vec![0x50, 0x4b, 0x06, 0x06];
+        zip64.extend([0; 28]);
+        zip64.extend(u64::MAX.to_le_bytes()); // entry数
+        zip64.extend([0; 8]);
+        zip64.extend(central_offset.to_le_bytes());
+        zip64.extend([0x50, 0x4b, 0x06, 0x07, 0, 0, 0, 0]);
+        zip64.extend((eocd as u64).to_le_bytes());
+        zip64.extend(1u32.to_le_bytes());
+        bytes[eocd + 16..eocd + 20].copy_from_slice(&[0xff; 4]);
+        bytes.splice(eocd..eocd, zip64);
+        assert!(matches!(zip_entries(&bytes), Err(Error::Decode(_))));
+    }
+}
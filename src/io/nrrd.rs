@@ -0,0 +1,517 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use glium::texture::{MipmapsOption, UncompressedFloatFormat};
+use tracing::{debug, info};
+
+use super::{decode_values, encode_values, DataType, Error, Image3D, Progress, Result};
+
+/// NRRDのtype名 -> DataType
+fn parse_type(name: &str) -> Option<DataType> {
+    let datatype = match name {
+        "signed char" | "int8" | "int8_t" => DataType::I8,
+        "uchar" | "unsigned char" | "uint8" | "uint8_t" => DataType::U8,
+        "short" | "short int" | "signed short" | "signed short int" | "int16" | "int16_t" => {
+            DataType::I16
+        }
+        "ushort" | "unsigned short" | "unsigned short int" | "uint16" | "uint16_t" => DataType::U16,
+        "int" | "signed int" | "int32" | "int32_t" => DataType::I32,
+        "uint" | "unsigned int" | "uint32" | "uint32_t" => DataType::U32,
+        "longlong"
+        | "long long"
+        | "long long int"
+        | "signed long long"
+        | "signed long long int"
+        | "int64"
+        | "int64_t" => DataType::I64,
+        "ulonglong" | "unsigned long long" | "unsigned long long int" | "uint64" | "uint64_t" => {
+            DataType::U64
+        }
+        "float" => DataType::F32,
+        "double" => DataType::F64,
+        _ => return None,
+    };
+    Some(datatype)
+}
+
+fn type_name(datatype: DataType) -> &'static str {
+    match datatype {
+        DataType::U8 => "uint8",
+        DataType::I8 => "int8",
+        DataType::U16 => "uint16",
+        DataType::I16 => "int16",
+        DataType::U32 => "uint32",
+        DataType::I32 => "int32",
+        DataType::U64 => "uint64",
+        DataType::I64 => "int64",
+        DataType::F32 => "float",
+        DataType::F64 => "double",
+    }
+}
+
+/// `(1.5,0,0)` のようなベクトル. `none`はNone
+fn parse_vector(text: &str) -> Result<Option<Vec<f64>>> {
+    let text = text.trim();
+    if text == "none" {
+        return Ok(None);
+    }
+    text.trim_start_matches('(')
+        .trim_end_matches(')')
+        .split(',')
+        .map(|x| {
+            x.trim()
+                .parse::<f64>()
+                .map_err(|_| Error::Decode(format!("invalid NRRD vector : {}", text)))
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(Some)
+}
+
+/// `space directions` の値を軸ごとのベクトル(またはnone)に分ける
+fn parse_vectors(text: &str) -> Result<Vec<Option<Vec<f64>>>> {
+    let mut vectors = Vec::new();
+    let mut rest = text.trim();
+    while !rest.is_empty() {
+        let end = if rest.starts_with('(') {
+            rest.find(')').map(|x| x + 1).unwrap_or(rest.len())
+        } else {
+            rest.find(char::is_whitespace).unwrap_or(rest.len())
+        };
+        vectors.push(parse_vector(&rest[..end])?);
+        rest = rest[end..].trim_start();
+    }
+    Ok(vectors)
+}
+
+/// spaceの各軸をRASにするための符号
+fn space_signs(space: &str) -> Result<[f64; 3]> {
+    match space {
+        "right-anterior-superior" | "RAS" | "scanner-xyz" | "3D-right-handed" => {
+            Ok([1.0, 1.0, 1.0])
+        }
+        "left-posterior-superior" | "LPS" => Ok([-1.0, -1.0, 1.0]),
+        "left-anterior-superior" | "LAS" => Ok([-1.0, 1.0, 1.0]),
+        _ => Err(Error::Decode(format!("unsupported NRRD space : {}", space))),
+    }
+}
+
+/// header部分とdata部分に分ける. headerは空行で終わる
+fn split_header(bytes: &[u8]) -> Result<(&str, usize)> {
+    let end = bytes
+        .windows(2)
+        .position(|x| x == b"\n\n")
+        .map(|x| (x + 1, x + 2))
+        .or_else(|| {
+            bytes
+                .windows(4)
+                .position(|x| x == b"\r\n\r\n")
+                .map(|x| (x + 2, x + 4))
+        });
+    let (header_end, data_start) = end.unwrap_or((bytes.len(), bytes.len()));
+    let header = std::str::from_utf8(&bytes[..header_end])
+        .map_err(|_| Error::Decode("NRRD header is not UTF-8".to_string()))?;
+    Ok((header, data_start))
+}
+
+/// 軸の順番を`order`の順に並べ替える. dataは最初の軸が最も速く変わる
+fn permute_axes(data: &[f32], sizes: &[usize], order: &[usize]) -> Vec<f32> {
+    let mut strides = vec![1; sizes.len()];
+    for i in 1..sizes.len() {
+        strides[i] = strides[i - 1] * sizes[i - 1];
+    }
+    let new_sizes: Vec<usize> = order.iter().map(|&i| sizes[i]).collect();
+    let new_strides: Vec<usize> = order.iter().map(|&i| strides[i]).collect();
+    let mut output = Vec::with_capacity(data.len());
+    let mut index = vec![0; sizes.len()];
+    for _ in 0..data.len() {
+        let src: usize = index.iter().zip(&new_strides).map(|(i, s)| i * s).sum();
+        output.push(data[src]);
+        for (i, size) in index.iter_mut().zip(&new_sizes) {
+            *i += 1;
+            if *i < *size {
+                break;
+            }
+            *i = 0;
+        }
+    }
+    output
+}
+
+pub fn load(data_path: &Path, progress: &Progress) -> Result<Image3D> {
+    debug!("Loading nrrd file");
+    let bytes = super::read_file(data_path, progress)?;
+    if !bytes.starts_with(b"NRRD000") {
+        return Err(Error::Decode("not a NRRD file".to_string()));
+    }
+    let (header, data_start) = split_header(&bytes)?;
+
+    let mut fields = HashMap::new();
+    for line in header.lines().skip(1) {
+        let line = line.trim_end_matches('\r');
+        if line.starts_with('#') || line.contains(":=") {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once(": ") {
+            fields.insert(key.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+    let field = |key: &str| {
+        fields
+            .get(key)
+            .map(|x| x.as_str())
+            .ok_or_else(|| Error::Decode(format!("NRRD field \"{}\" is missing", key)))
+    };
+
+    let type_field = field("type")?;
+    let datatype = parse_type(type_field)
+        .ok_or_else(|| Error::UnsupportedDataType(format!("NRRD type {}", type_field)))?;
+    let dimension: usize = field("dimension")?
+        .parse()
+        .map_err(|_| Error::Decode("invalid NRRD dimension".to_string()))?;
+    let sizes = field("sizes")?
+        .split_whitespace()
+        .map(|x| x.parse::<usize>())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|_| Error::Decode("invalid NRRD sizes".to_string()))?;
+    if sizes.len() != dimension || !(3..=4).contains(&dimension) {
+        return Err(Error::ShapeMismatch(format!(
+            "NRRD with sizes {:?} is not a 3D or 4D volume",
+            sizes
+        )));
+    }
+    let big_endian = fields.get("endian").is_some_and(|x| x == "big");
+
+    // data (attached or detached)
+    let mut data_bytes = match fields.get("data file").or_else(|| fields.get("datafile")) {
+        Some(name) => {
+            let dir = data_path.parent().unwrap_or_else(|| Path::new("."));
+            std::fs::read(dir.join(name))?
+        }
+        None => bytes[data_start..].to_vec(),
+    };
+    if let Some(line_skip) = fields
+        .get("line skip")
+        .and_then(|x| x.parse::<usize>().ok())
+    {
+        for _ in 0..line_skip {
+            match data_bytes.iter().position(|x| *x == b'\n') {
+                Some(end) => data_bytes.drain(..=end),
+                None => data_bytes.drain(..),
+            };
+        }
+    }
+    let encoding = field("encoding")?;
+    let n_voxels: usize = sizes.iter().product();
+    let mut data = match encoding {
+        "ascii" | "text" | "txt" => {
+            let values = String::from_utf8_lossy(&data_bytes)
+                .split_whitespace()
+                .map(|x| x.parse::<f32>())
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|e| Error::Decode(format!("NRRD ascii data : {}", e)))?;
+            if values.len() < n_voxels {
+                return Err(Error::ShapeMismatch(format!(
+                    "NRRD has {} values but sizes {:?} needs {}",
+                    values.len(),
+                    sizes,
+                    n_voxels
+                )));
+            }
+            values[..n_voxels].to_vec()
+        }
+        "raw" | "gzip" | "gz" => {
+            let expected = n_voxels * datatype.size_of();
+            if encoding != "raw" {
+                let mut decoded = Vec::with_capacity(expected);
+                flate2::read::MultiGzDecoder::new(data_bytes.as_slice())
+                    .read_to_end(&mut decoded)
+                    .map_err(|e| Error::Decode(format!("NRRD gzip : {}", e)))?;
+                data_bytes = decoded;
+            }
+            match fields.get("byte skip").and_then(|x| x.parse::<i64>().ok()) {
+                // dataはファイルの末尾にある
+                Some(-1) if data_bytes.len() >= expected => {
+                    data_bytes.drain(..data_bytes.len() - expected);
+                }
+                Some(skip) if skip > 0 => {
+                    data_bytes.drain(..(skip as usize).min(data_bytes.len()));
+                }
+                _ => (),
+            }
+            if data_bytes.len() < expected {
+                return Err(Error::TruncatedData {
+                    expected,
+                    actual: data_bytes.len(),
+                });
+            }
+            decode_values(&data_bytes[..expected], datatype, big_endian)
+        }
+        _ => {
+            return Err(Error::Decode(format!(
+                "unsupported NRRD encoding : {}",
+                encoding
+            )))
+        }
+    };
+
+    // 空間の軸とそれ以外(時間, list)の軸
+    let directions = match fields.get("space directions") {
+        Some(text) => {
+            let directions = parse_vectors(text)?;
+            if directions.len() != dimension {
+                return Err(Error::Decode(format!(
+                    "NRRD space directions has {} axes, expected {}",
+                    directions.len(),
+                    dimension
+                )));
+            }
+            Some(directions)
+        }
+        None => None,
+    };
+    let spatial_axes: Vec<usize> = match &directions {
+        Some(directions) => (0..dimension)
+            .filter(|&i| directions[i].is_some())
+            .collect(),
+        None => (0..3).collect(),
+    };
+    if spatial_axes.len() != 3 {
+        return Err(Error::ShapeMismatch(format!(
+            "NRRD with {} spatial axes",
+            spatial_axes.len()
+        )));
+    }
+    let mut order = spatial_axes.clone();
+    order.extend((0..dimension).filter(|i| !spatial_axes.contains(i)));
+    if order.iter().enumerate().any(|(i, &axis)| i != axis) {
+        debug!("Permute NRRD axes : {:?}", order);
+        data = permute_axes(&data, &sizes, &order);
+    }
+    let frames = if dimension == 4 { sizes[order[3]] } else { 1 };
+
+    // affine (RAS)
+    let mut affine = [[0.0f32; 4]; 4];
+    let mut spacing = [1.0f32; 3];
+    let mut sform_code = 0;
+    match &directions {
+        Some(directions) => {
+            let signs = match fields.get("space") {
+                Some(space) => space_signs(space)?,
+                None => [1.0; 3],
+            };
+            let origin = match fields.get("space origin") {
+                Some(text) => parse_vector(text)?.unwrap_or_default(),
+                None => vec![],
+            };
+            for (col, &axis) in spatial_axes.iter().enumerate() {
+                let direction = directions[axis].as_deref().unwrap_or_default();
+                for row in 0..3 {
+                    affine[row][col] =
+                        (direction.get(row).copied().unwrap_or(0.0) * signs[row]) as f32;
+                }
+                spacing[col] = direction.iter().map(|x| x * x).sum::<f64>().sqrt() as f32;
+            }
+            for row in 0..3 {
+                affine[row][3] = (origin.get(row).copied().unwrap_or(0.0) * signs[row]) as f32;
+            }
+            sform_code = 1;
+        }
+        None => {
+            if let Some(spacings) = fields.get("spacings") {
+                for (i, x) in spacings.split_whitespace().take(3).enumerate() {
+                    spacing[i] = x
+                        .parse()
+                        .ok()
+                        .filter(|x: &f32| x.is_finite())
+                        .unwrap_or(1.0);
+                }
+            }
+            affine = Image3D::spacing_affine((spacing[0], spacing[1], spacing[2]));
+        }
+    }
+    affine[3][3] = 1.0;
+
+    info!("NRRD : {:?} {:?}, encoding : {}", sizes, datatype, encoding);
+    Ok(Image3D {
+        data,
+        shape: (
+            sizes[order[0]] as u32,
+            sizes[order[1]] as u32,
+            sizes[order[2]] as u32,
+        ),
+        spacing: (spacing[0], spacing[1], spacing[2]),
+        frames: frames as u32,
+        frame_interval: 0.0,
+        datatype,
+        scl_slope: 0.0,
+        scl_inter: 0.0,
+        affine,
+        qform_code: 0,
+        sform_code,
+        format: Some(UncompressedFloatFormat::F32),
+        mipmaps: Some(MipmapsOption::NoMipmap),
+        lut: None,
+        chunked: None,
+        is_mask: false,
+    })
+}
+
+/// gzip encodingのNRRD (.nrrd) として保存する. 座標系はLPS (3D Slicerと同じ)
+pub fn save(image: &Image3D, data_path: &Path) -> Result<()> {
+    // NRRDにはscl_slope, scl_interがないのでrescale済みの値はfloatで書く
+    let (datatype, data) = if image.scl_slope != 0.0 {
+        (
+            DataType::F32,
+            encode_values(&image.data, DataType::F32, false),
+        )
+    } else {
+        (
+            image.datatype,
+            encode_values(&image.data, image.datatype, false),
+        )
+    };
+    let frames = image.frames.max(1);
+    let vector = |x: [f32; 3]| format!("({},{},{})", x[0], x[1], x[2]);
+    // RAS -> LPS
+    let lps = |row: usize, val: f32| if row < 2 { 0.0 - val } else { val };
+    let directions: Vec<String> = (0..3)
+        .map(|col| vector([0, 1, 2].map(|row| lps(row, image.affine[row][col]))))
+        .collect();
+    let origin = vector([0, 1, 2].map(|row| lps(row, image.affine[row][3])));
+
+    let mut header = String::from("NRRD0004\n# Complete NRRD file format specification at:\n# http://teem.sourceforge.net/nrrd/format.html\n");
+    header += &format!("type: {}\n", type_name(datatype));
+    if frames > 1 {
+        header += "dimension: 4\n";
+        header += "space: left-posterior-superior\n";
+        header += &format!(
+            "sizes: {} {} {} {}\n",
+            image.shape.0, image.shape.1, image.shape.2, frames
+        );
+        header += &format!("space directions: {} none\n", directions.join(" "));
+        header += "kinds: domain domain domain list\n";
+    } else {
+        header += "dimension: 3\n";
+        header += "space: left-posterior-superior\n";
+        header += &format!(
+            "sizes: {} {} {}\n",
+            image.shape.0, image.shape.1, image.shape.2
+        );
+        header += &format!("space directions: {}\n", directions.join(" "));
+        header += "kinds: domain domain domain\n";
+    }
+    header += "endian: little\n";
+    header += "encoding: gzip\n";
+    header += &format!("space origin: {}\n\n", origin);
+
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(data_path)?);
+    writer.write_all(header.as_bytes())?;
+    let mut encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+    encoder.write_all(&data)?;
+    encoder.finish()?.flush()?;
+    info!("Saved nrrd file to {:?}", data_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::test_util::{assert_same_affine, lps_affine, temp_dir, volume};
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = temp_dir("nrrd_round_trip");
+        let path = dir.join("image.nrrd");
+        let mut image = volume((5, 4, 3), 2, DataType::I16);
+        image.spacing = (0.8, 0.5, 2.5);
+        image.affine = lps_affine(image.spacing);
+        save(&image, &path).unwrap();
+        let loaded = load(&path, &Progress::default()).unwrap();
+        assert_eq!(loaded.shape, image.shape);
+        assert_eq!(loaded.frames, 2);
+        assert_eq!(loaded.datatype, DataType::I16);
+        assert_eq!(loaded.spacing, image.spacing);
+        assert_same_affine(&loaded.affine, &image.affine);
+        assert_eq!(loaded.data, image.data);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parse_lps_gzip_with_list_axis_first() {
+        let dir = temp_dir("nrrd_parse_lps");
+        let path = dir.join("inline.nrrd");
+        // 最初の軸が時間 (list), LPSの座標系
+        let header = "NRRD0004\n\
+            # comment\n\
+            type: uint8\n\
+            dimension: 4\n\
+            space: left-posterior-superior\n\
+            sizes: 2 3 2 2\n\
+            space directions: none (1,0,0) (0,2,0) (0,0,3)\n\
+            kinds: list domain domain domain\n\
+            encoding: gzip\n\
+            space origin: (10,20,30)\n\n";
+        let values: Vec<u8> = (0..24).collect();
+        let mut bytes = header.as_bytes().to_vec();
+        bytes.extend(gzip(&values));
+        std::fs::write(&path, bytes).unwrap();
+
+        let image = load(&path, &Progress::default()).unwrap();
+        assert_eq!(image.shape, (3, 2, 2));
+        assert_eq!(image.frames, 2);
+        assert_eq!(image.spacing, (1.0, 2.0, 3.0));
+        assert_same_affine(
+            &image.affine,
+            &[
+                [-1.0, 0.0, 0.0, -10.0],
+                [0.0, -2.0, 0.0, -20.0],
+                [0.0, 0.0, 3.0, 30.0],
+                [0.0, 0.0, 0.0, 1.0],
+            ],
+        );
+        for t in 0..2 {
+            for (voxel, value) in image.frame(t).iter().enumerate() {
+                assert_eq!(*value, (t as usize + 2 * voxel) as f32);
+            }
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parse_detached_data_at_end_of_file() {
+        let dir = temp_dir("nrrd_detached");
+        let path = dir.join("detached.nhdr");
+        std::fs::write(
+            &path,
+            "NRRD0004\n\
+             type: short\n\
+             dimension: 3\n\
+             sizes: 2 2 1\n\
+             spacings: 0.5 0.5 4\n\
+             endian: big\n\
+             encoding: raw\n\
+             byte skip: -1\n\
+             data file: detached.raw\n",
+        )
+        .unwrap();
+        // dataの前にある余分なbyteは読み飛ばす
+        let mut raw = b"junk".to_vec();
+        for x in [-2i16, -1, 0, 300] {
+            raw.extend(x.to_be_bytes());
+        }
+        std::fs::write(dir.join("detached.raw"), raw).unwrap();
+
+        let image = load(&path, &Progress::default()).unwrap();
+        assert_eq!(image.shape, (2, 2, 1));
+        assert_eq!(image.spacing, (0.5, 0.5, 4.0));
+        assert_eq!(image.data, vec![-2.0, -1.0, 0.0, 300.0]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
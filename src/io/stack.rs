@@ -0,0 +1,366 @@
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+
+use glium::texture::{MipmapsOption, UncompressedFloatFormat};
+use tracing::{debug, info};
+
+use super::image2d::{self, Image2D};
+use super::{DataType, Error, Image3D, LoadOptions, Result};
+
+// sliceとして読む画像の拡張子
+const SLICE_EXTENSIONS: [&str; 6] = ["png", "tif", "tiff", "bmp", "jpg", "jpeg"];
+
+/// 数字の部分を数値として比較する (slice2.png < slice10.png)
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let take_number = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+                    let mut digits = String::new();
+                    while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                        digits.push(*c);
+                        chars.next();
+                    }
+                    digits
+                };
+                let x = take_number(&mut a);
+                let y = take_number(&mut b);
+                let x = x.trim_start_matches('0');
+                let y = y.trim_start_matches('0');
+                let ordering = x.len().cmp(&y.len()).then_with(|| x.cmp(y));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.to_ascii_lowercase().cmp(&y.to_ascii_lowercase());
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+fn is_slice_file(path: &Path) -> bool {
+    path.is_file()
+        && path
+            .extension()
+            .and_then(|x| x.to_str())
+            .is_some_and(|x| SLICE_EXTENSIONS.contains(&x.to_ascii_lowercase().as_str()))
+}
+
+/// directory内のslice画像をファイル名の自然順に並べる
+fn slice_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|x| x.ok().map(|x| x.path()))
+        .filter(|x| is_slice_file(x))
+        .collect();
+    files.sort_by(|a, b| {
+        natural_cmp(
+            &a.file_name().unwrap_or_default().to_string_lossy(),
+            &b.file_name().unwrap_or_default().to_string_lossy(),
+        )
+    });
+    Ok(files)
+}
+
+/// 隠しファイル以外の全てのファイルがslice画像のdirectoryか.
+/// DICOMのdirectoryにpreviewの画像が混ざっていてもsliceとして読まない
+pub fn is_slice_dir(dir: &Path) -> bool {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return false;
+    };
+    let files: Vec<PathBuf> = entries
+        .filter_map(|x| x.ok().map(|x| x.path()))
+        .filter(|x| {
+            x.is_file()
+                && !x
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .starts_with('.')
+        })
+        .collect();
+    !files.is_empty() && files.iter().all(|x| is_slice_file(x))
+}
+
+/// multi-page TIFFの全てのpageを読む
+fn read_tiff_pages(data_path: &Path, options: &LoadOptions) -> Result<Vec<Image2D>> {
+    use tiff::decoder::DecodingResult;
+    use tiff::ColorType;
+
+    let bytes = super::read_file(data_path, &options.progress)?;
+    let tiff_error = |e: tiff::TiffError| Error::Decode(format!("TIFF : {}", e));
+    let mut decoder = tiff::decoder::Decoder::new(std::io::Cursor::new(bytes))
+        .map_err(tiff_error)?
+        .with_limits(tiff::decoder::Limits::unlimited());
+    let mut pages = Vec::new();
+    loop {
+        let (width, height) = decoder.dimensions().map_err(tiff_error)?;
+        let channels = match decoder.colortype().map_err(tiff_error)? {
+            ColorType::Gray(_) => 1,
+            ColorType::GrayA(_) => 2,
+            ColorType::RGB(_) => 3,
+            ColorType::RGBA(_) => 4,
+            x => {
+                return Err(Error::UnsupportedDataType(format!(
+                    "TIFF colour type {:?}",
+                    x
+                )))
+            }
+        };
+        macro_rules! samples {
+            ($values:expr) => {
+                $values.into_iter().map(|x| x as f32).collect()
+            };
+        }
+        let (samples, datatype) = match decoder.read_image().map_err(tiff_error)? {
+            DecodingResult::U8(x) => (samples!(x), DataType::U8),
+            DecodingResult::U16(x) => (samples!(x), DataType::U16),
+            DecodingResult::U32(x) => (samples!(x), DataType::U32),
+            DecodingResult::U64(x) => (samples!(x), DataType::U64),
+            DecodingResult::F32(x) => (x, DataType::F32),
+            DecodingResult::F64(x) => (samples!(x), DataType::F64),
+            DecodingResult::I8(x) => (samples!(x), DataType::I8),
+            DecodingResult::I16(x) => (samples!(x), DataType::I16),
+            DecodingResult::I32(x) => (samples!(x), DataType::I32),
+            DecodingResult::I64(x) => (samples!(x), DataType::I64),
+        };
+        pages.push(Image2D {
+            width,
+            height,
+            channels,
+            samples,
+            datatype,
+        });
+        if !decoder.more_images() {
+            break;
+        }
+        decoder.next_image().map_err(tiff_error)?;
+    }
+    Ok(pages)
+}
+
+/// 2 page以上のTIFFか (headerだけを読む)
+pub fn is_multi_page_tiff(data_path: &Path) -> bool {
+    std::fs::File::open(data_path)
+        .ok()
+        .and_then(|file| tiff::decoder::Decoder::new(std::io::BufReader::new(file)).ok())
+        .is_some_and(|decoder| decoder.more_images())
+}
+
+/// `<name>.json`のspacing
+fn sidecar_spacing(data_path: &Path) -> Result<Option<(f32, f32, f32)>> {
+    let header_path = data_path.with_extension("json");
+    if !header_path.exists() {
+        return Ok(None);
+    }
+    let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&header_path)?)?;
+    match json.get("spacing") {
+        Some(spacing) => Ok(Some(serde_json::from_value(spacing.clone())?)),
+        None => Ok(None),
+    }
+}
+
+/// sliceを重ねてvolumeにする
+fn stack(slices: Vec<Image2D>, data_path: &Path, options: &LoadOptions) -> Result<Image3D> {
+    let Some(first) = slices.first() else {
+        return Err(Error::Decode(format!("no slices in {:?}", data_path)));
+    };
+    let (width, height, datatype) = (first.width, first.height, first.datatype);
+    let depth = slices.len();
+    let mut data = Vec::with_capacity(width as usize * height as usize * depth);
+    for (i, slice) in slices.into_iter().enumerate() {
+        if (slice.width, slice.height) != (width, height) {
+            return Err(Error::ShapeMismatch(format!(
+                "slice {} is {}x{} but the first slice is {}x{}",
+                i, slice.width, slice.height, width, height
+            )));
+        }
+        if slice.datatype != datatype {
+            return Err(Error::UnsupportedDataType(format!(
+                "slice {} is {:?} but the first slice is {:?}",
+                i, slice.datatype, datatype
+            )));
+        }
+        data.extend(slice.into_gray(options.rgb_channel)?);
+    }
+    let spacing = match options.slice_spacing {
+        Some(spacing) => spacing,
+        None => sidecar_spacing(data_path)?.unwrap_or((1.0, 1.0, 1.0)),
+    };
+    // 画像の行は上から下に並んでいるので, y軸を反転して上を+yにする
+    let mut affine = Image3D::spacing_affine(spacing);
+    affine[1][1] = -spacing.1;
+    affine[1][3] = (height as f32 - 1.0) * spacing.1;
+
+    info!(
+        "Image stack : {}x{}x{} {:?}, spacing {:?}",
+        width, height, depth, datatype, spacing
+    );
+    Ok(Image3D {
+        data,
+        shape: (width, height, depth as u32),
+        spacing,
+        frames: 1,
+        frame_interval: 0.0,
+        datatype,
+        scl_slope: 0.0,
+        scl_inter: 0.0,
+        affine,
+        qform_code: 0,
+        sform_code: 0,
+        format: Some(UncompressedFloatFormat::F32),
+        mipmaps: Some(MipmapsOption::NoMipmap),
+        lut: None,
+        chunked: None,
+        is_mask: false,
+    })
+}
+
+/// directory内の連番のslice画像 (PNG, TIFF, BMP, JPEG) を読む
+pub fn load_dir(dir: &Path, options: &LoadOptions) -> Result<Image3D> {
+    debug!("Loading image sequence");
+    let files = slice_files(dir)?;
+    let mut slices = Vec::with_capacity(files.len());
+    for (i, file) in files.iter().enumerate() {
+        slices.push(image2d::load(file)?);
+        options
+            .progress
+            .report((i + 1) as f32 / files.len() as f32)?;
+    }
+    stack(slices, dir, options)
+}
+
+/// multi-page TIFFの各pageをsliceとして読む
+pub fn load_tiff(data_path: &Path, options: &LoadOptions) -> Result<Image3D> {
+    debug!("Loading multi-page TIFF");
+    let pages = read_tiff_pages(data_path, options)?;
+    stack(pages, data_path, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::test_util::temp_dir;
+
+    fn gray(width: u32, height: u32, first: f32, datatype: DataType) -> Image2D {
+        Image2D {
+            width,
+            height,
+            channels: 1,
+            samples: (0..width * height).map(|x| first + x as f32).collect(),
+            datatype,
+        }
+    }
+
+    #[test]
+    fn natural_order() {
+        assert_eq!(natural_cmp("slice2.png", "slice10.png"), Ordering::Less);
+        assert_eq!(natural_cmp("slice10.png", "slice9.png"), Ordering::Greater);
+        // 先頭の0は数値の大きさに影響しない
+        assert_eq!(natural_cmp("slice002.png", "slice10.png"), Ordering::Less);
+        assert_eq!(natural_cmp("slice007.png", "slice7.png"), Ordering::Equal);
+        // 大文字と小文字は区別しない
+        assert_eq!(natural_cmp("Slice3.png", "slice4.png"), Ordering::Less);
+        assert_eq!(natural_cmp("IMG_1.TIF", "img_1.tif"), Ordering::Equal);
+        assert_eq!(natural_cmp("a", "a1"), Ordering::Less);
+    }
+
+    #[test]
+    fn stack_flips_y_axis() {
+        let options = LoadOptions {
+            slice_spacing: Some((0.5, 0.25, 2.0)),
+            ..Default::default()
+        };
+        let slices = vec![gray(3, 2, 0.0, DataType::U8), gray(3, 2, 6.0, DataType::U8)];
+        let image = stack(slices, Path::new("stack"), &options).unwrap();
+        assert_eq!(image.shape, (3, 2, 2));
+        assert_eq!(image.spacing, (0.5, 0.25, 2.0));
+        assert_eq!(image.datatype, DataType::U8);
+        assert_eq!(image.data, (0..12).map(|x| x as f32).collect::<Vec<_>>());
+        // 画像の最下行がy = 0, 上に向かって+y
+        assert_eq!(image.voxel_to_world([0.0, 1.0, 0.0]), [0.0, 0.0, 0.0]);
+        assert_eq!(image.voxel_to_world([2.0, 0.0, 1.0]), [1.0, 0.25, 2.0]);
+    }
+
+    #[test]
+    fn mismatched_slices() {
+        let options = LoadOptions::default();
+        let slices = vec![gray(3, 2, 0.0, DataType::U8), gray(2, 3, 0.0, DataType::U8)];
+        assert!(matches!(
+            stack(slices, Path::new("stack"), &options),
+            Err(Error::ShapeMismatch(_))
+        ));
+        let slices = vec![
+            gray(3, 2, 0.0, DataType::U8),
+            gray(3, 2, 0.0, DataType::U16),
+        ];
+        assert!(matches!(
+            stack(slices, Path::new("stack"), &options),
+            Err(Error::UnsupportedDataType(_))
+        ));
+    }
+
+    #[test]
+    fn load_png_directory() {
+        let dir = temp_dir("stack_png");
+        // 16bitの値は丸めずに読む
+        for (i, name) in ["slice10.png", "slice2.png", "slice1.png"]
+            .iter()
+            .enumerate()
+        {
+            let pixels: Vec<u16> = (0..6).map(|x| 1000 * i as u16 + x).collect();
+            image::ImageBuffer::<image::Luma<u16>, _>::from_raw(3, 2, pixels)
+                .unwrap()
+                .save(dir.join(name))
+                .unwrap();
+        }
+        assert!(is_slice_dir(&dir));
+        let image = load_dir(&dir, &LoadOptions::default()).unwrap();
+        assert_eq!(image.shape, (3, 2, 3));
+        assert_eq!(image.datatype, DataType::U16);
+        // slice1, slice2, slice10の順
+        assert_eq!(image.data[0], 2000.0);
+        assert_eq!(image.data[6], 1000.0);
+        assert_eq!(image.data[17], 5.0);
+
+        // DICOMにpreviewの画像が混ざったdirectoryはslice画像のdirectoryではない
+        std::fs::write(dir.join("IM0001"), b"DICM").unwrap();
+        assert!(!is_slice_dir(&dir));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn load_multi_page_tiff() {
+        let dir = temp_dir("stack_tiff");
+        let path = dir.join("stack.tif");
+        let mut encoder =
+            tiff::encoder::TiffEncoder::new(std::fs::File::create(&path).unwrap()).unwrap();
+        for page in 0..4u16 {
+            let pixels: Vec<u16> = (0..6).map(|x| 300 * page + x).collect();
+            encoder
+                .write_image::<tiff::encoder::colortype::Gray16>(3, 2, &pixels)
+                .unwrap();
+        }
+        drop(encoder);
+        assert!(is_multi_page_tiff(&path));
+        let image = load_tiff(&path, &LoadOptions::default()).unwrap();
+        assert_eq!(image.shape, (3, 2, 4));
+        assert_eq!(image.datatype, DataType::U16);
+        assert_eq!(
+            image.data[6..12],
+            [300.0, 301.0, 302.0, 303.0, 304.0, 305.0]
+        );
+        assert_eq!(image.data[23], 905.0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,786 @@
+use std::io::Read;
+use std::path::Path;
+
+use glium::texture::{MipmapsOption, UncompressedFloatFormat};
+use tracing::{debug, info};
+
+use super::{decode_values, DataType, Error, Image3D, Progress, Result};
+
+/// legacy形式のscalarの型名. bitは1 voxel 1 bitに詰められているので読まない
+fn parse_legacy_type(name: &str) -> Option<DataType> {
+    let datatype = match name.to_ascii_lowercase().as_str() {
+        "unsigned_char" => DataType::U8,
+        "char" | "signed_char" => DataType::I8,
+        "unsigned_short" => DataType::U16,
+        "short" => DataType::I16,
+        "unsigned_int" => DataType::U32,
+        "int" => DataType::I32,
+        "unsigned_long" | "vtktypeuint64" => DataType::U64,
+        "long" | "vtktypeint64" => DataType::I64,
+        "float" => DataType::F32,
+        "double" => DataType::F64,
+        _ => return None,
+    };
+    Some(datatype)
+}
+
+/// XML形式のDataArrayの型名
+fn parse_xml_type(name: &str) -> Option<DataType> {
+    let datatype = match name {
+        "UInt8" => DataType::U8,
+        "Int8" => DataType::I8,
+        "UInt16" => DataType::U16,
+        "Int16" => DataType::I16,
+        "UInt32" => DataType::U32,
+        "Int32" => DataType::I32,
+        "UInt64" => DataType::U64,
+        "Int64" => DataType::I64,
+        "Float32" => DataType::F32,
+        "Float64" => DataType::F64,
+        _ => return None,
+    };
+    Some(datatype)
+}
+
+fn parse_floats(text: &str, what: &str) -> Result<Vec<f32>> {
+    text.split_whitespace()
+        .map(|x| {
+            x.parse::<f32>()
+                .map_err(|_| Error::Decode(format!("invalid VTK {} : {}", what, text)))
+        })
+        .collect()
+}
+
+/// VTKの座標には解剖学的な向きがないので、spacingとoriginだけのaffineにする
+fn new_image(
+    data: Vec<f32>,
+    dims: [usize; 3],
+    spacing: [f32; 3],
+    origin: [f32; 3],
+    datatype: DataType,
+) -> Image3D {
+    let mut affine = Image3D::spacing_affine((spacing[0], spacing[1], spacing[2]));
+    for row in 0..3 {
+        affine[row][3] = origin[row];
+    }
+    info!("VTK image data : {:?} {:?}", dims, datatype);
+    Image3D {
+        data,
+        shape: (dims[0] as u32, dims[1] as u32, dims[2] as u32),
+        spacing: (spacing[0].abs(), spacing[1].abs(), spacing[2].abs()),
+        frames: 1,
+        frame_interval: 0.0,
+        datatype,
+        scl_slope: 0.0,
+        scl_inter: 0.0,
+        affine,
+        qform_code: 0,
+        sform_code: 0,
+        format: Some(UncompressedFloatFormat::F32),
+        mipmaps: Some(MipmapsOption::NoMipmap),
+        lut: None,
+        chunked: None,
+        is_mask: false,
+    }
+}
+
+/// 改行までの1行を返し、offsetを次の行の先頭に進める
+fn next_line<'a>(bytes: &'a [u8], offset: &mut usize) -> Option<&'a str> {
+    if *offset >= bytes.len() {
+        return None;
+    }
+    let end = bytes[*offset..]
+        .iter()
+        .position(|x| *x == b'\n')
+        .map_or(bytes.len(), |x| *offset + x);
+    let line = std::str::from_utf8(&bytes[*offset..end]).unwrap_or("");
+    *offset = (end + 1).min(bytes.len());
+    Some(line.trim())
+}
+
+/// legacy形式 (.vtk) のSTRUCTURED_POINTSを読む
+pub fn load_legacy(data_path: &Path, progress: &Progress) -> Result<Image3D> {
+    debug!("Loading legacy vtk file");
+    let bytes = super::read_file(data_path, progress)?;
+    let mut offset = 0;
+    let version = next_line(&bytes, &mut offset).unwrap_or_default();
+    if !version.starts_with("# vtk DataFile") {
+        return Err(Error::Decode("not a VTK file".to_string()));
+    }
+    let _title = next_line(&bytes, &mut offset);
+    let is_binary = match next_line(&bytes, &mut offset).map(|x| x.to_ascii_uppercase()) {
+        Some(x) if x == "BINARY" => true,
+        Some(x) if x == "ASCII" => false,
+        x => return Err(Error::Decode(format!("invalid VTK file type : {:?}", x))),
+    };
+
+    let mut dims = None;
+    let mut spacing = [1.0; 3];
+    let mut origin = [0.0; 3];
+    let mut datatype = None;
+    while let Some(line) = next_line(&bytes, &mut offset) {
+        let mut words = line.split_whitespace();
+        let keyword = words.next().unwrap_or_default().to_ascii_uppercase();
+        let rest = line[keyword.len()..].trim();
+        match keyword.as_str() {
+            "DATASET" if !rest.eq_ignore_ascii_case("STRUCTURED_POINTS") => {
+                return Err(Error::UnsupportedFormat(data_path.to_path_buf()));
+            }
+            "DIMENSIONS" => {
+                let values = parse_floats(rest, "DIMENSIONS")?;
+                if values.len() != 3 {
+                    return Err(Error::ShapeMismatch(format!("VTK DIMENSIONS {}", rest)));
+                }
+                dims = Some([0, 1, 2].map(|i| values[i] as usize));
+            }
+            "SPACING" | "ASPECT_RATIO" => {
+                let values = parse_floats(rest, "SPACING")?;
+                spacing = [0, 1, 2].map(|i| values.get(i).copied().unwrap_or(1.0));
+            }
+            "ORIGIN" => {
+                let values = parse_floats(rest, "ORIGIN")?;
+                origin = [0, 1, 2].map(|i| values.get(i).copied().unwrap_or(0.0));
+            }
+            "SCALARS" => {
+                let fields: Vec<&str> = rest.split_whitespace().collect();
+                let components = fields.get(2).and_then(|x| x.parse().ok()).unwrap_or(1);
+                if components != 1 {
+                    return Err(Error::UnsupportedDataType(format!(
+                        "VTK scalars with {} components",
+                        components
+                    )));
+                }
+                let name = fields.get(1).copied().unwrap_or_default();
+                datatype = Some(
+                    parse_legacy_type(name)
+                        .ok_or_else(|| Error::UnsupportedDataType(format!("VTK type {}", name)))?,
+                );
+            }
+            // LOOKUP_TABLEの次の行からdataが始まる
+            "LOOKUP_TABLE" if datatype.is_some() => break,
+            "CELL_DATA" | "VECTORS" | "TENSORS" | "NORMALS" | "FIELD" => {
+                return Err(Error::UnsupportedDataType(format!(
+                    "VTK {} (only point scalars are supported)",
+                    keyword
+                )));
+            }
+            _ => (),
+        }
+    }
+    let (Some(dims), Some(datatype)) = (dims, datatype) else {
+        return Err(Error::Decode(
+            "VTK file has no DIMENSIONS or SCALARS".to_string(),
+        ));
+    };
+
+    let n_voxels: usize = dims.iter().product();
+    let data = if is_binary {
+        // binaryはbig endian
+        let expected = n_voxels * datatype.size_of();
+        let data_bytes = &bytes[offset..];
+        if data_bytes.len() < expected {
+            return Err(Error::TruncatedData {
+                expected,
+                actual: data_bytes.len(),
+            });
+        }
+        decode_values(&data_bytes[..expected], datatype, true)
+    } else {
+        let values = String::from_utf8_lossy(&bytes[offset..])
+            .split_whitespace()
+            .take(n_voxels)
+            .map(|x| x.parse::<f32>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| Error::Decode(format!("VTK ascii data : {}", e)))?;
+        if values.len() < n_voxels {
+            return Err(Error::ShapeMismatch(format!(
+                "VTK has {} values but DIMENSIONS {:?} needs {}",
+                values.len(),
+                dims,
+                n_voxels
+            )));
+        }
+        values
+    };
+    Ok(new_image(data, dims, spacing, origin, datatype))
+}
+
+/// base64 (padding付き) をdecodeする. 空白は無視する
+fn decode_base64(text: &[u8]) -> Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(text.len() / 4 * 3);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for &c in text {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => {
+                // paddingで1つのblockが終わる (複数のblockが連結されている場合がある)
+                buffer = 0;
+                bits = 0;
+                continue;
+            }
+            c if c.is_ascii_whitespace() => continue,
+            _ => return Err(Error::Decode("invalid base64 data".to_string())),
+        };
+        buffer = (buffer << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(output)
+}
+
+/// base64の文字列のうち`n_bytes`をdecodeするのに必要な文字数
+fn base64_len(n_bytes: usize) -> usize {
+    n_bytes.div_ceil(3) * 4
+}
+
+/// `<tag ...>` のattributeの値
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let pattern = format!(" {}=\"", name);
+    let start = tag.find(&pattern)? + pattern.len();
+    let end = tag[start..].find('"')? + start;
+    Some(&tag[start..end])
+}
+
+/// `text`の`from`以降で最初の`<name ...>`の開始位置とtag全体
+fn find_tag<'a>(text: &'a str, name: &str, from: usize) -> Option<(usize, &'a str)> {
+    let pattern = format!("<{}", name);
+    let mut from = from;
+    loop {
+        let start = text[from..].find(&pattern)? + from;
+        let after = text[start + pattern.len()..].chars().next()?;
+        if after.is_whitespace() || after == '>' || after == '/' {
+            let end = text[start..].find('>')? + start + 1;
+            return Some((start, &text[start..end]));
+        }
+        from = start + pattern.len();
+    }
+}
+
+/// header (UInt32 or UInt64) の整数列を読む
+fn read_header(bytes: &[u8], header_size: usize, little_endian: bool) -> Vec<usize> {
+    bytes
+        .chunks_exact(header_size)
+        .map(|x| {
+            let mut buf = [0u8; 8];
+            if little_endian {
+                buf[..header_size].copy_from_slice(x);
+                u64::from_le_bytes(buf) as usize
+            } else {
+                buf[8 - header_size..].copy_from_slice(x);
+                u64::from_be_bytes(buf) as usize
+            }
+        })
+        .collect()
+}
+
+/// XML形式 (.vti) のImageDataを読む
+pub fn load_xml(data_path: &Path, progress: &Progress) -> Result<Image3D> {
+    debug!("Loading vti file");
+    let bytes = super::read_file(data_path, progress)?;
+    // AppendedDataのrawデータはUTF-8ではないので、XMLの部分とdataの部分を分ける
+    let appended_start = bytes
+        .windows(b"<AppendedData".len())
+        .position(|x| x == b"<AppendedData");
+    let xml_end = appended_start.unwrap_or(bytes.len());
+    let xml = String::from_utf8_lossy(&bytes[..xml_end]);
+
+    let (_, file_tag) = find_tag(&xml, "VTKFile", 0)
+        .ok_or_else(|| Error::Decode("not a VTK XML file".to_string()))?;
+    if attribute(file_tag, "type") != Some("ImageData") {
+        return Err(Error::UnsupportedFormat(data_path.to_path_buf()));
+    }
+    let little_endian = attribute(file_tag, "byte_order") != Some("BigEndian");
+    let header_size = match attribute(file_tag, "header_type") {
+        Some("UInt64") => 8,
+        _ => 4,
+    };
+    let compressed = match attribute(file_tag, "compressor") {
+        Some("vtkZLibDataCompressor") => true,
+        Some(x) if !x.is_empty() => {
+            return Err(Error::Decode(format!("unsupported VTK compressor : {}", x)))
+        }
+        _ => false,
+    };
+
+    let (_, image_tag) = find_tag(&xml, "ImageData", 0)
+        .ok_or_else(|| Error::Decode("VTK file has no ImageData".to_string()))?;
+    let extent = parse_floats(
+        attribute(image_tag, "WholeExtent").unwrap_or(""),
+        "WholeExtent",
+    )?;
+    if extent.len() != 6 {
+        return Err(Error::ShapeMismatch(format!(
+            "VTK WholeExtent {:?}",
+            extent
+        )));
+    }
+    let dims = [0, 1, 2].map(|i| (extent[2 * i + 1] - extent[2 * i]) as usize + 1);
+    let spacing = parse_floats(
+        attribute(image_tag, "Spacing").unwrap_or("1 1 1"),
+        "Spacing",
+    )?;
+    let spacing = [0, 1, 2].map(|i| spacing.get(i).copied().unwrap_or(1.0));
+    let origin = parse_floats(attribute(image_tag, "Origin").unwrap_or("0 0 0"), "Origin")?;
+    let origin = [0, 1, 2].map(|i| origin.get(i).copied().unwrap_or(0.0));
+    // extentの開始位置もoriginに含める
+    let origin = [0, 1, 2].map(|i| origin[i] + extent[2 * i] * spacing[i]);
+
+    // PointDataのScalarsに指定されたDataArray (指定がなければ最初のもの)
+    let (point_data_start, point_data_tag) = find_tag(&xml, "PointData", 0)
+        .ok_or_else(|| Error::Decode("VTK file has no PointData".to_string()))?;
+    let scalars = attribute(point_data_tag, "Scalars");
+    let mut from = point_data_start;
+    let (array_start, array_tag) = loop {
+        let (start, tag) = find_tag(&xml, "DataArray", from)
+            .ok_or_else(|| Error::Decode("VTK file has no DataArray".to_string()))?;
+        if scalars.is_none() || attribute(tag, "Name") == scalars {
+            break (start, tag);
+        }
+        from = start + tag.len();
+    };
+    let components: usize = attribute(array_tag, "NumberOfComponents")
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(1);
+    if components != 1 {
+        return Err(Error::UnsupportedDataType(format!(
+            "VTK DataArray with {} components",
+            components
+        )));
+    }
+    let type_name = attribute(array_tag, "type").unwrap_or_default();
+    let datatype = parse_xml_type(type_name)
+        .ok_or_else(|| Error::UnsupportedDataType(format!("VTK type {}", type_name)))?;
+    let n_voxels: usize = dims.iter().product();
+    let expected = n_voxels * datatype.size_of();
+
+    let content_start = array_start + array_tag.len();
+    let content_end = xml[content_start..]
+        .find("</DataArray>")
+        .map_or(xml.len(), |x| x + content_start);
+    let content = xml[content_start..content_end].trim();
+    let format = attribute(array_tag, "format").unwrap_or("ascii");
+    let data_bytes = match format {
+        "ascii" => {
+            let values = parse_floats(content, "DataArray")?;
+            if values.len() < n_voxels {
+                return Err(Error::ShapeMismatch(format!(
+                    "VTK has {} values but extent {:?} needs {}",
+                    values.len(),
+                    dims,
+                    n_voxels
+                )));
+            }
+            return Ok(new_image(
+                values[..n_voxels].to_vec(),
+                dims,
+                spacing,
+                origin,
+                datatype,
+            ));
+        }
+        "binary" => {
+            decode_base64_array(content.as_bytes(), compressed, header_size, little_endian)?
+        }
+        "appended" => {
+            let appended = &bytes[appended_start
+                .ok_or_else(|| Error::Decode("VTK file has no AppendedData".to_string()))?..];
+            // dataは"_"の次から始まる
+            let data_start = appended
+                .iter()
+                .position(|x| *x == b'_')
+                .ok_or_else(|| Error::Decode("invalid AppendedData".to_string()))?
+                + 1;
+            let appended_tag = String::from_utf8_lossy(&appended[..data_start]);
+            let is_base64 = attribute(&appended_tag, "encoding") == Some("base64");
+            let offset: usize = attribute(array_tag, "offset")
+                .and_then(|x| x.trim().parse().ok())
+                .unwrap_or(0);
+            let data = appended
+                .get(data_start + offset..)
+                .ok_or_else(|| Error::Decode("invalid DataArray offset".to_string()))?;
+            if is_base64 {
+                let end = data.iter().position(|x| *x == b'<').unwrap_or(data.len());
+                decode_base64_array(&data[..end], compressed, header_size, little_endian)?
+            } else if compressed {
+                decompress_blocks(data, header_size, little_endian)?
+            } else {
+                // 先頭のheaderはdataのbyte数
+                let len = read_header(
+                    &data[..header_size.min(data.len())],
+                    header_size,
+                    little_endian,
+                )
+                .first()
+                .copied()
+                .unwrap_or(0);
+                data[header_size.min(data.len())..]
+                    .iter()
+                    .take(len)
+                    .copied()
+                    .collect()
+            }
+        }
+        _ => {
+            return Err(Error::Decode(format!(
+                "unsupported VTK DataArray format : {}",
+                format
+            )))
+        }
+    };
+    if data_bytes.len() < expected {
+        return Err(Error::TruncatedData {
+            expected,
+            actual: data_bytes.len(),
+        });
+    }
+    let data = decode_values(&data_bytes[..expected], datatype, !little_endian);
+    Ok(new_image(data, dims, spacing, origin, datatype))
+}
+
+/// base64 encodeされたDataArrayをdecodeする (appendedの場合は末尾に別の配列が続くことがある)
+fn decode_base64_array(
+    content: &[u8],
+    compressed: bool,
+    header_size: usize,
+    little_endian: bool,
+) -> Result<Vec<u8>> {
+    if compressed {
+        // 圧縮されている場合はheaderとdataが別々にbase64 encodeされている
+        let first = decode_base64(&content[..base64_len(header_size * 3).min(content.len())])?;
+        let n_blocks = read_header(&first, header_size, little_endian)
+            .first()
+            .copied()
+            .unwrap_or(0);
+        let header_len = base64_len(header_size * (3 + n_blocks)).min(content.len());
+        let mut bytes = decode_base64(&content[..header_len])?;
+        bytes.extend(decode_base64(&content[header_len..])?);
+        decompress_blocks(&bytes, header_size, little_endian)
+    } else {
+        let decoded = decode_base64(content)?;
+        let len = read_header(
+            &decoded[..header_size.min(decoded.len())],
+            header_size,
+            little_endian,
+        )
+        .first()
+        .copied()
+        .unwrap_or(0);
+        Ok(decoded[header_size.min(decoded.len())..]
+            .iter()
+            .take(len)
+            .copied()
+            .collect())
+    }
+}
+
+/// vtkZLibDataCompressorのblock header `[n_blocks, block_size, last_block_size, compressed_sizes...]`
+/// に続くzlibのblockを展開する
+fn decompress_blocks(bytes: &[u8], header_size: usize, little_endian: bool) -> Result<Vec<u8>> {
+    let n_blocks = read_header(
+        &bytes[..header_size.min(bytes.len())],
+        header_size,
+        little_endian,
+    )
+    .first()
+    .copied()
+    .unwrap_or(0);
+    let header_len = header_size * (3 + n_blocks);
+    if bytes.len() < header_len {
+        return Err(Error::TruncatedData {
+            expected: header_len,
+            actual: bytes.len(),
+        });
+    }
+    let header = read_header(&bytes[..header_len], header_size, little_endian);
+    let mut output = Vec::new();
+    let mut offset = header_len;
+    for &size in &header[3..] {
+        let block = bytes
+            .get(offset..offset + size)
+            .ok_or(Error::TruncatedData {
+                expected: offset + size,
+                actual: bytes.len(),
+            })?;
+        flate2::read::ZlibDecoder::new(block)
+            .read_to_end(&mut output)
+            .map_err(|e| Error::Decode(format!("VTK zlib : {}", e)))?;
+        offset += size;
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::test_util::{assert_same_affine, temp_dir};
+
+    #[test]
+    fn legacy_binary_structured_points() {
+        let dir = temp_dir("vtk_legacy_binary");
+        let path = dir.join("image.vtk");
+        let mut bytes = b"# vtk DataFile Version 3.0\n\
+            title\n\
+            BINARY\n\
+            DATASET STRUCTURED_POINTS\n\
+            DIMENSIONS 3 2 1\n\
+            SPACING 0.5 2 1\n\
+            ORIGIN 10 -20 30\n\
+            POINT_DATA 6\n\
+            SCALARS density short 1\n\
+            LOOKUP_TABLE default\n"
+            .to_vec();
+        // binaryはbig endian
+        let values = [-300i16, -1, 0, 1, 2, 30000];
+        bytes.extend(values.iter().flat_map(|x| x.to_be_bytes()));
+        std::fs::write(&path, bytes).unwrap();
+
+        let image = load_legacy(&path, &Progress::default()).unwrap();
+        assert_eq!(image.shape, (3, 2, 1));
+        assert_eq!(image.datatype, DataType::I16);
+        assert_eq!(image.spacing, (0.5, 2.0, 1.0));
+        assert_same_affine(
+            &image.affine,
+            &[
+                [0.5, 0.0, 0.0, 10.0],
+                [0.0, 2.0, 0.0, -20.0],
+                [0.0, 0.0, 1.0, 30.0],
+                [0.0, 0.0, 0.0, 1.0],
+            ],
+        );
+        assert_eq!(image.data, values.map(|x| x as f32).to_vec());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn legacy_bit_scalars_are_unsupported() {
+        let dir = temp_dir("vtk_legacy_bit");
+        let path = dir.join("image.vtk");
+        let mut bytes = b"# vtk DataFile Version 3.0
+            title
+            BINARY
+            DATASET STRUCTURED_POINTS
+            DIMENSIONS 8 1 1
+            POINT_DATA 8
+            SCALARS mask bit 1
+            LOOKUP_TABLE default
+"
+        .to_vec();
+        bytes.push(0b1010_0000);
+        std::fs::write(&path, bytes).unwrap();
+        assert!(matches!(
+            load_legacy(&path, &Progress::default()),
+            Err(Error::UnsupportedDataType(_))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn xml_appended_raw() {
+        let dir = temp_dir("vtk_xml_appended");
+        let path = dir.join("image.vti");
+        // 2つ目の配列がScalars. offsetはappended dataの先頭からのbyte数
+        let mut bytes = b"<?xml version=\"1.0\"?>\n\
+            <VTKFile type=\"ImageData\" version=\"1.0\" byte_order=\"LittleEndian\" header_type=\"UInt32\">\n\
+            <ImageData WholeExtent=\"1 2 0 1 0 0\" Origin=\"0 0 0\" Spacing=\"2 3 4\">\n\
+            <Piece Extent=\"1 2 0 1 0 0\">\n\
+            <PointData Scalars=\"label\">\n\
+            <DataArray type=\"Float64\" Name=\"other\" format=\"appended\" offset=\"0\"/>\n\
+            <DataArray type=\"UInt16\" Name=\"label\" format=\"appended\" offset=\"36\"/>\n\
+            </PointData>\n\
+            </Piece>\n\
+            </ImageData>\n\
+            <AppendedData encoding=\"raw\">\n_"
+            .to_vec();
+        bytes.extend(32u32.to_le_bytes());
+        bytes.extend([1.0f64, 2.0, 3.0, 4.0].iter().flat_map(|x| x.to_le_bytes()));
+        bytes.extend(8u32.to_le_bytes());
+        bytes.extend([7u16, 0, 65535, 3].iter().flat_map(|x| x.to_le_bytes()));
+        bytes.extend(b"\n</AppendedData>\n</VTKFile>\n");
+        std::fs::write(&path, bytes).unwrap();
+
+        let image = load_xml(&path, &Progress::default()).unwrap();
+        assert_eq!(image.shape, (2, 2, 1));
+        assert_eq!(image.datatype, DataType::U16);
+        assert_eq!(image.spacing, (2.0, 3.0, 4.0));
+        // extentの開始位置だけoriginがずれる
+        assert_eq!(image.affine[0][3], 2.0);
+        assert_eq!(image.data, vec![7.0, 0.0, 65535.0, 3.0]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn legacy_ascii_structured_points() {
+        let dir = temp_dir("vtk_legacy_ascii");
+        let path = dir.join("image.vtk");
+        let text = "# vtk DataFile Version 2.0\n\
+            title\n\
+            ASCII\n\
+            DATASET STRUCTURED_POINTS\n\
+            DIMENSIONS 2 2 2\n\
+            ASPECT_RATIO 1 1 2.5\n\
+            POINT_DATA 8\n\
+            SCALARS values float\n\
+            LOOKUP_TABLE default\n\
+            0.5 -1 2\n3 4\n  5 6e2 7\n";
+        std::fs::write(&path, text).unwrap();
+        let image = load_legacy(&path, &Progress::default()).unwrap();
+        assert_eq!(image.shape, (2, 2, 2));
+        assert_eq!(image.datatype, DataType::F32);
+        assert_eq!(image.spacing, (1.0, 1.0, 2.5));
+        assert_eq!(image.data, vec![0.5, -1.0, 2.0, 3.0, 4.0, 5.0, 600.0, 7.0]);
+
+        // 値が足りない
+        std::fs::write(&path, text.replace("5 6e2 7", "5")).unwrap();
+        assert!(matches!(
+            load_legacy(&path, &Progress::default()),
+            Err(Error::ShapeMismatch(_))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn encode_base64(bytes: &[u8]) -> String {
+        const TABLE: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut text = String::new();
+        for chunk in bytes.chunks(3) {
+            let n = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |n, (i, x)| n | (*x as u32) << (16 - 8 * i));
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    text.push(TABLE[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+                } else {
+                    text.push('=');
+                }
+            }
+        }
+        text
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(decode_base64(b"").unwrap(), b"");
+        assert_eq!(decode_base64(b"TWFu").unwrap(), b"Man");
+        assert_eq!(decode_base64(b"TWE=").unwrap(), b"Ma");
+        assert_eq!(decode_base64(b"TQ==").unwrap(), b"M");
+        // 空白は無視し, paddingの後に続くblockも読む
+        assert_eq!(decode_base64(b"TQ==\n TWE=").unwrap(), b"MMa");
+        let bytes: Vec<u8> = (0..=255).collect();
+        assert_eq!(
+            decode_base64(encode_base64(&bytes).as_bytes()).unwrap(),
+            bytes
+        );
+        assert!(matches!(decode_base64(b"TW*u"), Err(Error::Decode(_))));
+    }
+
+    /// 3x2x1のUInt16のvolumeのDataArrayを1つ持つ.vti
+    fn vti(compressor: &str, array: &str) -> String {
+        format!(
+            "<?xml version=\"1.0\"?>\n\
+             <VTKFile type=\"ImageData\" version=\"1.0\" byte_order=\"LittleEndian\" \
+             header_type=\"UInt32\"{}>\n\
+             <ImageData WholeExtent=\"0 2 0 1 0 0\" Origin=\"1 2 3\" Spacing=\"1 1 1\">\n\
+             <Piece Extent=\"0 2 0 1 0 0\">\n\
+             <PointData Scalars=\"values\">\n\
+             {}\n\
+             </PointData>\n\
+             </Piece>\n\
+             </ImageData>\n\
+             </VTKFile>\n",
+            compressor, array
+        )
+    }
+
+    const VALUES: [u16; 6] = [0, 1, 2, 300, 4000, 65535];
+
+    fn value_bytes() -> Vec<u8> {
+        VALUES.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn xml_inline_base64() {
+        let dir = temp_dir("vtk_xml_base64");
+        let path = dir.join("image.vti");
+        // headerはdataのbyte数
+        let mut bytes = 12u32.to_le_bytes().to_vec();
+        bytes.extend(value_bytes());
+        let array = format!(
+            "<DataArray type=\"UInt16\" Name=\"values\" format=\"binary\">\n{}\n</DataArray>",
+            encode_base64(&bytes)
+        );
+        std::fs::write(&path, vti("", &array)).unwrap();
+        let image = load_xml(&path, &Progress::default()).unwrap();
+        assert_eq!(image.shape, (3, 2, 1));
+        assert_eq!(image.datatype, DataType::U16);
+        assert_eq!(image.affine[1][3], 2.0);
+        assert_eq!(image.data, VALUES.map(|x| x as f32).to_vec());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// vtkZLibDataCompressorの形式. 8 byteのblockに分けて圧縮する
+    fn compressed_blocks() -> (Vec<u8>, Vec<u8>) {
+        use std::io::Write;
+        let data = value_bytes();
+        let blocks: Vec<Vec<u8>> = data
+            .chunks(8)
+            .map(|x| {
+                let mut encoder =
+                    flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(x).unwrap();
+                encoder.finish().unwrap()
+            })
+            .collect();
+        let mut header = vec![blocks.len() as u32, 8, (data.len() % 8) as u32];
+        header.extend(blocks.iter().map(|x| x.len() as u32));
+        (
+            header.iter().flat_map(|x| x.to_le_bytes()).collect(),
+            blocks.concat(),
+        )
+    }
+
+    #[test]
+    fn xml_zlib_compressed() {
+        let dir = temp_dir("vtk_xml_zlib");
+        let path = dir.join("image.vti");
+        let compressor = " compressor=\"vtkZLibDataCompressor\"";
+        let (header, blocks) = compressed_blocks();
+        // inline: block headerとdataは別々にbase64 encodeされている
+        let array = format!(
+            "<DataArray type=\"UInt16\" Name=\"values\" format=\"binary\">\n{}{}\n</DataArray>",
+            encode_base64(&header),
+            encode_base64(&blocks)
+        );
+        std::fs::write(&path, vti(compressor, &array)).unwrap();
+        let image = load_xml(&path, &Progress::default()).unwrap();
+        assert_eq!(image.data, VALUES.map(|x| x as f32).to_vec());
+
+        // appended raw
+        let array = "<DataArray type=\"UInt16\" Name=\"values\" format=\"appended\" offset=\"0\"/>";
+        let text = vti(compressor, array).replace("</VTKFile>\n", "");
+        let mut bytes = text.into_bytes();
+        bytes.extend(b"<AppendedData encoding=\"raw\">\n_");
+        bytes.extend(&header);
+        bytes.extend(&blocks);
+        bytes.extend(b"\n</AppendedData>\n</VTKFile>\n");
+        std::fs::write(&path, &bytes).unwrap();
+        let image = load_xml(&path, &Progress::default()).unwrap();
+        assert_eq!(image.data, VALUES.map(|x| x as f32).to_vec());
+
+        // blockの大きさがdataより大きい
+        let mut truncated = header.clone();
+        truncated.extend(&blocks[..blocks.len() - 2]);
+        assert!(matches!(
+            decompress_blocks(&truncated, 4, true),
+            Err(Error::TruncatedData { .. })
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
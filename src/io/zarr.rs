@@ -0,0 +1,874 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use cgmath::prelude::*;
+use glium::texture::{MipmapsOption, UncompressedFloatFormat};
+use tracing::{debug, info};
+
+use super::npy::{parse_descr, NpyType};
+use super::{blosc, Error, Image3D, Progress, Result};
+
+// 全体を読んでtextureに転送する解像度 (overview) のvoxel数の上限. これを超えない最も細かい解像度を使う.
+// 斜めの断面, volume rendering, 断面のtileが届くまでの表示に使う
+const MAX_OVERVIEW_VOXELS: usize = 1 << 24;
+const MAX_TEXTURE_SIZE: usize = 2048;
+// 断面の表示に読む領域 (tile) のvoxel数の上限
+const MAX_TILE_VOXELS: usize = 1 << 24;
+// tileを表示範囲の外に広げる幅 (表示範囲の大きさに対する割合). 少しの平行移動では読み直さない
+const TILE_MARGIN: f32 = 0.25;
+// 読み込んだchunkを保持するvoxel数の上限 (f32で512MB). 超えた場合は最も長く使っていないchunkから捨てる
+const MAX_CACHED_VALUES: usize = 1 << 27;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compressor {
+    Raw,
+    Gzip,
+    Zlib,
+    Blosc,
+}
+
+/// Zarr v2の配列 (.zarray) のmetadata
+#[derive(Debug)]
+struct ArrayMeta {
+    dir: PathBuf,
+    shape: Vec<usize>,
+    chunks: Vec<usize>,
+    npy_type: NpyType,
+    big_endian: bool,
+    compressor: Compressor,
+    fill_value: f32,
+    fortran_order: bool,
+    separator: String,
+}
+
+fn read_json(path: &Path) -> Result<serde_json::Value> {
+    Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+}
+
+fn usize_list(value: &serde_json::Value, key: &str) -> Result<Vec<usize>> {
+    value[key]
+        .as_array()
+        .map(|x| {
+            x.iter()
+                .filter_map(|x| x.as_u64())
+                .map(|x| x as usize)
+                .collect()
+        })
+        .ok_or_else(|| Error::Decode(format!("zarr array has no {}", key)))
+}
+
+fn float_list(value: &serde_json::Value) -> Option<Vec<f32>> {
+    value
+        .as_array()
+        .map(|x| x.iter().map(|x| x.as_f64().unwrap_or(0.0) as f32).collect())
+}
+
+impl ArrayMeta {
+    fn read(dir: &Path) -> Result<ArrayMeta> {
+        let meta = read_json(&dir.join(".zarray"))?;
+        if meta["zarr_format"].as_u64() != Some(2) {
+            return Err(Error::UnsupportedFormat(dir.to_path_buf()));
+        }
+        if meta["filters"].as_array().is_some_and(|x| !x.is_empty()) {
+            return Err(Error::Decode(format!(
+                "zarr filters are not supported : {}",
+                meta["filters"]
+            )));
+        }
+        let dtype = meta["dtype"]
+            .as_str()
+            .ok_or_else(|| Error::UnsupportedDataType(format!("zarr dtype {}", meta["dtype"])))?;
+        let (npy_type, big_endian) = parse_descr(dtype)?;
+        let compressor = match meta["compressor"]["id"].as_str() {
+            None => Compressor::Raw,
+            Some("gzip") => Compressor::Gzip,
+            Some("zlib") => Compressor::Zlib,
+            Some("blosc") => Compressor::Blosc,
+            Some(x) => {
+                return Err(Error::Decode(format!(
+                    "unsupported zarr compressor : {}",
+                    x
+                )))
+            }
+        };
+        let fill_value = match &meta["fill_value"] {
+            serde_json::Value::Number(x) => x.as_f64().unwrap_or(0.0) as f32,
+            serde_json::Value::String(x) if x == "NaN" => f32::NAN,
+            serde_json::Value::Bool(x) => *x as u8 as f32,
+            _ => 0.0,
+        };
+        let shape = usize_list(&meta, "shape")?;
+        let chunks = usize_list(&meta, "chunks")?;
+        if shape.len() != chunks.len() || chunks.contains(&0) {
+            return Err(Error::ShapeMismatch(format!(
+                "zarr shape {:?} and chunks {:?}",
+                shape, chunks
+            )));
+        }
+        Ok(ArrayMeta {
+            dir: dir.to_path_buf(),
+            shape,
+            chunks,
+            npy_type,
+            big_endian,
+            compressor,
+            fill_value,
+            fortran_order: meta["order"].as_str() == Some("F"),
+            separator: meta["dimension_separator"]
+                .as_str()
+                .unwrap_or(".")
+                .to_string(),
+        })
+    }
+}
+
+// (解像度, 配列の次元ごとのchunkのindex)
+type ChunkKey = (usize, Vec<usize>);
+
+/// 展開したchunkのLRU cache. 全ての解像度で共有する
+struct ChunkCache {
+    // chunk -> (値, 最後に使った時刻)
+    chunks: HashMap<ChunkKey, (Arc<Vec<f32>>, u64)>,
+    clock: u64,
+    values: usize,
+    // 保持するvoxel数の上限
+    capacity: usize,
+}
+
+impl ChunkCache {
+    fn new(capacity: usize) -> Self {
+        ChunkCache {
+            chunks: HashMap::new(),
+            clock: 0,
+            values: 0,
+            capacity,
+        }
+    }
+
+    fn get(&mut self, key: &ChunkKey) -> Option<Arc<Vec<f32>>> {
+        self.clock += 1;
+        let (chunk, used) = self.chunks.get_mut(key)?;
+        *used = self.clock;
+        Some(chunk.clone())
+    }
+
+    fn insert(&mut self, key: ChunkKey, chunk: Arc<Vec<f32>>) {
+        while self.values + chunk.len() > self.capacity {
+            let Some(oldest) = self
+                .chunks
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if let Some((evicted, _)) = self.chunks.remove(&oldest) {
+                self.values -= evicted.len();
+            }
+        }
+        self.clock += 1;
+        self.values += chunk.len();
+        if let Some((replaced, _)) = self.chunks.insert(key, (chunk, self.clock)) {
+            self.values -= replaced.len();
+        }
+    }
+}
+
+/// 断面の表示に読む領域. `level`の解像度のvoxel indexで lo <= v < hi
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TileRequest {
+    pub level: usize,
+    pub lo: [usize; 3],
+    pub hi: [usize; 3],
+}
+
+impl TileRequest {
+    pub fn shape(&self) -> [usize; 3] {
+        [0, 1, 2].map(|d| self.hi[d] - self.lo[d])
+    }
+
+    /// 同じ解像度で`other`の領域を全て含むか
+    pub fn contains(&self, other: &TileRequest) -> bool {
+        self.level == other.level
+            && (0..3).all(|d| self.lo[d] <= other.lo[d] && other.hi[d] <= self.hi[d])
+    }
+
+    /// textureとして転送できる大きさか
+    fn fits(&self) -> bool {
+        let shape = self.shape();
+        shape.iter().product::<usize>() <= MAX_TILE_VOXELS
+            && shape.iter().all(|x| *x <= MAX_TEXTURE_SIZE)
+    }
+}
+
+/// chunkごとに必要な部分だけを読むZarrの配列 (OME-Zarrのmultiscaleの1つの解像度)
+pub struct ZarrArray {
+    meta: ArrayMeta,
+    // volumeのx, y, z軸に対応する配列の次元. それ以外の次元 (t, c) は0番目を読む
+    axes: [usize; 3],
+    // multiscaleの何番目の解像度か (cacheのkey)
+    level: usize,
+    // voxel index -> world座標(mm) (column-major)
+    affine: cgmath::Matrix4<f32>,
+    cache: Arc<Mutex<ChunkCache>>,
+}
+
+impl fmt::Debug for ZarrArray {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZarrArray")
+            .field("dir", &self.meta.dir)
+            .field("shape", &self.meta.shape)
+            .field("chunks", &self.meta.chunks)
+            .field("axes", &self.axes)
+            .finish()
+    }
+}
+
+impl ZarrArray {
+    /// x, y, z軸の大きさ
+    pub fn shape(&self) -> [usize; 3] {
+        self.axes.map(|i| self.meta.shape[i])
+    }
+
+    /// voxel index -> world座標(mm)の変換行列
+    pub fn affine_matrix(&self) -> cgmath::Matrix4<f32> {
+        self.affine
+    }
+
+    fn world_to_voxel(&self, world: [f32; 3]) -> [f32; 3] {
+        let inverse = self
+            .affine
+            .inverse_transform()
+            .unwrap_or_else(cgmath::Matrix4::identity);
+        let voxel = inverse * cgmath::Vector4::new(world[0], world[1], world[2], 1.0);
+        [voxel.x, voxel.y, voxel.z]
+    }
+
+    /// 配列の次元ごとのindexのchunkを読む. ファイルがないchunkはfill_value
+    fn chunk(&self, index: &[usize]) -> Result<Arc<Vec<f32>>> {
+        let key = (self.level, index.to_vec());
+        if let Some(chunk) = self.cache.lock().unwrap().get(&key) {
+            return Ok(chunk);
+        }
+        let meta = &self.meta;
+        let chunk_len: usize = meta.chunks.iter().product();
+        let name = index
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()
+            .join(&meta.separator);
+        let path = meta.dir.join(&name);
+        let chunk = if path.exists() {
+            debug!("Read chunk {:?}", path);
+            let bytes = std::fs::read(&path)?;
+            let bytes = match meta.compressor {
+                Compressor::Raw => bytes,
+                Compressor::Gzip | Compressor::Zlib => {
+                    let mut decoded = Vec::new();
+                    let result = if meta.compressor == Compressor::Gzip {
+                        flate2::read::MultiGzDecoder::new(bytes.as_slice())
+                            .read_to_end(&mut decoded)
+                    } else {
+                        flate2::read::ZlibDecoder::new(bytes.as_slice()).read_to_end(&mut decoded)
+                    };
+                    result.map_err(|e| Error::Decode(format!("zarr chunk {} : {}", name, e)))?;
+                    decoded
+                }
+                Compressor::Blosc => blosc::decompress(&bytes)?,
+            };
+            let expected = chunk_len * meta.npy_type.size_of();
+            if bytes.len() < expected {
+                return Err(Error::TruncatedData {
+                    expected,
+                    actual: bytes.len(),
+                });
+            }
+            meta.npy_type.decode(&bytes[..expected], meta.big_endian)
+        } else {
+            vec![meta.fill_value; chunk_len]
+        };
+        let chunk = Arc::new(chunk);
+        self.cache.lock().unwrap().insert(key, chunk.clone());
+        Ok(chunk)
+    }
+
+    /// x, y, z軸のindexが lo <= v < hi の領域を読む. x軸が最も速く変わる順に並べる.
+    /// z方向のchunkの行ごとに進捗を通知する
+    pub fn read_box(
+        &self,
+        lo: [usize; 3],
+        hi: [usize; 3],
+        progress: &Progress,
+    ) -> Result<Vec<f32>> {
+        let meta = &self.meta;
+        let shape = self.shape();
+        if (0..3).any(|d| lo[d] >= hi[d] || hi[d] > shape[d]) {
+            return Err(Error::ShapeMismatch(format!(
+                "zarr box {:?} - {:?} in shape {:?}",
+                lo, hi, shape
+            )));
+        }
+        let dims = [0, 1, 2].map(|d| hi[d] - lo[d]);
+        let mut data = vec![meta.fill_value; dims.iter().product()];
+
+        // chunk内のindex -> offset
+        let n_dims = meta.chunks.len();
+        let mut strides = vec![1; n_dims];
+        if meta.fortran_order {
+            for i in 1..n_dims {
+                strides[i] = strides[i - 1] * meta.chunks[i - 1];
+            }
+        } else {
+            for i in (0..n_dims - 1).rev() {
+                strides[i] = strides[i + 1] * meta.chunks[i + 1];
+            }
+        }
+        let chunk_size = self.axes.map(|i| meta.chunks[i]);
+        let stride = self.axes.map(|i| strides[i]);
+        let mut chunk_index = vec![0; n_dims];
+        let cz_range = lo[2] / chunk_size[2]..=(hi[2] - 1) / chunk_size[2];
+        let n_rows = cz_range.clone().count();
+        for (row_count, cz) in cz_range.enumerate() {
+            for cy in lo[1] / chunk_size[1]..=(hi[1] - 1) / chunk_size[1] {
+                for cx in lo[0] / chunk_size[0]..=(hi[0] - 1) / chunk_size[0] {
+                    let c = [cx, cy, cz];
+                    for d in 0..3 {
+                        chunk_index[self.axes[d]] = c[d];
+                    }
+                    let chunk = self.chunk(&chunk_index)?;
+                    let start = [0, 1, 2].map(|d| lo[d].max(c[d] * chunk_size[d]));
+                    let end = [0, 1, 2].map(|d| hi[d].min((c[d] + 1) * chunk_size[d]));
+                    for z in start[2]..end[2] {
+                        for y in start[1]..end[1] {
+                            let row = (y - c[1] * chunk_size[1]) * stride[1]
+                                + (z - c[2] * chunk_size[2]) * stride[2];
+                            let dst = (y - lo[1]) * dims[0] + (z - lo[2]) * dims[0] * dims[1];
+                            for x in start[0]..end[0] {
+                                data[dst + x - lo[0]] =
+                                    chunk[row + (x - c[0] * chunk_size[0]) * stride[0]];
+                            }
+                        }
+                    }
+                }
+            }
+            progress.report((row_count + 1) as f32 / n_rows as f32)?;
+        }
+        Ok(data)
+    }
+}
+
+/// OME-Zarrのmultiscaleの全ての解像度 (細かい順).
+/// 断面は表示の倍率に合った解像度で表示範囲だけを読み, 全体は粗い解像度 (overview) で持つ
+pub struct ZarrPyramid {
+    levels: Vec<ZarrArray>,
+    // (解像度, 全体の値). textureに収まる解像度がない場合はNone
+    overview: Option<(usize, Vec<f32>)>,
+}
+
+impl fmt::Debug for ZarrPyramid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZarrPyramid")
+            .field("levels", &self.levels)
+            .field("overview", &self.overview.as_ref().map(|(level, _)| level))
+            .finish()
+    }
+}
+
+impl ZarrPyramid {
+    pub fn level(&self, level: usize) -> &ZarrArray {
+        &self.levels[level]
+    }
+
+    /// overviewの配列と値
+    pub fn overview(&self) -> Option<(&ZarrArray, &[f32])> {
+        self.overview
+            .as_ref()
+            .map(|(level, data)| (&self.levels[*level], data.as_slice()))
+    }
+
+    /// 表示範囲 (world座標の左下, 右下, 左上の隅) を`pixels` (横, 縦) で表示するときに読む領域.
+    /// 1 pixelに1 voxel以上が対応する最も粗い解像度を選ぶ. 範囲がvolumeの外の場合はNone
+    pub fn visible_tile(&self, corners: [[f32; 3]; 3], pixels: [u32; 2]) -> Option<TileRequest> {
+        let distance =
+            |a: [f32; 3], b: [f32; 3]| (0..3).map(|d| (a[d] - b[d]).powi(2)).sum::<f32>().sqrt();
+        // 1 pixelあたりのvoxel数
+        let voxels_per_pixel = |array: &ZarrArray| {
+            let [p0, p1, p2] = corners.map(|x| array.world_to_voxel(x));
+            (distance(p0, p1) / pixels[0].max(1) as f32)
+                .max(distance(p0, p2) / pixels[1].max(1) as f32)
+        };
+        let finest = (0..self.levels.len())
+            .rev()
+            .find(|&i| voxels_per_pixel(&self.levels[i]) >= 1.0)
+            .unwrap_or(0);
+        for (level, array) in self.levels.iter().enumerate().skip(finest) {
+            let [p0, p1, p2] = corners.map(|x| array.world_to_voxel(x));
+            let p3 = [0, 1, 2].map(|d| p1[d] + p2[d] - p0[d]);
+            let shape = array.shape();
+            let mut lo = [0; 3];
+            let mut hi = [0; 3];
+            for d in 0..3 {
+                let values = [p0[d], p1[d], p2[d], p3[d]];
+                let min = values.into_iter().fold(f32::INFINITY, f32::min);
+                let max = values.into_iter().fold(f32::NEG_INFINITY, f32::max);
+                // voxel vは v - 0.5 <= x < v + 0.5 の範囲
+                lo[d] = (min.round().max(0.0) as usize).min(shape[d]);
+                hi[d] = ((max.round() + 1.0).max(0.0) as usize).min(shape[d]);
+                if lo[d] >= hi[d] {
+                    return None;
+                }
+            }
+            let tile = TileRequest { level, lo, hi };
+            if tile.fits() {
+                return Some(tile);
+            }
+        }
+        None
+    }
+
+    /// `tile`を表示範囲の外に広げた領域. 1 voxelの厚さの方向 (断面に垂直な方向) は広げない
+    pub fn expand_tile(&self, tile: &TileRequest) -> TileRequest {
+        let shape = self.levels[tile.level].shape();
+        let mut expanded = *tile;
+        for (d, size) in tile.shape().into_iter().enumerate() {
+            if size > 1 {
+                let margin = (size as f32 * TILE_MARGIN).ceil() as usize;
+                expanded.lo[d] = tile.lo[d].saturating_sub(margin);
+                expanded.hi[d] = (tile.hi[d] + margin).min(shape[d]);
+            }
+        }
+        if expanded.fits() {
+            expanded
+        } else {
+            *tile
+        }
+    }
+
+    pub fn read_tile(&self, tile: &TileRequest, progress: &Progress) -> Result<Vec<f32>> {
+        self.levels
+            .get(tile.level)
+            .ok_or_else(|| Error::ShapeMismatch(format!("zarr level {}", tile.level)))?
+            .read_box(tile.lo, tile.hi, progress)
+    }
+}
+
+/// OME-Zarrのaxesのunitをmmに換算する倍率
+fn unit_to_mm(unit: Option<&str>) -> f32 {
+    match unit {
+        Some("nanometer") => 1e-6,
+        Some("micrometer") => 1e-3,
+        Some("centimeter") => 10.0,
+        Some("meter") => 1e3,
+        _ => 1.0,
+    }
+}
+
+/// coordinateTransformationsのscaleとtranslation
+fn transformations(value: &serde_json::Value, n_dims: usize) -> (Vec<f32>, Vec<f32>) {
+    let mut scale = vec![1.0; n_dims];
+    let mut translation = vec![0.0; n_dims];
+    for x in value.as_array().into_iter().flatten() {
+        match x["type"].as_str() {
+            Some("scale") => {
+                if let Some(values) = float_list(&x["scale"]).filter(|x| x.len() == n_dims) {
+                    scale = values;
+                }
+            }
+            Some("translation") => {
+                if let Some(values) = float_list(&x["translation"]).filter(|x| x.len() == n_dims) {
+                    translation = values;
+                }
+            }
+            _ => (),
+        }
+    }
+    (scale, translation)
+}
+
+/// Zarr v2のdirectory (OME-Zarrのmultiscale, または1つの配列) を開く.
+/// voxel値はoverviewの解像度だけを読み, 全ての解像度を`Image3D::chunked`に設定する
+pub fn load(data_path: &Path, progress: &Progress) -> Result<Image3D> {
+    debug!("Loading zarr directory");
+    let attrs_path = data_path.join(".zattrs");
+    let attrs = if attrs_path.exists() {
+        read_json(&attrs_path)?
+    } else {
+        serde_json::Value::Null
+    };
+    let multiscale = &attrs["multiscales"][0];
+
+    // (配列のpath, scale, translation) を解像度の細かい順に並べる
+    let mut levels = Vec::new();
+    match multiscale["datasets"].as_array() {
+        Some(datasets) => {
+            for dataset in datasets {
+                let dir = data_path.join(dataset["path"].as_str().unwrap_or_default());
+                let meta = ArrayMeta::read(&dir)?;
+                let n_dims = meta.shape.len();
+                let (mut scale, mut translation) =
+                    transformations(&dataset["coordinateTransformations"], n_dims);
+                // multiscale全体の変換はdatasetごとの変換の後に適用する
+                let (global_scale, global_translation) =
+                    transformations(&multiscale["coordinateTransformations"], n_dims);
+                for i in 0..n_dims {
+                    scale[i] *= global_scale[i];
+                    translation[i] = translation[i] * global_scale[i] + global_translation[i];
+                }
+                levels.push((meta, scale, translation));
+            }
+        }
+        None => {
+            let meta = ArrayMeta::read(data_path)?;
+            let n_dims = meta.shape.len();
+            levels.push((meta, vec![1.0; n_dims], vec![0.0; n_dims]));
+        }
+    }
+    if levels.is_empty() {
+        return Err(Error::Decode("zarr multiscale has no datasets".to_string()));
+    }
+    let n_dims = levels[0].0.shape.len();
+    if n_dims < 3 {
+        return Err(Error::ShapeMismatch(format!(
+            "zarr array with shape {:?} is not a volume",
+            levels[0].0.shape
+        )));
+    }
+
+    // axesの名前 (v0.4は{name, type, unit}, v0.3は名前のみ). なければ末尾の3次元を z, y, x とする
+    let axes_meta = multiscale["axes"].as_array().cloned().unwrap_or_default();
+    let axis_name = |i: usize| {
+        axes_meta
+            .get(i)
+            .and_then(|x| x["name"].as_str().or(x.as_str()))
+            .map(|x| x.to_ascii_lowercase())
+    };
+    let find = |name: &str| (0..n_dims).find(|&i| axis_name(i).as_deref() == Some(name));
+    let axes = match (find("x"), find("y"), find("z")) {
+        (Some(x), Some(y), Some(z)) => [x, y, z],
+        _ => [n_dims - 1, n_dims - 2, n_dims - 3],
+    };
+    let units = axes.map(|i| unit_to_mm(axes_meta.get(i).and_then(|x| x["unit"].as_str())));
+
+    let cache = Arc::new(Mutex::new(ChunkCache::new(MAX_CACHED_VALUES)));
+    let levels: Vec<ZarrArray> = levels
+        .into_iter()
+        .enumerate()
+        .map(|(level, (meta, scale, translation))| {
+            let mut affine = [[0.0; 4]; 4];
+            for d in 0..3 {
+                affine[d][d] = scale[axes[d]] * units[d];
+                affine[d][3] = translation[axes[d]] * units[d];
+            }
+            affine[3][3] = 1.0;
+            ZarrArray {
+                meta,
+                axes,
+                level,
+                affine: cgmath::Matrix4::from(affine).transpose(),
+                cache: cache.clone(),
+            }
+        })
+        .collect();
+    for array in &levels {
+        info!(
+            "Zarr level {} : {:?} (array {:?}, chunks {:?})",
+            array.level,
+            array.shape(),
+            array.meta.shape,
+            array.meta.chunks
+        );
+    }
+
+    // 全体を読む解像度
+    let overview = match levels.iter().position(|array| {
+        let shape = array.shape();
+        shape.iter().product::<usize>() <= MAX_OVERVIEW_VOXELS
+            && shape.iter().all(|x| *x <= MAX_TEXTURE_SIZE)
+    }) {
+        Some(level) => {
+            let array = &levels[level];
+            info!("Zarr overview level : {}", level);
+            Some((level, array.read_box([0; 3], array.shape(), progress)?))
+        }
+        None => {
+            info!(
+                "No zarr level fits in a texture. Oblique planes and volume rendering are disabled"
+            );
+            None
+        }
+    };
+
+    let array = &levels[0];
+    let shape = array.shape();
+    let affine = array.affine_matrix().transpose();
+    let spacing = [0, 1, 2].map(|d| affine[d][d].abs());
+    let datatype = array.meta.npy_type.datatype();
+    Ok(Image3D {
+        data: Vec::new(),
+        shape: (shape[0] as u32, shape[1] as u32, shape[2] as u32),
+        spacing: (spacing[0], spacing[1], spacing[2]),
+        frames: 1,
+        frame_interval: 0.0,
+        datatype,
+        scl_slope: 0.0,
+        scl_inter: 0.0,
+        affine: affine.into(),
+        qform_code: 0,
+        sform_code: 0,
+        format: Some(UncompressedFloatFormat::F32),
+        mipmaps: Some(MipmapsOption::NoMipmap),
+        lut: None,
+        chunked: Some(Arc::new(ZarrPyramid { levels, overview })),
+        is_mask: false,
+    })
+}
+
+/// Zarrのdirectory (.zarray か .zattrsを含む) か
+pub fn is_zarr_dir(data_path: &Path) -> bool {
+    data_path.join(".zarray").exists() || data_path.join(".zattrs").exists()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::test_util::temp_dir;
+
+    // 配列の次元 (z, y, x) の大きさとchunk. どの次元もchunkで割り切れない
+    const SHAPE: [usize; 3] = [5, 4, 3];
+    const CHUNKS: [usize; 3] = [2, 3, 2];
+    const FILL_VALUE: f32 = 7.0;
+    // ファイルを書かないchunk
+    const MISSING: [usize; 3] = [1, 1, 0];
+
+    fn value(index: [usize; 3]) -> f32 {
+        (index[0] * 100 + index[1] * 10 + index[2]) as f32
+    }
+
+    fn expected(index: [usize; 3]) -> f32 {
+        if (0..3).all(|d| index[d] / CHUNKS[d] == MISSING[d]) {
+            FILL_VALUE
+        } else {
+            value(index)
+        }
+    }
+
+    /// u16の配列を圧縮せずに書く. 端のchunkも全体の大きさで書く
+    fn write_array(dir: &Path, order: &str, separator: &str) {
+        std::fs::create_dir_all(dir).unwrap();
+        let meta = serde_json::json!({
+            "zarr_format": 2,
+            "shape": SHAPE,
+            "chunks": CHUNKS,
+            "dtype": "<u2",
+            "compressor": null,
+            "fill_value": FILL_VALUE,
+            "filters": null,
+            "order": order,
+            "dimension_separator": separator,
+        });
+        std::fs::write(dir.join(".zarray"), meta.to_string()).unwrap();
+        let n_chunks = [0, 1, 2].map(|d| SHAPE[d].div_ceil(CHUNKS[d]));
+        for c0 in 0..n_chunks[0] {
+            for c1 in 0..n_chunks[1] {
+                for c2 in 0..n_chunks[2] {
+                    let c = [c0, c1, c2];
+                    if c == MISSING {
+                        continue;
+                    }
+                    let mut bytes = vec![0; CHUNKS.iter().product::<usize>() * 2];
+                    for i0 in 0..CHUNKS[0] {
+                        for i1 in 0..CHUNKS[1] {
+                            for i2 in 0..CHUNKS[2] {
+                                let offset = if order == "F" {
+                                    i0 + CHUNKS[0] * (i1 + CHUNKS[1] * i2)
+                                } else {
+                                    (i0 * CHUNKS[1] + i1) * CHUNKS[2] + i2
+                                };
+                                let index = [0, 1, 2].map(|d| c[d] * CHUNKS[d] + [i0, i1, i2][d]);
+                                let x = value(index) as u16;
+                                bytes[offset * 2..offset * 2 + 2].copy_from_slice(&x.to_le_bytes());
+                            }
+                        }
+                    }
+                    let key = c.map(|x| x.to_string()).join(separator);
+                    let path = dir.join(key);
+                    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                    std::fs::write(path, bytes).unwrap();
+                }
+            }
+        }
+    }
+
+    /// x, y, z軸の`lo`から`hi`の領域の値 (x軸が最も速く変わる順)
+    fn expected_box(lo: [usize; 3], hi: [usize; 3]) -> Vec<f32> {
+        let mut values = Vec::new();
+        for z in lo[2]..hi[2] {
+            for y in lo[1]..hi[1] {
+                for x in lo[0]..hi[0] {
+                    values.push(expected([z, y, x]));
+                }
+            }
+        }
+        values
+    }
+
+    #[test]
+    fn cache_evicts_least_recently_used() {
+        let mut cache = ChunkCache::new(6);
+        let key = |i: usize| (0, vec![i]);
+        for i in 0..3 {
+            cache.insert(key(i), Arc::new(vec![i as f32; 2]));
+        }
+        // 0を使ったので, 次に追加すると1が捨てられる
+        assert!(cache.get(&key(0)).is_some());
+        cache.insert(key(3), Arc::new(vec![3.0; 2]));
+        assert!(cache.get(&key(1)).is_none());
+        assert_eq!(cache.get(&key(0)).unwrap()[0], 0.0);
+        assert!(cache.get(&key(2)).is_some());
+        assert_eq!(cache.values, 6);
+        // 大きいchunkは必要なだけ捨てる
+        cache.insert((1, vec![0]), Arc::new(vec![9.0; 4]));
+        assert_eq!(cache.chunks.len(), 2);
+        assert!(cache.get(&key(2)).is_some());
+        assert_eq!(cache.values, 6);
+    }
+
+    #[test]
+    fn read_slice_across_edge_chunks() {
+        let root = temp_dir("zarr_slices");
+        for (name, order, separator) in [("c", "C", "/"), ("f", "F", ".")] {
+            let dir = root.join(name);
+            write_array(&dir, order, separator);
+            let image = load(&dir, &Progress::default()).unwrap();
+            assert_eq!(image.shape, (3, 4, 5));
+            assert_eq!(image.datatype, crate::io::DataType::U16);
+            let pyramid = image.chunked.unwrap();
+            let array = pyramid.level(0);
+            let shape = array.shape();
+            for axis in 0..3 {
+                for index in 0..shape[axis] {
+                    let mut lo = [0; 3];
+                    let mut hi = shape;
+                    lo[axis] = index;
+                    hi[axis] = index + 1;
+                    let slice = array.read_box(lo, hi, &Progress::default()).unwrap();
+                    assert_eq!(
+                        slice,
+                        expected_box(lo, hi),
+                        "{} axis {} index {}",
+                        name,
+                        axis,
+                        index
+                    );
+                }
+            }
+            // chunkの境界をまたぐ領域
+            let (lo, hi) = ([1, 2, 1], [3, 4, 4]);
+            assert_eq!(
+                array.read_box(lo, hi, &Progress::default()).unwrap(),
+                expected_box(lo, hi)
+            );
+            assert!(array
+                .read_box([0, 0, 0], [4, 1, 1], &Progress::default())
+                .is_err());
+            // 全体が小さいのでoverviewは最も細かい解像度
+            let (overview, data) = pyramid.overview().unwrap();
+            assert_eq!(overview.shape(), shape);
+            assert_eq!(data, expected_box([0; 3], shape).as_slice());
+        }
+    }
+
+    #[test]
+    fn visible_tile_follows_zoom() {
+        let root = temp_dir("zarr_pyramid");
+        let level = |path: &str, size: usize| {
+            let dir = root.join(path);
+            std::fs::create_dir_all(&dir).unwrap();
+            let meta = serde_json::json!({
+                "zarr_format": 2,
+                "shape": [size, size, size],
+                "chunks": [4, 4, 4],
+                "dtype": "|u1",
+                "compressor": null,
+                "fill_value": 0,
+                "order": "C",
+            });
+            std::fs::write(dir.join(".zarray"), meta.to_string()).unwrap();
+        };
+        level("0", 8);
+        level("1", 4);
+        let attrs = serde_json::json!({
+            "multiscales": [{
+                "axes": ["z", "y", "x"],
+                "datasets": [
+                    {"path": "0", "coordinateTransformations": [{"type": "scale", "scale": [1.0, 1.0, 1.0]}]},
+                    {"path": "1", "coordinateTransformations": [
+                        {"type": "scale", "scale": [2.0, 2.0, 2.0]},
+                        {"type": "translation", "translation": [0.5, 0.5, 0.5]},
+                    ]},
+                ],
+            }],
+        });
+        std::fs::write(root.join(".zattrs"), attrs.to_string()).unwrap();
+        let image = load(&root, &Progress::default()).unwrap();
+        let pyramid = image.chunked.unwrap();
+        assert_eq!(pyramid.level(1).shape(), [4; 3]);
+
+        // z = 3のaxialの断面全体
+        let corners = [[-0.5, -0.5, 3.0], [7.5, -0.5, 3.0], [-0.5, 7.5, 3.0]];
+        let tile = |level, lo, hi| Some(TileRequest { level, lo, hi });
+        // 1 pixelに1 voxel
+        assert_eq!(
+            pyramid.visible_tile(corners, [8, 8]),
+            tile(0, [0, 0, 3], [8, 8, 4])
+        );
+        // 縮小すると粗い解像度
+        assert_eq!(
+            pyramid.visible_tile(corners, [4, 4]),
+            tile(1, [0, 0, 1], [4, 4, 2])
+        );
+        assert_eq!(
+            pyramid.visible_tile(corners, [2, 2]),
+            tile(1, [0, 0, 1], [4, 4, 2])
+        );
+        // 拡大した一部分
+        let zoomed = [[1.5, 2.5, 3.0], [3.5, 2.5, 3.0], [1.5, 4.5, 3.0]];
+        let visible = pyramid.visible_tile(zoomed, [100, 100]).unwrap();
+        assert_eq!(
+            visible,
+            TileRequest {
+                level: 0,
+                lo: [2, 3, 3],
+                hi: [5, 6, 4]
+            }
+        );
+        // 断面に垂直な方向には広げない
+        assert_eq!(
+            pyramid.expand_tile(&visible),
+            TileRequest {
+                level: 0,
+                lo: [1, 2, 3],
+                hi: [6, 7, 4]
+            }
+        );
+        assert!(pyramid.expand_tile(&visible).contains(&visible));
+        // volumeの外
+        let outside = corners.map(|[x, y, _]| [x, y, 20.0]);
+        assert_eq!(pyramid.visible_tile(outside, [8, 8]), None);
+    }
+
+    #[test]
+    fn multiscale_without_datasets() {
+        let root = temp_dir("zarr_no_datasets");
+        let attrs = serde_json::json!({"multiscales": [{"datasets": []}]});
+        std::fs::write(root.join(".zattrs"), attrs.to_string()).unwrap();
+        assert!(matches!(
+            load(&root, &Progress::default()),
+            Err(Error::Decode(_))
+        ));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
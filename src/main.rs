@@ -1,11 +1,11 @@
 mod io;
 mod shader;
 mod view;
-use tracing::info;
-use tracing_subscriber;
+use io::loader::LoadEvent;
+use tracing::{error, info};
 use view::simple::Simple2DView;
 use view::simple3d::Simple3DView;
-use view::View;
+use view::{UserEvent, View};
 use winit::{
     keyboard::{Key, NamedKey},
     platform::modifier_supplement::KeyEventExtModifierSupplement,
@@ -30,12 +30,43 @@ impl ViewMode {
     fn get_view_mut(&mut self) -> &mut Box<dyn View> {
         &mut self.views[self.current_view]
     }
+
+    /// 画像の読み込みに成功した場合のみ表示するviewを切り替える
+    fn set_image(
+        &mut self,
+        display: &glium::Display<glium::glutin::surface::WindowSurface>,
+        path: &std::path::Path,
+    ) {
+        let is_2d = io::is_image2d_file(path);
+        if !is_2d && !io::is_volume_file(path) {
+            error!("Unsupported file : {:?}", path);
+            return;
+        }
+        let index = if is_2d { 1 } else { 0 };
+        match self.views[index].set_image(display, path) {
+            Ok(()) if is_2d => self.set_2d_view(),
+            Ok(()) => self.set_3d_view(),
+            Err(e) => error!("Failed to load {:?} : {}", path, e),
+        }
+    }
+
+    /// volumeの読み込みは3D viewだけが行う
+    fn handle_user_event(
+        &mut self,
+        display: &glium::Display<glium::glutin::surface::WindowSurface>,
+        event: UserEvent,
+    ) {
+        self.views[0].handle_user_event(display, event);
+    }
 }
 
-fn serialize_image(nii_file_path: &std::path::Path, output_dir: &std::path::Path) {
+fn serialize_image(
+    nii_file_path: &std::path::Path,
+    output_dir: &std::path::Path,
+) -> io::Result<()> {
     if !output_dir.exists() {
         info!("Creating directory: {:?}", output_dir);
-        std::fs::create_dir_all(output_dir).unwrap();
+        std::fs::create_dir_all(output_dir)?;
     }
     let stem = nii_file_path.file_name().unwrap().to_str().unwrap();
     let output_file = if &stem[stem.len() - 4..] == ".nii" {
@@ -43,11 +74,87 @@ fn serialize_image(nii_file_path: &std::path::Path, output_dir: &std::path::Path
     } else {
         output_dir.join(format!("{}.raw", &stem[..stem.len() - 7]))
     };
-    io::load_image3d(nii_file_path).serialize(&output_file);
+    io::load_image3d(nii_file_path, &io::LoadOptions::default())?.serialize(&output_file)
+}
+
+/// `viewer3d convert <input> <output> [options]` : 読み込める形式のvolumeを出力の拡張子の形式に変換する
+fn convert_image(
+    input: &std::path::Path,
+    output: &std::path::Path,
+    options: &io::LoadOptions,
+) -> io::Result<()> {
+    let image = io::load_image3d(input, options)?;
+    io::save_image3d(&image, output)
+}
+
+/// convertのoption (`--array <name>`, `--spacing <x,y,z>`, `--channel <r|g|b>`) を読む.
+/// 以前の`convert <input> <output> <array>`の形式の配列名も受け付ける
+fn parse_convert_options(args: &[String]) -> Result<io::LoadOptions, String> {
+    let mut options = io::LoadOptions::default();
+    let mut args = args.iter().peekable();
+    if let Some(array) = args.next_if(|x| !x.starts_with("--")) {
+        options.npz_array = Some(array.clone());
+    }
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} needs a value", flag))?;
+        match flag.as_str() {
+            "--array" => options.npz_array = Some(value.clone()),
+            "--spacing" => {
+                let spacing = value
+                    .split(',')
+                    .map(|x| x.trim().parse::<f32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .ok()
+                    .filter(|x| x.len() == 3)
+                    .ok_or_else(|| format!("invalid spacing : {}", value))?;
+                options.slice_spacing = Some((spacing[0], spacing[1], spacing[2]));
+            }
+            "--channel" => {
+                options.rgb_channel = Some(match value.to_ascii_lowercase().as_str() {
+                    "r" | "0" => 0,
+                    "g" | "1" => 1,
+                    "b" | "2" => 2,
+                    _ => return Err(format!("invalid channel : {}", value)),
+                })
+            }
+            _ => return Err(format!("unknown option : {}", flag)),
+        }
+    }
+    Ok(options)
 }
 
 fn main() {
     tracing_subscriber::fmt::init();
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|x| x.as_str()) == Some("convert") {
+        let options = match args.get(4..).map(parse_convert_options) {
+            Some(Ok(options)) => options,
+            result => {
+                if let Some(Err(e)) = result {
+                    eprintln!("{}", e);
+                }
+                eprintln!(
+                    "Usage: {} convert <input> <output(.nii|.nii.gz|.nrrd|.mha|.mhd|.npy|.raw)> \
+                     [<array name in .npz> | --array <name>] [--spacing <x,y,z>] [--channel <r|g|b>]",
+                    args[0]
+                );
+                std::process::exit(1);
+            }
+        };
+        if let Err(e) = convert_image(
+            std::path::Path::new(&args[2]),
+            std::path::Path::new(&args[3]),
+            &options,
+        ) {
+            eprintln!("Failed to convert {} : {}", args[2], e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     info!("Starting image viewer");
 
     if false {
@@ -57,10 +164,12 @@ fn main() {
             "/data/cas/1-200/1.img.nii"
         ));
         let output_dir = std::path::Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/data/cas/raw"));
-        serialize_image(nii_file_path, output_dir);
+        if let Err(e) = serialize_image(nii_file_path, output_dir) {
+            error!("Failed to serialize {:?} : {}", nii_file_path, e);
+        }
     }
 
-    let event_loop = winit::event_loop::EventLoopBuilder::new()
+    let event_loop = winit::event_loop::EventLoopBuilder::<UserEvent>::with_user_event()
         .build()
         .expect("Event loop building");
     // event_loop.set_control_flow(winit::event_loop::ControlFlow::Wait);
@@ -68,8 +177,14 @@ fn main() {
         .with_title("image viewer")
         .build(&event_loop);
 
-    let view2d = Simple2DView::new(&display);
-    let view3d = Simple3DView::new(&display);
+    let view2d = Simple2DView::new(&display).unwrap_or_else(|e| {
+        error!("Failed to create the 2D view : {}", e);
+        std::process::exit(1)
+    });
+    let view3d = Simple3DView::new(&display, event_loop.create_proxy()).unwrap_or_else(|e| {
+        error!("Failed to create the 3D view : {}", e);
+        std::process::exit(1)
+    });
 
     let mut view_mode = ViewMode {
         current_view: 0,
@@ -82,16 +197,7 @@ fn main() {
                 winit::event::WindowEvent::CloseRequested => window_target.exit(),
                 winit::event::WindowEvent::DroppedFile(path) => {
                     info!("dropped file: {:?}", path);
-                    match path.extension() {
-                        Some(ext) => match ext.to_ascii_lowercase().to_str().unwrap() {
-                            "png" | "jpg" | "jpeg" => {
-                                view_mode.set_2d_view();
-                            }
-                            _ => view_mode.set_3d_view(),
-                        },
-                        None => (),
-                    }
-                    view_mode.get_view_mut().set_image(&display, &path);
+                    view_mode.set_image(&display, &path);
                 }
                 winit::event::WindowEvent::KeyboardInput { event, .. } => {
                     match event.key_without_modifiers().as_ref() {
@@ -132,7 +238,21 @@ fn main() {
                 }
                 _ => (),
             },
+            winit::event::Event::UserEvent(event) => {
+                match &event {
+                    UserEvent::Load(LoadEvent::Progress { fraction }) => window
+                        .set_title(&format!("image viewer - loading {:.0}%", fraction * 100.0)),
+                    UserEvent::Load(LoadEvent::Finished { result, .. })
+                        if !matches!(result, Err(io::Error::Cancelled)) =>
+                    {
+                        window.set_title("image viewer")
+                    }
+                    _ => (),
+                }
+                view_mode.handle_user_event(&display, event);
+            }
             winit::event::Event::AboutToWait => {
+                view_mode.get_view_mut().update(&display);
                 window.request_redraw();
             }
             _ => (),
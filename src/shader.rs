@@ -1,4 +1,5 @@
 use glium::glutin::surface::WindowSurface;
+use tracing::{debug, error};
 
 pub struct ShaderSrc {
     pub stem: &'static str,
@@ -8,8 +9,13 @@ pub struct ShaderSrc {
 }
 
 impl ShaderSrc {
-    pub fn compile(self, display: &glium::Display<WindowSurface>) -> glium::Program {
-        glium::Program::from_source(display, self.vertex, self.fragment, self.geometry).unwrap()
+    pub fn compile(
+        self,
+        display: &glium::Display<WindowSurface>,
+    ) -> Result<glium::Program, glium::ProgramCreationError> {
+        debug!("Compiling shader : {}", self.stem);
+        glium::Program::from_source(display, self.vertex, self.fragment, self.geometry)
+            .inspect_err(|e| error!("Failed to compile shader {} : {}", self.stem, e))
     }
 }
 
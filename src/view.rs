@@ -4,9 +4,20 @@ use winit::{
     event::{ElementState, MouseButton, MouseScrollDelta, TouchPhase},
 };
 
+/// event loopに送られるwinitのuser event
+#[derive(Debug)]
+pub enum UserEvent {
+    Load(crate::io::loader::LoadEvent),
+}
+
 pub trait View {
     fn draw(&self, display: &glium::Display<WindowSurface>);
-    fn set_image(&mut self, display: &glium::Display<WindowSurface>, data_path: &std::path::Path);
+    /// 読み込みに失敗した場合は表示中の画像を保持したままErrを返す
+    fn set_image(
+        &mut self,
+        display: &glium::Display<WindowSurface>,
+        data_path: &std::path::Path,
+    ) -> crate::io::Result<()>;
     fn handle_keyboard_input(
         &mut self,
         display: &glium::Display<WindowSurface>,
@@ -39,7 +50,12 @@ pub trait View {
         display: &glium::Display<WindowSurface>,
         window_size: winit::dpi::PhysicalSize<u32>,
     );
+    fn handle_user_event(&mut self, display: &glium::Display<WindowSurface>, event: UserEvent);
+    /// 描画前に毎回呼ばれる (動画の再生など)
+    fn update(&mut self, display: &glium::Display<WindowSurface>);
 }
 
 pub mod simple;
 pub mod simple3d;
+pub mod viewport;
+pub mod volume;
@@ -3,6 +3,7 @@ use glium::glutin::surface::WindowSurface;
 use glium::texture::{MipmapsOption, UncompressedFloatFormat};
 use glium::Surface;
 use glium::{implement_vertex, uniform};
+use tracing::info;
 use winit::dpi::PhysicalPosition;
 use winit::event::{ElementState, MouseButton, MouseScrollDelta, TouchPhase};
 use winit::keyboard::ModifiersState;
@@ -15,6 +16,7 @@ struct SimpleVertex {
     position: [f32; 2],
     tex_coords: [f32; 2],
 }
+implement_vertex!(SimpleVertex, position, tex_coords);
 
 pub struct Simple2DView {
     indices: glium::index::NoIndices,
@@ -22,6 +24,9 @@ pub struct Simple2DView {
     program: glium::Program,
     texture: glium::texture::Texture2d,
     matrix: [[f32; 4]; 4],
+    // 画素値のwindow (3D viewと同じく中心と幅)
+    window_width: f32,
+    window_level: f32,
     is_left_button_pressed: bool,
     is_right_button_pressed: bool,
     is_shift_button_pressed: bool,
@@ -29,8 +34,9 @@ pub struct Simple2DView {
 }
 
 impl Simple2DView {
-    pub fn new(display: &glium::Display<WindowSurface>) -> Self {
-        implement_vertex!(SimpleVertex, position, tex_coords);
+    pub fn new(
+        display: &glium::Display<WindowSurface>,
+    ) -> Result<Self, glium::ProgramCreationError> {
         let shape = vec![
             SimpleVertex {
                 position: [-0.5, -0.5],
@@ -61,9 +67,9 @@ impl Simple2DView {
         let vertex_buffer = glium::VertexBuffer::new(display, &shape).unwrap();
 
         let shader = shader!("simple");
-        let program = shader.compile(display);
+        let program = shader.compile(display)?;
 
-        Simple2DView {
+        Ok(Simple2DView {
             indices,
             vertex_buffer,
             program,
@@ -74,37 +80,62 @@ impl Simple2DView {
                 [0.0, 0.0, 1.0, 0.0],
                 [0.0, 0.0, 0.0, 1.0],
             ],
+            window_width: 1.0,
+            window_level: 0.5,
             is_left_button_pressed: false,
             is_right_button_pressed: false,
             is_shift_button_pressed: false,
             prev_mouse_pos: None,
-        }
+        })
     }
 }
 
 impl super::View for Simple2DView {
-    fn set_image(&mut self, display: &glium::Display<WindowSurface>, data_path: &std::path::Path) {
-        let image = std::fs::read(data_path).unwrap();
-        let image = image::load(std::io::Cursor::new(image), image::ImageFormat::Png)
-            .unwrap()
-            .to_rgba8();
-        println!(
-            "Image shape : {:?}, data len : {}",
-            image.dimensions(),
-            image.len()
+    fn set_image(
+        &mut self,
+        display: &glium::Display<WindowSurface>,
+        data_path: &std::path::Path,
+    ) -> crate::io::Result<()> {
+        let image = crate::io::load_image2d(data_path)?;
+        info!(
+            "Image shape : {}x{}, {} channels {:?}",
+            image.width, image.height, image.channels, image.datatype
         );
+        // 8bitは0 - 255, それ以外は画素値の範囲をwindowにする
+        let (min, max) = match image.datatype {
+            crate::io::DataType::U8 => (0.0, 255.0),
+            _ => image.value_range(),
+        };
+        let (window_width, window_level) = if max > min {
+            (max - min, (max + min) / 2.0)
+        } else {
+            (1.0, min)
+        };
 
-        let image_dimensions = image.dimensions();
+        // 元の画素値のままRGBAにする
+        let rgba: Vec<f32> = image
+            .samples
+            .chunks_exact(image.channels)
+            .flat_map(|x| match x {
+                [v] => [*v, *v, *v, 1.0],
+                [v, a] => [*v, *v, *v, *a],
+                [r, g, b] => [*r, *g, *b, 1.0],
+                [r, g, b, a, ..] => [*r, *g, *b, *a],
+                _ => [0.0; 4],
+            })
+            .collect();
         let image =
-            glium::texture::RawImage2d::from_raw_rgba_reversed(&image.into_raw(), image_dimensions);
-        println!("image client format : {:?}", image.format);
+            glium::texture::RawImage2d::from_raw_rgba_reversed(&rgba, (image.width, image.height));
         self.texture = glium::Texture2d::with_format(
             display,
             image,
             UncompressedFloatFormat::F32F32F32F32,
             MipmapsOption::NoMipmap,
         )
-        .unwrap();
+        .map_err(|e| crate::io::Error::TextureUpload(e.to_string()))?;
+        self.window_width = window_width;
+        self.window_level = window_level;
+        Ok(())
     }
 
     fn draw(&self, display: &glium::Display<WindowSurface>) {
@@ -125,11 +156,13 @@ impl super::View for Simple2DView {
             tex: &self.texture,
             perspective: perspective,
             model: self.matrix,
+            window_width: self.window_width,
+            window_level: self.window_level,
         };
         target
             .draw(
                 &self.vertex_buffer,
-                &self.indices,
+                self.indices,
                 &self.program,
                 &uniforms,
                 &glium::DrawParameters::default(),
@@ -199,8 +232,8 @@ impl super::View for Simple2DView {
                 MouseScrollDelta::LineDelta(_, y) => 1.0 + y / 10.0,
                 MouseScrollDelta::PixelDelta(_) => 1.0,
             };
-            self.matrix[0][0] *= scale as f32;
-            self.matrix[1][1] *= scale as f32;
+            self.matrix[0][0] *= scale;
+            self.matrix[1][1] *= scale;
         }
     }
 
@@ -210,4 +243,13 @@ impl super::View for Simple2DView {
         _window_size: winit::dpi::PhysicalSize<u32>,
     ) {
     }
+
+    fn handle_user_event(
+        &mut self,
+        _display: &glium::Display<WindowSurface>,
+        _event: super::UserEvent,
+    ) {
+    }
+
+    fn update(&mut self, _display: &glium::Display<WindowSurface>) {}
 }
@@ -3,11 +3,18 @@ use glium;
 use glium::glutin::surface::WindowSurface;
 use glium::Surface;
 use glium::{implement_vertex, uniform};
-use tracing::info;
+use tracing::{debug, error, info};
 use winit::dpi::PhysicalPosition;
 use winit::event::{ElementState, MouseButton, MouseScrollDelta, TouchPhase};
+use winit::event_loop::EventLoopProxy;
 use winit::keyboard::ModifiersState;
 
+use super::viewport::{
+    plane_axes, rect_to_ndc, slice_model_matrix, Layout, ObliquePlane, Pick, Viewport,
+};
+use super::volume::{VolumeLayers, VolumeRenderer};
+use super::UserEvent;
+use crate::io::loader::{LoadEvent, Loader};
 use crate::shader;
 use crate::shader::ShaderSrc;
 
@@ -15,65 +22,334 @@ const DEFAULT_MASK_WINDOW_WIDTH: f32 = 1.0;
 const DEFAULT_MASK_WINDOW_LEVEL: f32 = 0.5;
 const DEFAULT_IMAGE_WINDOW_WIDTH: f32 = 600.0;
 const DEFAULT_IMAGE_WINDOW_LEVEL: f32 = 200.0;
+// 標準の向きに並べ替えた後の各軸に垂直な断面の名前
+const PLANE_NAMES: [&str; 3] = ["sagittal", "coronal", "axial"];
+// colour lookup tableのtextureに入れるlabelの上限
+const MAX_LUT_LABELS: u32 = 16384;
+// 時系列volumeの再生速度 (frame / 秒)
+const DEFAULT_FRAME_RATE: f32 = 10.0;
+const MIN_FRAME_RATE: f32 = 0.5;
+const MAX_FRAME_RATE: f32 = 120.0;
 
 #[derive(Copy, Clone)]
 struct Simple3DVertex {
     position: [f32; 2],
     tex_coords: [f32; 2],
 }
+implement_vertex!(Simple3DVertex, position, tex_coords);
+
+/// chunkごとに読むvolumeの1つの領域に表示している断面の周りの値
+#[derive(Debug)]
+struct Tile {
+    request: crate::io::TileRequest,
+    texture: glium::texture::Texture3d,
+}
+
+/// 1つの領域の断面のtile. 読み込み中の要求は1つまで
+#[derive(Debug, Default)]
+struct TileSlot {
+    shown: Option<Tile>,
+    pending: Option<crate::io::TileRequest>,
+    // 読み込みに失敗した領域. 毎フレーム読み直さないように記録する
+    failed: Option<crate::io::TileRequest>,
+}
+
+/// texture座標 [0, 1]^3 -> `lo`から`shape`の大きさの領域のvoxel index
+fn texture_to_voxel(lo: [f32; 3], shape: [f32; 3]) -> cgmath::Matrix4<f32> {
+    // texture座標tとvoxel index vの関係は t = (v - lo + 0.5) / n
+    cgmath::Matrix4::from_translation(cgmath::vec3(lo[0] - 0.5, lo[1] - 0.5, lo[2] - 0.5))
+        * cgmath::Matrix4::from_nonuniform_scale(shape[0], shape[1], shape[2])
+}
+
+/// workerからの通知をevent loopに送る関数
+fn load_event_sender(
+    proxy: &EventLoopProxy<UserEvent>,
+) -> impl Fn(LoadEvent) + Clone + Send + Sync + 'static {
+    let proxy = proxy.clone();
+    move |event| {
+        // event loopが終了していれば結果は捨てる
+        let _ = proxy.send_event(UserEvent::Load(event));
+    }
+}
+
+/// `slot`番目の領域のtile, tileを使うか, imageのtexture座標 -> tileのtexture座標.
+/// tileがない場合は使わないtextureとしてvolumeのtextureを渡す
+fn tile_uniforms(
+    texture: &Texture,
+    slot: usize,
+    image_to_world: cgmath::Matrix4<f32>,
+) -> (&glium::texture::Texture3d, bool, [[f32; 4]; 4]) {
+    match texture.tile(slot) {
+        Some((tile, world_to_tile)) => (tile, true, (world_to_tile * image_to_world).into()),
+        None => (
+            &texture.texture,
+            false,
+            cgmath::Matrix4::<f32>::identity().into(),
+        ),
+    }
+}
+
+/// 読み込んだvolumeに置き換えられたvolume.
+/// 最後に読み込んだvolumeのimage/maskを入れ替えると元の場所に戻す
+#[derive(Debug)]
+struct Displaced {
+    image: crate::io::Image3D,
+    path: Option<std::path::PathBuf>,
+    window_width: f32,
+    window_level: f32,
+}
 
 #[derive(Debug)]
 struct Texture {
     pub image: Option<crate::io::Image3D>,
+    // 読み込んだファイルのpath (export時の出力先に使う)
+    pub path: Option<std::path::PathBuf>,
     pub texture: glium::texture::Texture3d,
-    pub model_matrix: cgmath::Matrix4<f32>,
+    // textureに転送している時系列のframe
+    pub frame: u32,
+    // label -> 色 (imageにcolour lookup tableがない場合は1 texelのダミー)
+    pub lut_texture: glium::texture::Texture1d,
+    // chunkごとに読むvolumeの各領域 (viewportのindex) の断面. textureはoverview
+    pub tiles: Vec<TileSlot>,
     pub window_width: f32,
     pub window_level: f32,
 }
 
+/// label値をindexとするRGBAのtextureを作る
+fn upload_lut(
+    display: &glium::Display<WindowSurface>,
+    lut: Option<&crate::io::ColorLut>,
+) -> crate::io::Result<glium::texture::Texture1d> {
+    let len = lut
+        .and_then(|lut| lut.labels.keys().next_back())
+        .map_or(1, |max| (max + 1).min(MAX_LUT_LABELS));
+    let mut colors = vec![0u8; len as usize * 4];
+    if let Some(lut) = lut {
+        for label in 0..len {
+            if let Some(color) = lut.color(label) {
+                colors[label as usize * 4..label as usize * 4 + 4].copy_from_slice(&color);
+            }
+        }
+    }
+    glium::texture::Texture1d::new(display, glium::texture::RawImage1d::from_raw_rgba(colors))
+        .map_err(|e| crate::io::Error::TextureUpload(e.to_string()))
+}
+
+/// F-orderの値を浮動小数点のtextureとして転送する
+fn upload_values(
+    display: &glium::Display<WindowSurface>,
+    data: &[f32],
+    shape: [usize; 3],
+) -> crate::io::Result<glium::texture::Texture3d> {
+    let image3d = glium::texture::RawImage3d {
+        data: std::borrow::Cow::Borrowed(data),
+        width: shape[0] as u32,
+        height: shape[1] as u32,
+        depth: shape[2] as u32,
+        format: glium::texture::ClientFormat::F32,
+    };
+    glium::texture::Texture3d::with_format(
+        display,
+        image3d,
+        glium::texture::UncompressedFloatFormat::F32,
+        glium::texture::MipmapsOption::NoMipmap,
+    )
+    .map_err(|e| crate::io::Error::TextureUpload(e.to_string()))
+}
+
+/// imageの`frame`番目のvolumeをtextureとして転送する.
+/// chunkごとに読むvolumeはoverviewを転送する (overviewがない場合は1 voxelの0)
+fn upload_frame(
+    display: &glium::Display<WindowSurface>,
+    image: &crate::io::Image3D,
+    frame: u32,
+) -> crate::io::Result<glium::texture::Texture3d> {
+    if let Some(pyramid) = &image.chunked {
+        return match pyramid.overview() {
+            Some((array, data)) => upload_values(display, data, array.shape()),
+            None => upload_values(display, &[0.0], [1, 1, 1]),
+        };
+    }
+    let image3d = glium::texture::RawImage3d {
+        data: std::borrow::Cow::Borrowed(image.frame(frame)),
+        width: image.shape.0,
+        height: image.shape.1,
+        depth: image.shape.2,
+        format: glium::texture::ClientFormat::F32,
+    };
+    let mipmaps = image
+        .mipmaps
+        .unwrap_or(glium::texture::MipmapsOption::NoMipmap);
+    match image.format {
+        Some(format) => glium::texture::Texture3d::with_format(display, image3d, format, mipmaps),
+        None => glium::texture::Texture3d::with_mipmaps(display, image3d, mipmaps),
+    }
+    .map_err(|e| crate::io::Error::TextureUpload(e.to_string()))
+}
+
 impl Texture {
     pub fn empty(display: &glium::Display<WindowSurface>) -> Self {
         Texture {
             image: None,
+            path: None,
             texture: glium::texture::Texture3d::empty(display, 0, 0, 0).unwrap(),
-            model_matrix: cgmath::Matrix4::identity(),
+            frame: 0,
+            lut_texture: upload_lut(display, None).unwrap(),
+            tiles: Vec::new(),
             window_width: 1.0,
             window_level: 0.0,
         }
     }
 
-    pub fn set_model_matrix(&mut self, current_axis: &u32) {
-        let (spacing_x, spacing_y) = match &self.image {
-            Some(image) => match current_axis {
-                0 => (image.spacing.2, image.spacing.1),
-                1 => (image.spacing.2, image.spacing.0),
-                2 => (image.spacing.1, image.spacing.0),
-                _ => panic!("Invalid axis : {}", current_axis),
-            },
-            _ => (1.0, 1.0),
+    /// `axis`に垂直な断面を表示するときのmodel行列
+    pub fn model_matrix(&self, axis: u32) -> cgmath::Matrix4<f32> {
+        slice_model_matrix(self.image.as_ref(), axis)
+    }
+
+    /// texture座標 [0, 1]^3 -> world座標(mm)への変換行列
+    pub fn texture_to_world(&self) -> cgmath::Matrix4<f32> {
+        match &self.image {
+            Some(image) => {
+                let shape = [image.shape.0, image.shape.1, image.shape.2];
+                image.affine_matrix() * texture_to_voxel([0.0; 3], shape.map(|x| x as f32))
+            }
+            None => cgmath::Matrix4::identity(),
+        }
+    }
+
+    /// `texture`のtexture座標 -> world座標への変換行列. chunkごとに読むvolumeはoverviewの座標
+    pub fn sampled_to_world(&self) -> cgmath::Matrix4<f32> {
+        let overview = self
+            .image
+            .as_ref()
+            .and_then(|x| x.chunked.as_ref())
+            .and_then(|x| x.overview());
+        match overview {
+            Some((array, _)) => {
+                array.affine_matrix() * texture_to_voxel([0.0; 3], array.shape().map(|x| x as f32))
+            }
+            None => self.texture_to_world(),
+        }
+    }
+
+    /// 画像のvoxel数. 画像がない場合はtextureの大きさ
+    pub fn shape(&self) -> [u32; 3] {
+        match &self.image {
+            Some(image) => [image.shape.0, image.shape.1, image.shape.2],
+            None => [
+                self.texture.get_width(),
+                self.texture.get_height().unwrap_or(1),
+                self.texture.get_depth().unwrap_or(1),
+            ],
+        }
+    }
+
+    /// volume全体がtextureにあるか (chunkごとに読むvolumeはoverviewがあるか).
+    /// ない場合は斜めの断面とvolume renderingを使えない
+    pub fn has_whole_volume(&self) -> bool {
+        self.image
+            .as_ref()
+            .and_then(|x| x.chunked.as_ref())
+            .is_none_or(|x| x.overview().is_some())
+    }
+
+    /// `slot`番目の領域に表示しているtileと, world座標 -> tileのtexture座標の変換行列
+    pub fn tile(&self, slot: usize) -> Option<(&glium::texture::Texture3d, cgmath::Matrix4<f32>)> {
+        let pyramid = self.image.as_ref()?.chunked.as_ref()?;
+        let tile = self.tiles.get(slot)?.shown.as_ref()?;
+        let array = pyramid.level(tile.request.level);
+        let to_world = array.affine_matrix()
+            * texture_to_voxel(
+                tile.request.lo.map(|x| x as f32),
+                tile.request.shape().map(|x| x as f32),
+            );
+        Some((&tile.texture, to_world.inverse_transform()?))
+    }
+
+    /// chunkごとに読むvolumeの場合, `slot`番目の領域の表示範囲 (world座標の左下, 右下, 左上の隅) を
+    /// 表示しているtileが含まなければworker threadで読む. 結果は`LoadEvent::Tile`で届く
+    pub fn request_tile(
+        &mut self,
+        loader: &Loader,
+        proxy: &EventLoopProxy<UserEvent>,
+        slot: usize,
+        corners: [[f32; 3]; 3],
+        pixels: [u32; 2],
+    ) {
+        let Some(pyramid) = self.image.as_ref().and_then(|x| x.chunked.clone()) else {
+            return;
         };
+        let Some(visible) = pyramid.visible_tile(corners, pixels) else {
+            return;
+        };
+        if self.tiles.len() <= slot {
+            self.tiles.resize_with(slot + 1, TileSlot::default);
+        }
+        let tile_slot = &mut self.tiles[slot];
+        let is_covered =
+            |x: Option<&crate::io::TileRequest>| x.is_some_and(|x| x.contains(&visible));
+        if tile_slot.pending.is_some()
+            || is_covered(tile_slot.shown.as_ref().map(|x| &x.request))
+            || is_covered(tile_slot.failed.as_ref())
+        {
+            return;
+        }
+        let request = pyramid.expand_tile(&visible);
+        debug!("Request tile {} : {:?}", slot, request);
+        tile_slot.pending = Some(request);
+        loader.spawn_tile(pyramid, slot, request, load_event_sender(proxy));
+    }
+
+    /// このvolumeの`slot`番目の領域で読み込み中の要求か
+    pub fn is_pending(
+        &self,
+        pyramid: &std::sync::Arc<crate::io::ZarrPyramid>,
+        slot: usize,
+        request: &crate::io::TileRequest,
+    ) -> bool {
+        self.image
+            .as_ref()
+            .and_then(|x| x.chunked.as_ref())
+            .is_some_and(|x| std::sync::Arc::ptr_eq(x, pyramid))
+            && self
+                .tiles
+                .get(slot)
+                .is_some_and(|x| x.pending.as_ref() == Some(request))
+    }
 
-        self.model_matrix = cgmath::Matrix4::from([
-            [spacing_x, 0.0, 0.0, 0.0],
-            [0.0, spacing_y, 0.0, 0.0],
-            [0.0, 0.0, 1.0, 0.0],
-            [0.0, 0.0, 0.0, 1.0],
-        ]);
+    /// 読み込んだtileを`slot`番目の領域に表示する
+    pub fn finish_tile(
+        &mut self,
+        display: &glium::Display<WindowSurface>,
+        slot: usize,
+        request: crate::io::TileRequest,
+        result: crate::io::Result<Vec<f32>>,
+    ) -> crate::io::Result<()> {
+        let Some(tile_slot) = self.tiles.get_mut(slot) else {
+            return Ok(());
+        };
+        tile_slot.pending = None;
+        let texture = result.and_then(|data| upload_values(display, &data, request.shape()));
+        match texture {
+            Ok(texture) => {
+                tile_slot.shown = Some(Tile { request, texture });
+                Ok(())
+            }
+            Err(e) => {
+                tile_slot.failed = Some(request);
+                Err(e)
+            }
+        }
     }
 
     pub fn set_image(
         &mut self,
         display: &glium::Display<WindowSurface>,
         image: crate::io::Image3D,
-        current_axis: u32,
-    ) {
-        let image3d = glium::texture::RawImage3d {
-            data: std::borrow::Cow::Borrowed(&image.data),
-            width: image.shape.0,
-            height: image.shape.1,
-            depth: image.shape.2,
-            format: glium::texture::ClientFormat::F32,
-        };
+    ) -> crate::io::Result<()> {
+        let frame = self.frame.min(image.frames.max(1) - 1);
+        let texture = upload_frame(display, &image, frame)?;
+        let lut_texture = upload_lut(display, image.lut.as_ref())?;
         if self.image.is_none() {
             if image.is_mask {
                 self.window_width = DEFAULT_MASK_WINDOW_WIDTH;
@@ -83,22 +359,101 @@ impl Texture {
                 self.window_level = DEFAULT_IMAGE_WINDOW_LEVEL;
             }
         }
-        self.texture = match image.format {
-            Some(format) => glium::texture::Texture3d::with_format(
-                display,
-                image3d,
-                format,
-                image.mipmaps.unwrap(),
-            )
-            .unwrap(),
-            None => {
-                glium::texture::Texture3d::with_mipmaps(display, image3d, image.mipmaps.unwrap())
-                    .unwrap()
-            }
-        };
+        self.texture = texture;
+        self.lut_texture = lut_texture;
+        self.tiles.clear();
+        self.frame = frame;
         self.image = Some(image);
-        self.set_model_matrix(&current_axis);
+        Ok(())
+    }
+
+    /// 表示中のvolumeを`image`に置き換え, 置き換えたvolumeを返す. window width/levelは引き継ぐ.
+    /// 失敗した場合は表示中のvolumeのまま
+    pub fn replace_image(
+        &mut self,
+        display: &glium::Display<WindowSurface>,
+        image: crate::io::Image3D,
+    ) -> crate::io::Result<Option<Displaced>> {
+        let previous = self.image.take().map(|image| Displaced {
+            image,
+            path: self.path.take(),
+            window_width: self.window_width,
+            window_level: self.window_level,
+        });
+        if let Err(e) = self.set_image(display, image) {
+            if let Some(previous) = previous {
+                self.image = Some(previous.image);
+                self.path = previous.path;
+            }
+            return Err(e);
+        }
+        if let Some(previous) = &previous {
+            self.window_width = previous.window_width;
+            self.window_level = previous.window_level;
+        }
+        Ok(previous)
     }
+
+    /// 置き換えられたvolumeを表示中のvolumeに戻す
+    pub fn restore(
+        &mut self,
+        display: &glium::Display<WindowSurface>,
+        displaced: Displaced,
+    ) -> crate::io::Result<()> {
+        self.set_image(display, displaced.image)?;
+        self.path = displaced.path;
+        self.window_width = displaced.window_width;
+        self.window_level = displaced.window_level;
+        Ok(())
+    }
+
+    /// 時系列の`frame`番目のvolumeを表示する. frame数を超える場合は最後のframe
+    pub fn set_frame(
+        &mut self,
+        display: &glium::Display<WindowSurface>,
+        frame: u32,
+    ) -> crate::io::Result<()> {
+        if let Some(image) = &self.image {
+            let frame = frame.min(image.frames.max(1) - 1);
+            if frame != self.frame {
+                self.texture = upload_frame(display, image, frame)?;
+                self.frame = frame;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.image.as_ref().map_or(0, |image| image.frames.max(1))
+    }
+
+    /// 読み込み済みの画像を指定した向きに並べ替えてtextureを作り直す
+    pub fn reorient(
+        &mut self,
+        display: &glium::Display<WindowSurface>,
+        orientation: crate::io::Orientation,
+    ) -> crate::io::Result<()> {
+        if let Some(image) = self.image.take() {
+            let (window_width, window_level) = (self.window_width, self.window_level);
+            self.set_image(display, image.reorient(orientation))?;
+            self.window_width = window_width;
+            self.window_level = window_level;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Layer {
+    Image,
+    Mask,
+}
+
+/// 複数のseriesを含むDICOM directoryで表示するseriesを選ぶ
+struct SeriesPicker {
+    dir: std::path::PathBuf,
+    series: Vec<crate::io::dicom::DicomSeries>,
+    index: usize,
 }
 
 pub struct Simple3DView {
@@ -107,19 +462,43 @@ pub struct Simple3DView {
     program: glium::Program,
     image: Texture,
     mask: Texture,
-    view_matrix: cgmath::Matrix4<f32>,        // カメラの位置, zoom
-    perspective_matrix: cgmath::Matrix4<f32>, // aspect比
-    axis: u32,
+    // 画面の分割と各領域の断面. 全ての領域でcurrent_posを共有する
+    layout: Layout,
+    viewports: Vec<Viewport>,
+    // 操作の対象の領域 (cursorの下にある領域). Noneの場合はvolume renderingの領域
+    active_viewport: Option<usize>,
+    volume: VolumeRenderer,
+    volume_rect: Option<glium::Rect>,
+    show_crosshair: bool,
+    // 最後にcursorの下にあった点
+    last_pick: Option<Pick>,
+    // 斜めの断面. Noneの場合はvolumeの軸に直交する断面
+    oblique: Option<ObliquePlane>,
     current_pos: [u32; 3],
+    load_options: crate::io::LoadOptions,
+    loader: Loader,
+    proxy: EventLoopProxy<UserEvent>,
+    last_loaded: Option<Layer>,
+    // 最後に読み込んだvolumeに置き換えられたvolume
+    displaced: Option<Displaced>,
+    series_picker: Option<SeriesPicker>,
+    // 時系列volumeの表示中のframeと再生状態
+    frame: u32,
+    is_playing: bool,
+    frame_rate: f32,
+    last_frame_time: std::time::Instant,
     is_left_button_pressed: bool,
     is_right_button_pressed: bool,
     is_shift_button_pressed: bool,
+    is_control_button_pressed: bool,
     prev_mouse_pos: Option<PhysicalPosition<f64>>,
 }
 
 impl Simple3DView {
-    pub fn new(display: &glium::Display<WindowSurface>) -> Self {
-        implement_vertex!(Simple3DVertex, position, tex_coords);
+    pub fn new(
+        display: &glium::Display<WindowSurface>,
+        proxy: EventLoopProxy<UserEvent>,
+    ) -> Result<Self, glium::ProgramCreationError> {
         let shape = vec![
             Simple3DVertex {
                 position: [-1.0, -1.0],
@@ -150,63 +529,518 @@ impl Simple3DView {
         let vertex_buffer = glium::VertexBuffer::new(display, &shape).unwrap();
 
         let shader = shader!("simple3d");
-        let program = shader.compile(display);
+        let program = shader.compile(display)?;
 
         let (width, height) = display.get_framebuffer_dimensions();
-        let aspect_ratio = height as f32 / width as f32;
-        let f = if 1.0.lt(&aspect_ratio) {
-            1.0
-        } else {
-            1.0 / aspect_ratio
-        };
 
-        Simple3DView {
+        Ok(Simple3DView {
             indices,
             vertex_buffer,
             program,
             image: Texture::empty(display),
             mask: Texture::empty(display),
-            view_matrix: cgmath::Matrix4::identity(),
-            perspective_matrix: cgmath::Matrix4::from([
-                [f * aspect_ratio, 0.0, 0.0, 0.0],
-                [0.0, f, 0.0, 0.0],
-                [0.0, 0.0, 1.0, 0.0],
-                [0.0, 0.0, 0.0, 1.0],
-            ]),
-            axis: 2,
+            layout: Layout::Single,
+            viewports: vec![Viewport::new(2, Layout::Single.split(width, height)[0])],
+            active_viewport: Some(0),
+            volume: VolumeRenderer::new(display)?,
+            volume_rect: None,
+            show_crosshair: true,
+            last_pick: None,
+            oblique: None,
             current_pos: [0, 0, 0],
+            load_options: crate::io::LoadOptions {
+                orientation: Some(crate::io::Orientation::Ras),
+                ..Default::default()
+            },
+            loader: Loader::default(),
+            proxy,
+            last_loaded: None,
+            displaced: None,
+            series_picker: None,
+            frame: 0,
+            is_playing: false,
+            frame_rate: DEFAULT_FRAME_RATE,
+            last_frame_time: std::time::Instant::now(),
             is_left_button_pressed: false,
             is_right_button_pressed: false,
             is_shift_button_pressed: false,
+            is_control_button_pressed: false,
             prev_mouse_pos: None,
-        }
+        })
     }
 }
 
-impl super::View for Simple3DView {
-    fn set_image(&mut self, display: &glium::Display<WindowSurface>, data_path: &std::path::Path) {
-        let image3d = crate::io::load_image3d(data_path);
-        info!(
-            "Image shape : {:?}, data len : {}, spacing : {:?}",
-            image3d.shape,
-            image3d.data.len(),
-            image3d.spacing
+impl Simple3DView {
+    /// 操作の対象の領域で表示している断面の軸
+    fn axis(&self) -> u32 {
+        self.active_viewport().map_or(2, |x| x.axis)
+    }
+
+    fn active_viewport(&self) -> Option<&Viewport> {
+        self.active_viewport.and_then(|i| self.viewports.get(i))
+    }
+
+    fn active_viewport_mut(&mut self) -> Option<&mut Viewport> {
+        self.active_viewport.and_then(|i| self.viewports.get_mut(i))
+    }
+
+    /// 画面の分割を切り替える. 1つの断面の場合は操作していた領域の断面を表示する
+    fn set_layout(&mut self, display: &glium::Display<WindowSurface>, layout: Layout) {
+        let (width, height) = display.get_framebuffer_dimensions();
+        let axes = match layout {
+            Layout::Single => vec![self.axis()],
+            Layout::TriPlanar | Layout::Quad => vec![2, 1, 0],
+            Layout::Volume => vec![],
+        };
+        self.viewports = axes
+            .into_iter()
+            .zip(layout.split(width, height))
+            .map(|(axis, rect)| Viewport::new(axis, rect))
+            .collect();
+        self.volume_rect = layout.volume_rect(width, height);
+        self.layout = layout;
+        self.active_viewport = (!self.viewports.is_empty()).then_some(0);
+        self.image.tiles.clear();
+        self.mask.tiles.clear();
+        info!("Layout : {:?}", layout);
+    }
+
+    /// windowの座標の下にあるimage, maskの点
+    fn pick(
+        &self,
+        display: &glium::Display<WindowSurface>,
+        position: &PhysicalPosition<f64>,
+    ) -> Option<Pick> {
+        self.active_viewport()?.pick(
+            position,
+            display.get_framebuffer_dimensions().1,
+            self.image.image.as_ref()?,
+            self.mask.image.as_ref(),
+            self.center_voxel(),
+            self.oblique.as_ref(),
+        )
+    }
+
+    /// 全ての断面が通る点 (imageの連続なvoxel座標). 斜めの断面の場合は回転の中心
+    fn center_voxel(&self) -> [f32; 3] {
+        match (&self.oblique, &self.image.image) {
+            (Some(plane), Some(image)) => image.world_to_voxel(plane.center),
+            _ => self.current_pos.map(|x| x as f32),
+        }
+    }
+
+    /// 斜めの断面の中心をworld座標の`center`に移し, current_posを最も近いvoxelにする
+    fn set_oblique_center(&mut self, center: [f32; 3]) {
+        let (Some(plane), Some(image)) = (&mut self.oblique, &self.image.image) else {
+            return;
+        };
+        plane.center = center;
+        let voxel = image.world_to_voxel(center);
+        let shape = [image.shape.0, image.shape.1, image.shape.2];
+        for i in 0..3 {
+            self.current_pos[i] =
+                (voxel[i].round().max(0.0) as u32).min(shape[i].saturating_sub(1));
+        }
+    }
+
+    /// 操作している領域の断面をcursorの移動量 (pixel) だけ傾ける.
+    /// 横の移動は断面の縦の方向, 縦の移動は横の方向を軸に回転する
+    fn rotate_plane(&mut self, dx: f64, dy: f64) {
+        let Some(image) = &self.image.image else {
+            return;
+        };
+        // 全体がtextureにないvolumeは回転した断面をsampleできない
+        if !self.image.has_whole_volume() {
+            return;
+        }
+        let center = image.voxel_to_world(self.current_pos.map(|x| x as f32));
+        let Some(viewport) = self.active_viewport.and_then(|i| self.viewports.get(i)) else {
+            return;
+        };
+        let plane = self
+            .oblique
+            .get_or_insert_with(|| ObliquePlane::new(center));
+        let [u, v] = plane_axes(viewport.axis).map(|axis| plane.direction(image, axis));
+        let height = viewport.rect.height.max(1) as f64;
+        plane.rotate(v, (dx / height * std::f64::consts::PI) as f32);
+        plane.rotate(u, (dy / height * std::f64::consts::PI) as f32);
+    }
+
+    /// 斜めの断面をやめてvolumeの軸に直交する断面に戻す
+    fn snap_to_orthogonal(&mut self) {
+        if self.oblique.take().is_some() {
+            info!("Snap to orthogonal planes");
+        }
+    }
+
+    /// cursorの下のvoxelを通るように, 表示中の断面以外の2つの座標を変える
+    fn move_to_cursor(
+        &mut self,
+        display: &glium::Display<WindowSurface>,
+        position: &PhysicalPosition<f64>,
+    ) {
+        let Some(pick) = self.pick(display, position) else {
+            return;
+        };
+        // 画像の外の場合は移動しない
+        if let Some(voxel) = pick.image_voxel {
+            if self.oblique.is_some() {
+                // 表示中の断面上の点なので, この断面は動かない
+                self.set_oblique_center(pick.world);
+            } else {
+                for axis in plane_axes(self.axis()) {
+                    self.current_pos[axis] = voxel[axis];
+                }
+            }
+            debug!("Current position : {:?}", self.current_pos);
+        }
+    }
+
+    /// cursorの下のimageの値とmaskのlabelを表示する (voxelが変わった場合のみ)
+    fn report_pick(
+        &mut self,
+        display: &glium::Display<WindowSurface>,
+        position: &PhysicalPosition<f64>,
+    ) {
+        let pick = self.pick(display, position);
+        let voxels = pick.map(|x| (x.image_voxel, x.mask_voxel));
+        if voxels == self.last_pick.map(|x| (x.image_voxel, x.mask_voxel)) {
+            return;
+        }
+        self.last_pick = pick;
+        let Some(pick) = pick else {
+            return;
+        };
+        let value = |texture: &Texture, voxel: Option<[u32; 3]>| {
+            texture
+                .image
+                .as_ref()
+                .zip(voxel)
+                .and_then(|(image, voxel)| image.value(voxel, texture.frame))
+        };
+        let mut text = format!(
+            "({:.1}, {:.1}, {:.1}) mm",
+            pick.world[0], pick.world[1], pick.world[2]
         );
+        if let Some(voxel) = pick.image_voxel {
+            text += &format!(", voxel {:?}", voxel);
+            if let Some(value) = value(&self.image, pick.image_voxel) {
+                text += &format!(", value {}", value);
+            }
+        }
+        if let Some(label) = value(&self.mask, pick.mask_voxel) {
+            let label = label.round() as u32;
+            text += &format!(", label {}", label);
+            let lut = self.mask.image.as_ref().and_then(|x| x.lut.as_ref());
+            if let Some((name, _)) = lut.and_then(|lut| lut.labels.get(&label)) {
+                text += &format!(" ({})", name);
+            }
+        }
+        info!("{}", text);
+    }
+
+    /// 標準の向きを RAS と LPS で切り替える.
+    /// 読み込み済みのvolumeは新しい向きに並べ替える
+    fn toggle_orientation(&mut self, display: &glium::Display<WindowSurface>) {
+        use crate::io::Orientation;
+        let orientation = match self.load_options.orientation {
+            Some(Orientation::Ras) => Orientation::Lps,
+            _ => Orientation::Ras,
+        };
+        self.load_options.orientation = Some(orientation);
+        info!("Orientation : {:?}", orientation);
+        self.oblique = None;
+        if let Err(e) = self
+            .image
+            .reorient(display, orientation)
+            .and_then(|_| self.mask.reorient(display, orientation))
+        {
+            error!("Failed to reorient : {}", e);
+        }
+        if let Some(image) = &self.image.image {
+            self.current_pos = [image.shape.0 / 2, image.shape.1 / 2, image.shape.2 / 2];
+        }
+    }
 
+    /// is_maskに従ってimageかmaskのどちらかに読み込んだvolumeを設定する.
+    /// 置き換えたvolumeは`displaced`に保持する
+    fn set_image3d(
+        &mut self,
+        display: &glium::Display<WindowSurface>,
+        image3d: crate::io::Image3D,
+    ) -> crate::io::Result<()> {
         if image3d.is_mask {
-            self.mask.set_image(display, image3d, self.axis);
+            self.displaced = self.mask.replace_image(display, image3d)?;
+            self.last_loaded = Some(Layer::Mask);
         } else {
-            if self.image.image.is_none() && self.mask.image.is_none() {
-                self.axis = 2;
-                self.current_pos = [
+            let mut current_pos = self.current_pos;
+            let is_first = self.image.image.is_none() && self.mask.image.is_none();
+            if is_first {
+                current_pos = [
                     image3d.shape.0 / 2,
                     image3d.shape.1 / 2,
                     image3d.shape.2 / 2,
                 ];
+            } else if let Some(prev) = &self.image.image {
+                // 前の画像と同じworld座標の位置を表示する
+                let world = prev.voxel_to_world(self.current_pos.map(|x| x as f32));
+                let voxel = image3d.world_to_voxel(world);
+                let shape = [image3d.shape.0, image3d.shape.1, image3d.shape.2];
+                for i in 0..3 {
+                    current_pos[i] =
+                        (voxel[i].round().max(0.0) as u32).min(shape[i].saturating_sub(1));
+                }
+                info!("Keep world position : {:?} -> {:?}", world, current_pos);
+            }
+            // 読み込みに失敗した場合は表示位置を変えない
+            self.displaced = self.image.replace_image(display, image3d)?;
+            if is_first && self.layout == Layout::Single {
+                self.viewports[0].axis = 2;
+            }
+            self.current_pos = current_pos;
+            self.last_loaded = Some(Layer::Image);
+        }
+        // image, maskで同じframeを表示する
+        if self.frame >= self.frames() {
+            self.frame = 0;
+        }
+        self.is_playing &= self.frames() > 1;
+        self.image.set_frame(display, self.frame)?;
+        self.mask.set_frame(display, self.frame)
+    }
+
+    /// 最後に読み込んだvolumeのimage/maskを入れ替える.
+    /// 読み込みで置き換えたvolumeは元の場所に戻し, 入れ替え先のvolumeを代わりに保持する
+    fn retag_last_loaded(&mut self, display: &glium::Display<WindowSurface>) {
+        let from = match self.last_loaded {
+            Some(Layer::Image) => &mut self.image,
+            Some(Layer::Mask) => &mut self.mask,
+            None => return,
+        };
+        let Some(mut image3d) = from.image.take() else {
+            return;
+        };
+        let path = from.path.take();
+        *from = Texture::empty(display);
+        if let Some(mut displaced) = self.displaced.take() {
+            if let Some(orientation) = self.load_options.orientation {
+                displaced.image = displaced.image.reorient(orientation);
+            }
+            if let Err(e) = from.restore(display, displaced) {
+                error!("Failed to restore the replaced volume : {}", e);
+            }
+        }
+        image3d.is_mask = !image3d.is_mask;
+        info!(
+            "Retag last loaded volume as {}",
+            if image3d.is_mask { "mask" } else { "image" }
+        );
+        match self.set_image3d(display, image3d) {
+            Ok(()) => self.last_loaded_texture_mut().path = path,
+            Err(e) => error!("Failed to retag : {}", e),
+        }
+    }
+
+    /// worker threadで読み込みを始める. 結果は`UserEvent::Load`で届く
+    fn load(&mut self, data_path: &std::path::Path) {
+        self.loader.spawn(
+            data_path,
+            &self.load_options,
+            load_event_sender(&self.proxy),
+        );
+    }
+
+    fn handle_load_event(&mut self, display: &glium::Display<WindowSurface>, event: LoadEvent) {
+        match event {
+            LoadEvent::Progress { .. } => (),
+            LoadEvent::Scanned { id, dir, series } => {
+                if !self.loader.is_current(id) || series.len() <= 1 {
+                    return;
+                }
+                for (i, x) in series.iter().enumerate() {
+                    info!(
+                        "Series {} : {} [{}] {} ({} slices)",
+                        i,
+                        x.description,
+                        x.modality,
+                        x.uid,
+                        x.files.len()
+                    );
+                }
+                info!("Press N to show the next series");
+                self.series_picker = Some(SeriesPicker {
+                    dir,
+                    series,
+                    index: 0,
+                });
+            }
+            LoadEvent::Tile {
+                pyramid,
+                slot,
+                request,
+                result,
+            } => {
+                // 表示しなくなったvolumeや古い要求の結果は捨てる
+                let texture = [&mut self.image, &mut self.mask]
+                    .into_iter()
+                    .find(|x| x.is_pending(&pyramid, slot, &request));
+                if let Some(texture) = texture {
+                    if let Err(e) = texture.finish_tile(display, slot, request, result) {
+                        error!("Failed to load tile {:?} : {}", request, e);
+                    }
+                }
+            }
+            LoadEvent::Finished { id, path, result } => {
+                if !self.loader.finish(id) {
+                    return;
+                }
+                let image3d = match result {
+                    Ok(image3d) => image3d,
+                    Err(e) => {
+                        error!("Failed to load {:?} : {}", path, e);
+                        return;
+                    }
+                };
+                info!(
+                    "Image shape : {:?}, data len : {}, spacing : {:?}",
+                    image3d.shape,
+                    image3d.data.len(),
+                    image3d.spacing
+                );
+                match self.set_image3d(display, image3d) {
+                    Ok(()) => {
+                        self.last_loaded_texture_mut().path = Some(path);
+                        info!("Image loaded");
+                    }
+                    Err(e) => error!("Failed to load {:?} : {}", path, e),
+                }
+            }
+        }
+    }
+
+    fn last_loaded_texture_mut(&mut self) -> &mut Texture {
+        match self.last_loaded {
+            Some(Layer::Mask) => &mut self.mask,
+            _ => &mut self.image,
+        }
+    }
+
+    /// maskまたはimageをNIfTIとして読み込み元の隣に保存する
+    fn export(&self, layer: Layer) {
+        let texture = match layer {
+            Layer::Image => &self.image,
+            Layer::Mask => &self.mask,
+        };
+        if let (Some(image), Some(path)) = (&texture.image, &texture.path) {
+            let output =
+                path.with_file_name(format!("{}_export.nii.gz", crate::io::file_stem(path)));
+            match crate::io::save_image3d(image, &output) {
+                Ok(()) => info!("Exported to {:?}", output),
+                Err(e) => error!("Failed to export {:?} : {}", output, e),
             }
-            self.image.set_image(display, image3d, self.axis);
         }
-        info!("Image loaded");
+    }
+
+    /// image, maskのうち多い方のframe数
+    fn frames(&self) -> u32 {
+        self.image.frames().max(self.mask.frames())
+    }
+
+    /// 時系列の`step`だけ先のframeを表示する (最後のframeの次は最初に戻る)
+    fn step_frame(&mut self, display: &glium::Display<WindowSurface>, step: i32) {
+        let frames = self.frames();
+        if frames <= 1 {
+            return;
+        }
+        self.frame = (self.frame as i64 + step as i64).rem_euclid(frames as i64) as u32;
+        debug!("Frame : {} / {}", self.frame, frames);
+        if let Err(e) = self
+            .image
+            .set_frame(display, self.frame)
+            .and_then(|_| self.mask.set_frame(display, self.frame))
+        {
+            error!("Failed to show frame {} : {}", self.frame, e);
+            self.is_playing = false;
+        }
+    }
+
+    fn toggle_playback(&mut self) {
+        if self.frames() <= 1 {
+            info!("Not a time series");
+            return;
+        }
+        self.is_playing = !self.is_playing;
+        self.last_frame_time = std::time::Instant::now();
+        info!(
+            "{} ({} fps)",
+            if self.is_playing { "Play" } else { "Pause" },
+            self.frame_rate
+        );
+    }
+
+    fn change_frame_rate(&mut self, scale: f32) {
+        self.frame_rate = (self.frame_rate * scale).clamp(MIN_FRAME_RATE, MAX_FRAME_RATE);
+        info!("Frame rate : {} fps", self.frame_rate);
+    }
+
+    /// chunkごとに読むimage, maskについて, 各領域の表示範囲のtileをworker threadで読む
+    fn request_visible_tiles(&mut self) {
+        let Some(image) = &self.image.image else {
+            return;
+        };
+        // 斜めの断面はoverviewで表示する
+        if self.oblique.is_some() {
+            return;
+        }
+        let center = self.center_voxel();
+        let visible: Vec<_> = self
+            .viewports
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, viewport)| {
+                let corners = viewport.visible_corners(image, center)?;
+                let pixels = [viewport.rect.width, viewport.rect.height];
+                Some((slot, corners.map(|x| image.voxel_to_world(x)), pixels))
+            })
+            .collect();
+        for (slot, corners, pixels) in visible {
+            self.image
+                .request_tile(&self.loader, &self.proxy, slot, corners, pixels);
+            self.mask
+                .request_tile(&self.loader, &self.proxy, slot, corners, pixels);
+        }
+    }
+
+    /// DICOM directoryの次のseriesを読み込む. directoryは走査し直さない
+    fn show_next_series(&mut self) {
+        let Some(picker) = &mut self.series_picker else {
+            return;
+        };
+        picker.index = (picker.index + 1) % picker.series.len();
+        let series = picker.series[picker.index].clone();
+        info!("Series {} : {}", picker.index, series.description);
+        self.loader.spawn_series(
+            &picker.dir,
+            series,
+            &self.load_options,
+            load_event_sender(&self.proxy),
+        );
+    }
+}
+
+impl super::View for Simple3DView {
+    fn set_image(
+        &mut self,
+        _display: &glium::Display<WindowSurface>,
+        data_path: &std::path::Path,
+    ) -> crate::io::Result<()> {
+        self.series_picker = None;
+        if data_path.is_dir() {
+            // seriesの一覧はworkerからLoadEvent::Scannedで届く
+            self.load_options.dicom_series = None;
+        }
+        self.load(data_path);
+        Ok(())
     }
 
     fn draw(&self, display: &glium::Display<WindowSurface>) {
@@ -218,58 +1052,145 @@ impl super::View for Simple3DView {
             ..Default::default()
         };
 
-        let image_model: [[f32; 4]; 4] = self.image.model_matrix.into();
-        let mask_transform: [[f32; 4]; 4] =
-            (self.image.model_matrix * self.mask.model_matrix.inverse_transform().unwrap()).into();
-        let view: [[f32; 4]; 4] = self.view_matrix.into();
-        let perspective: [[f32; 4]; 4] = self.perspective_matrix.into();
-        let current_pos = [
-            self.current_pos[0] as f32 / self.image.texture.get_width() as f32,
-            self.current_pos[1] as f32 / self.image.texture.get_height().unwrap() as f32,
-            self.current_pos[2] as f32 / self.image.texture.get_depth().unwrap() as f32,
-        ];
-        // draw image
-        let uniforms = uniform! {
-            axis: self.axis as i32,
-            current_pos: current_pos,
-            tex: glium::uniforms::Sampler(&self.image.texture, behavior),
-            mask: glium::uniforms::Sampler(&self.mask.texture, behavior),
-            perspective: perspective,
-            view: view,
-            model: image_model,
-            mask_texture_transform: mask_transform,
-            window_width: self.image.window_width,
-            window_level: self.image.window_level,
+        // imageのtexture座標 -> world座標 -> image, maskのtextureのtexture座標.
+        // chunkごとに読むvolumeのtextureはoverviewなのでimageの座標と異なる
+        let to_world = self.image.texture_to_world();
+        let to_sampled = |texture: &Texture, from_world: cgmath::Matrix4<f32>| match texture
+            .sampled_to_world()
+            .inverse_transform()
+        {
+            Some(world_to_texture) => world_to_texture * from_world,
+            None => cgmath::Matrix4::identity(),
         };
-        target
-            .draw(
-                &self.vertex_buffer,
-                &self.indices,
-                &self.program,
-                &uniforms,
-                &glium::DrawParameters::default(),
-            )
-            .unwrap();
+        let tex_transform: [[f32; 4]; 4] = to_sampled(&self.image, to_world).into();
+        let mask_transform: [[f32; 4]; 4] = to_sampled(&self.mask, to_world).into();
+        let center = self.center_voxel();
+        let shape = self.image.shape();
+        let current_pos = [0, 1, 2].map(|i| (center[i] + 0.5) / shape[i] as f32);
+        // 直交する断面上のtexture座標 -> world座標で回転 -> texture座標
+        let plane_transform: [[f32; 4]; 4] = match (&self.oblique, to_world.inverse_transform()) {
+            (Some(plane), Some(to_texture)) => (to_texture * plane.matrix() * to_world).into(),
+            _ => cgmath::Matrix4::<f32>::identity().into(),
+        };
+        // draw image
+        for (slot, viewport) in self.viewports.iter().enumerate() {
+            let (image_tile, use_tile, tile_transform) = tile_uniforms(&self.image, slot, to_world);
+            let (mask_tile, use_mask_tile, mask_tile_transform) =
+                tile_uniforms(&self.mask, slot, to_world);
+            let image_model: [[f32; 4]; 4] = self.image.model_matrix(viewport.axis).into();
+            let view: [[f32; 4]; 4] = viewport.view_matrix.into();
+            let perspective: [[f32; 4]; 4] = viewport.perspective_matrix.into();
+            let uniforms = uniform! {
+                axis: viewport.axis as i32,
+                current_pos: current_pos,
+                tex: glium::uniforms::Sampler(&self.image.texture, behavior),
+                mask: glium::uniforms::Sampler(&self.mask.texture, behavior),
+                mask_lut: self
+                    .mask
+                    .lut_texture
+                    .sampled()
+                    .magnify_filter(glium::uniforms::MagnifySamplerFilter::Nearest)
+                    .minify_filter(glium::uniforms::MinifySamplerFilter::Nearest),
+                use_mask_lut: self.mask.image.as_ref().is_some_and(|x| x.lut.is_some()),
+                perspective: perspective,
+                view: view,
+                model: image_model,
+                tex_transform: tex_transform,
+                tile: glium::uniforms::Sampler(image_tile, behavior),
+                use_tile: use_tile,
+                tile_transform: tile_transform,
+                mask_tile: glium::uniforms::Sampler(mask_tile, behavior),
+                use_mask_tile: use_mask_tile,
+                mask_tile_transform: mask_tile_transform,
+                mask_texture_transform: mask_transform,
+                plane_transform: plane_transform,
+                window_width: self.image.window_width,
+                window_level: self.image.window_level,
+                show_crosshair: self.show_crosshair && self.image.image.is_some(),
+            };
+            target
+                .draw(
+                    &self.vertex_buffer,
+                    self.indices,
+                    &self.program,
+                    &uniforms,
+                    &glium::DrawParameters {
+                        viewport: Some(viewport.rect),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+        }
+        // draw volume (全体がtextureにないvolumeは描かない)
+        if let (Some(rect), true, true) = (
+            self.volume_rect,
+            self.image.image.is_some(),
+            self.image.has_whole_volume(),
+        ) {
+            let mask_lut = self.mask.image.as_ref().and_then(|x| x.lut.as_ref());
+            let sampled_to_world = self.image.sampled_to_world();
+            // imageのtextureのtexture座標 -> maskのtextureのtexture座標
+            let mask_transform: [[f32; 4]; 4] = to_sampled(&self.mask, sampled_to_world).into();
+            let layers = VolumeLayers {
+                image: &self.image.texture,
+                // overviewのtextureはlevel 0とspacingが違うのでtextureの範囲から求める
+                size: [
+                    sampled_to_world.x.truncate().magnitude(),
+                    sampled_to_world.y.truncate().magnitude(),
+                    sampled_to_world.z.truncate().magnitude(),
+                ],
+                window_width: self.image.window_width,
+                window_level: self.image.window_level,
+                mask: self.mask.image.is_some().then_some(&self.mask.texture),
+                mask_lut: mask_lut.map(|_| &self.mask.lut_texture),
+                mask_texture_transform: mask_transform,
+            };
+            if let Err(e) = self.volume.draw(&mut target, rect, &layers) {
+                error!("Failed to draw volume : {}", e);
+            }
+        }
 
         target.finish().unwrap();
     }
 
     fn handle_keyboard_input(
         &mut self,
-        _display: &glium::Display<WindowSurface>,
+        display: &glium::Display<WindowSurface>,
         event: &winit::event::KeyEvent,
     ) {
         if event.state == ElementState::Released {
-            match event.physical_key {
-                winit::keyboard::PhysicalKey::Code(x) => match x {
+            if let winit::keyboard::PhysicalKey::Code(code) = event.physical_key {
+                match code {
                     winit::keyboard::KeyCode::KeyX => {
-                        self.axis = (self.axis + 1) % 3;
-                        self.image.set_model_matrix(&self.axis);
-                        self.mask.set_model_matrix(&self.axis);
+                        if let Some(viewport) = self.active_viewport_mut() {
+                            viewport.axis = (viewport.axis + 1) % 3;
+                            info!("Current plane : {}", PLANE_NAMES[viewport.axis as usize]);
+                        }
+                    }
+                    winit::keyboard::KeyCode::KeyR if self.active_viewport.is_none() => {
+                        self.volume.reset_view()
+                    }
+                    winit::keyboard::KeyCode::KeyR => self.snap_to_orthogonal(),
+                    winit::keyboard::KeyCode::KeyT => self.volume.next_preset(display),
+                    winit::keyboard::KeyCode::KeyC => self.show_crosshair = !self.show_crosshair,
+                    winit::keyboard::KeyCode::KeyL => self.set_layout(display, self.layout.next()),
+                    winit::keyboard::KeyCode::KeyO => self.toggle_orientation(display),
+                    winit::keyboard::KeyCode::KeyM => self.retag_last_loaded(display),
+                    winit::keyboard::KeyCode::KeyN => self.show_next_series(),
+                    winit::keyboard::KeyCode::Space => self.toggle_playback(),
+                    winit::keyboard::KeyCode::Period => self.step_frame(display, 1),
+                    winit::keyboard::KeyCode::Comma => self.step_frame(display, -1),
+                    winit::keyboard::KeyCode::BracketRight => self.change_frame_rate(2.0),
+                    winit::keyboard::KeyCode::BracketLeft => self.change_frame_rate(0.5),
+                    winit::keyboard::KeyCode::KeyS => {
+                        if self.is_shift_button_pressed || self.mask.image.is_none() {
+                            self.export(Layer::Image);
+                        } else {
+                            self.export(Layer::Mask);
+                        }
                     }
                     _ => (),
-                },
-                _ => (),
+                }
             }
         }
     }
@@ -281,17 +1202,30 @@ impl super::View for Simple3DView {
     ) {
         self.is_shift_button_pressed =
             modifiers.state() & ModifiersState::SHIFT == ModifiersState::SHIFT;
+        self.is_control_button_pressed =
+            modifiers.state() & ModifiersState::CONTROL == ModifiersState::CONTROL;
     }
 
     fn handle_mouse_input(
         &mut self,
-        _display: &glium::Display<WindowSurface>,
+        display: &glium::Display<WindowSurface>,
         state: &ElementState,
         button: &MouseButton,
     ) {
-        println!("{:?} {:?}", state, button);
+        debug!("{:?} {:?}", state, button);
         match button {
-            MouseButton::Left => self.is_left_button_pressed = state == &ElementState::Pressed,
+            MouseButton::Left => {
+                self.is_left_button_pressed = state == &ElementState::Pressed;
+                if !self.is_left_button_pressed {
+                    self.volume.end_drag();
+                }
+                // ctrl + clickでcursorの位置に移動する (dragは平行移動)
+                if self.is_left_button_pressed && self.is_control_button_pressed {
+                    if let Some(pos) = self.prev_mouse_pos {
+                        self.move_to_cursor(display, &pos);
+                    }
+                }
+            }
             MouseButton::Right => self.is_right_button_pressed = state == &ElementState::Pressed,
             _ => (),
         }
@@ -302,15 +1236,47 @@ impl super::View for Simple3DView {
         display: &glium::Display<WindowSurface>,
         position: &PhysicalPosition<f64>,
     ) {
-        let (_, height) = display.get_framebuffer_dimensions();
-        if self.is_left_button_pressed {
+        let (_, framebuffer_height) = display.get_framebuffer_dimensions();
+        if let (true, None, Some(rect)) = (
+            self.is_left_button_pressed,
+            self.active_viewport,
+            self.volume_rect,
+        ) {
+            // volume renderingの領域はdragで回転する
+            self.volume
+                .drag(&rect, rect_to_ndc(&rect, position, framebuffer_height));
+        } else if self.is_left_button_pressed && self.is_control_button_pressed {
+            self.move_to_cursor(display, position);
+        } else if self.is_left_button_pressed && self.is_shift_button_pressed {
+            // shift + dragで断面を傾ける
             if let Some(prev) = self.prev_mouse_pos {
+                self.rotate_plane(position.x - prev.x, position.y - prev.y);
+            }
+        } else if self.is_left_button_pressed {
+            if let (Some(prev), Some(viewport)) = (
+                self.prev_mouse_pos,
+                self.active_viewport.and_then(|i| self.viewports.get_mut(i)),
+            ) {
+                let height = viewport.rect.height.max(1);
                 let dx = (position.x - prev.x) / height as f64 * 2.0;
                 let dy = -(position.y - prev.y) / height as f64 * 2.0;
-                self.view_matrix[3][0] += dx as f32;
-                self.view_matrix[3][1] += dy as f32;
+                viewport.view_matrix[3][0] += dx as f32;
+                viewport.view_matrix[3][1] += dy as f32;
+            }
+        } else if let Some(index) = self
+            .viewports
+            .iter()
+            .position(|x| x.contains(position, framebuffer_height))
+        {
+            // drag中は同じ領域を操作し続ける
+            self.active_viewport = Some(index);
+        } else if let Some(rect) = self.volume_rect {
+            let ndc = rect_to_ndc(&rect, position, framebuffer_height);
+            if ndc.iter().all(|x| (-1.0..=1.0).contains(x)) {
+                self.active_viewport = None;
             }
         }
+        self.report_pick(display, position);
         self.prev_mouse_pos = Some(*position);
     }
 
@@ -320,19 +1286,21 @@ impl super::View for Simple3DView {
         delta: &MouseScrollDelta,
         _phase: &TouchPhase,
     ) {
+        let scale = match delta {
+            MouseScrollDelta::LineDelta(_, y) => 1.0 + y / 10.0,
+            MouseScrollDelta::PixelDelta(_) => 1.0,
+        };
+        let Some(viewport) = self.active_viewport.and_then(|i| self.viewports.get_mut(i)) else {
+            // volume renderingの領域はwheelでzoomする
+            self.volume.zoom(scale);
+            return;
+        };
         if self.is_shift_button_pressed {
-            let scale = match delta {
-                MouseScrollDelta::LineDelta(_, y) => 1.0 + y / 10.0,
-                MouseScrollDelta::PixelDelta(_) => 1.0,
-            };
             match self.prev_mouse_pos {
                 Some(pos) => {
-                    let x = (pos.x as f32 / display.get_framebuffer_dimensions().0 as f32 * 2.0
-                        - 1.0)
-                        / self.perspective_matrix[0][0];
-                    let y = (1.0
-                        - pos.y as f32 / display.get_framebuffer_dimensions().1 as f32 * 2.0)
-                        / self.perspective_matrix[1][1];
+                    let [x, y] = viewport.to_ndc(&pos, display.get_framebuffer_dimensions().1);
+                    let x = x / viewport.perspective_matrix[0][0];
+                    let y = y / viewport.perspective_matrix[1][1];
                     let pre_trans = cgmath::Matrix4::from([
                         [1.0, 0.0, 0.0, 0.0],
                         [0.0, 1.0, 0.0, 0.0],
@@ -351,28 +1319,38 @@ impl super::View for Simple3DView {
                         [0.0, 0.0, 1.0, 0.0],
                         [x, y, 0.0, 1.0],
                     ]);
-                    self.view_matrix = post_trans * scale * pre_trans * self.view_matrix;
+                    viewport.view_matrix = post_trans * scale * pre_trans * viewport.view_matrix;
                 }
                 None => {
-                    self.view_matrix[3][0] += (1.0 - scale) * self.view_matrix[3][0];
-                    self.view_matrix[3][1] += (1.0 - scale) * self.view_matrix[3][1];
-                    self.view_matrix[0][0] *= scale as f32;
-                    self.view_matrix[1][1] *= scale as f32;
+                    let view_matrix = &mut viewport.view_matrix;
+                    view_matrix[3][0] += (1.0 - scale) * view_matrix[3][0];
+                    view_matrix[3][1] += (1.0 - scale) * view_matrix[3][1];
+                    view_matrix[0][0] *= scale;
+                    view_matrix[1][1] *= scale;
                 }
             }
+        } else if self.is_control_button_pressed {
+            if let MouseScrollDelta::LineDelta(_, y) = delta {
+                self.step_frame(display, y.abs().ceil() as i32 * y.signum() as i32);
+            }
+        } else if let (Some(plane), Some(image)) = (&self.oblique, &self.image.image) {
+            // 斜めの断面は回転した法線の方向にspacingずつ動かす
+            if let MouseScrollDelta::LineDelta(_, y) = delta {
+                let axis = self.axis() as usize;
+                let spacing = [image.spacing.0, image.spacing.1, image.spacing.2][axis];
+                let step = plane.direction(image, axis) * (y.abs().ceil() * y.signum() * spacing);
+                let center = cgmath::Vector3::from(plane.center) + step;
+                self.set_oblique_center(center.into());
+            }
         } else {
-            let index = self.current_pos[self.axis as usize] as f32;
+            let axis = self.axis();
+            let index = self.current_pos[axis as usize] as f32;
             let index = match delta {
                 MouseScrollDelta::LineDelta(_, y) => index + y.abs().ceil() * y.signum(),
                 MouseScrollDelta::PixelDelta(_) => index,
             };
-            let max = match self.axis {
-                0 => self.image.texture.get_width(),
-                1 => self.image.texture.get_height().unwrap(),
-                2 => self.image.texture.get_depth().unwrap(),
-                _ => 0,
-            };
-            self.current_pos[self.axis as usize] = (index as i32).max(0).min(max as i32) as u32;
+            let max = self.image.shape()[axis as usize];
+            self.current_pos[axis as usize] = (index as i32).min(max as i32 - 1).max(0) as u32;
         }
     }
 
@@ -381,17 +1359,28 @@ impl super::View for Simple3DView {
         _display: &glium::Display<WindowSurface>,
         window_size: winit::dpi::PhysicalSize<u32>,
     ) {
-        let aspect_ratio = window_size.height as f32 / window_size.width as f32;
-        let f = if 1.0.lt(&aspect_ratio) {
-            0.5
-        } else {
-            0.5 / aspect_ratio
-        };
-        self.perspective_matrix = cgmath::Matrix4::from([
-            [f * aspect_ratio, 0.0, 0.0, 0.0],
-            [0.0, f, 0.0, 0.0],
-            [0.0, 0.0, 1.0, 0.0],
-            [0.0, 0.0, 0.0, 1.0],
-        ]);
+        // 各領域の大きさをwindowの大きさに合わせる. zoom, 位置はそのまま
+        let rects = self.layout.split(window_size.width, window_size.height);
+        for (viewport, rect) in self.viewports.iter_mut().zip(rects) {
+            viewport.set_rect(rect);
+        }
+        self.volume_rect = self
+            .layout
+            .volume_rect(window_size.width, window_size.height);
+    }
+
+    fn handle_user_event(&mut self, display: &glium::Display<WindowSurface>, event: UserEvent) {
+        match event {
+            UserEvent::Load(event) => self.handle_load_event(display, event),
+        }
+    }
+
+    fn update(&mut self, display: &glium::Display<WindowSurface>) {
+        self.request_visible_tiles();
+        if self.is_playing && self.last_frame_time.elapsed().as_secs_f32() >= 1.0 / self.frame_rate
+        {
+            self.last_frame_time = std::time::Instant::now();
+            self.step_frame(display, 1);
+        }
     }
 }
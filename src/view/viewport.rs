@@ -0,0 +1,532 @@
+use cgmath::prelude::*;
+use winit::dpi::PhysicalPosition;
+
+use crate::io::Image3D;
+
+/// 画面の分割方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// 1つの断面だけを表示する
+    Single,
+    /// axial, coronal, sagittalを横に並べる
+    TriPlanar,
+    /// 2x2に分割して左上からaxial, coronal, sagittal. 右下はvolume rendering
+    Quad,
+    /// volume renderingだけを表示する
+    Volume,
+}
+
+impl Layout {
+    pub fn next(self) -> Self {
+        match self {
+            Layout::Single => Layout::TriPlanar,
+            Layout::TriPlanar => Layout::Quad,
+            Layout::Quad => Layout::Volume,
+            Layout::Volume => Layout::Single,
+        }
+    }
+
+    /// width x heightの画面を分割した断面の各領域 (左下が原点)
+    pub fn split(self, width: u32, height: u32) -> Vec<glium::Rect> {
+        let rect = |left, bottom, width, height| glium::Rect {
+            left,
+            bottom,
+            width,
+            height,
+        };
+        match self {
+            Layout::Single => vec![rect(0, 0, width, height)],
+            Layout::TriPlanar => {
+                let w = width / 3;
+                vec![
+                    rect(0, 0, w, height),
+                    rect(w, 0, w, height),
+                    // 割り切れない分は右端の領域に含める
+                    rect(2 * w, 0, width - 2 * w, height),
+                ]
+            }
+            Layout::Quad => {
+                let (w, h) = (width / 2, height / 2);
+                vec![
+                    rect(0, h, w, height - h),
+                    rect(w, h, width - w, height - h),
+                    rect(0, 0, w, h),
+                ]
+            }
+            Layout::Volume => vec![],
+        }
+    }
+
+    /// volume renderingの領域
+    pub fn volume_rect(self, width: u32, height: u32) -> Option<glium::Rect> {
+        let (w, h) = (width / 2, height / 2);
+        match self {
+            Layout::Single | Layout::TriPlanar => None,
+            Layout::Quad => Some(glium::Rect {
+                left: w,
+                bottom: 0,
+                width: width - w,
+                height: h,
+            }),
+            Layout::Volume => Some(glium::Rect {
+                left: 0,
+                bottom: 0,
+                width,
+                height,
+            }),
+        }
+    }
+}
+
+/// windowの座標 (左上が原点) -> `rect`内の正規化デバイス座標 [-1, 1]
+pub fn rect_to_ndc(
+    rect: &glium::Rect,
+    position: &PhysicalPosition<f64>,
+    framebuffer_height: u32,
+) -> [f32; 2] {
+    let x = (position.x as f32 - rect.left as f32) / rect.width.max(1) as f32;
+    let y = (framebuffer_height as f32 - position.y as f32 - rect.bottom as f32)
+        / rect.height.max(1) as f32;
+    [x * 2.0 - 1.0, y * 2.0 - 1.0]
+}
+
+/// `axis`に垂直な断面の横, 縦の方向の軸
+pub fn plane_axes(axis: u32) -> [usize; 2] {
+    match axis {
+        0 => [1, 2],
+        1 => [0, 2],
+        _ => [0, 1],
+    }
+}
+
+/// `axis`に垂直な断面を表示するときのmodel行列
+pub fn slice_model_matrix(image: Option<&Image3D>, axis: u32) -> cgmath::Matrix4<f32> {
+    let (spacing_x, spacing_y) = match image {
+        Some(image) => match axis {
+            0 => (image.spacing.2, image.spacing.1),
+            1 => (image.spacing.2, image.spacing.0),
+            2 => (image.spacing.1, image.spacing.0),
+            _ => panic!("Invalid axis : {}", axis),
+        },
+        _ => (1.0, 1.0),
+    };
+
+    cgmath::Matrix4::from([
+        [spacing_x, 0.0, 0.0, 0.0],
+        [0.0, spacing_y, 0.0, 0.0],
+        [0.0, 0.0, 1.0, 0.0],
+        [0.0, 0.0, 0.0, 1.0],
+    ])
+}
+
+/// 連続なvoxel座標に最も近いvoxelのindex. volumeの外の場合はNone
+fn nearest_voxel(voxel: [f32; 3], shape: (u32, u32, u32)) -> Option<[u32; 3]> {
+    let shape = [shape.0, shape.1, shape.2];
+    let mut index = [0; 3];
+    for i in 0..3 {
+        let v = voxel[i].round();
+        if !(0.0..shape[i] as f32).contains(&v) {
+            return None;
+        }
+        index[i] = v as u32;
+    }
+    Some(index)
+}
+
+/// windowの座標の下にある点
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pick {
+    // world座標 (mm)
+    pub world: [f32; 3],
+    // imageのvoxel index. imageの外の場合はNone
+    pub image_voxel: Option<[u32; 3]>,
+    // 同じworld座標にあるmaskのvoxel index. maskの外の場合はNone
+    pub mask_voxel: Option<[u32; 3]>,
+}
+
+/// 斜めの断面. 直交する断面をworld座標の`center`を中心に`rotation`だけ回転する.
+/// 全ての領域で共有するので, 各領域の断面は互いに直交したまま
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ObliquePlane {
+    pub rotation: cgmath::Quaternion<f32>,
+    // 回転の中心 (world座標, mm). 全ての断面はこの点を通る
+    pub center: [f32; 3],
+}
+
+impl ObliquePlane {
+    pub fn new(center: [f32; 3]) -> Self {
+        ObliquePlane {
+            rotation: cgmath::Quaternion::one(),
+            center,
+        }
+    }
+
+    /// world座標 -> 回転したworld座標の変換行列
+    pub fn matrix(&self) -> cgmath::Matrix4<f32> {
+        let center = cgmath::Vector3::from(self.center);
+        cgmath::Matrix4::from_translation(center)
+            * cgmath::Matrix4::from(self.rotation)
+            * cgmath::Matrix4::from_translation(-center)
+    }
+
+    pub fn transform(&self, world: [f32; 3]) -> [f32; 3] {
+        let world = self.matrix() * cgmath::Vector4::new(world[0], world[1], world[2], 1.0);
+        [world.x, world.y, world.z]
+    }
+
+    /// 回転した後のimageの`axis`の方向 (world座標の単位vector)
+    pub fn direction(&self, image: &Image3D, axis: usize) -> cgmath::Vector3<f32> {
+        let direction = cgmath::vec3(
+            image.affine[0][axis],
+            image.affine[1][axis],
+            image.affine[2][axis],
+        );
+        self.rotation * direction.normalize()
+    }
+
+    /// world座標の`axis`を軸に`angle` (radian) だけ回転を加える
+    pub fn rotate(&mut self, axis: cgmath::Vector3<f32>, angle: f32) {
+        self.rotation = (cgmath::Quaternion::from_axis_angle(axis, cgmath::Rad(angle))
+            * self.rotation)
+            .normalize();
+    }
+}
+
+/// 画面を分割した1つの領域. volumeを`axis`に垂直な断面で表示する
+pub struct Viewport {
+    pub axis: u32,
+    // framebuffer上の領域 (左下が原点)
+    pub rect: glium::Rect,
+    pub view_matrix: cgmath::Matrix4<f32>, // カメラの位置, zoom
+    pub perspective_matrix: cgmath::Matrix4<f32>, // aspect比
+}
+
+impl Viewport {
+    pub fn new(axis: u32, rect: glium::Rect) -> Self {
+        let mut viewport = Viewport {
+            axis,
+            rect,
+            view_matrix: cgmath::Matrix4::identity(),
+            perspective_matrix: cgmath::Matrix4::identity(),
+        };
+        viewport.set_rect(rect);
+        viewport
+    }
+
+    /// 領域を変更し, 領域のaspect比に合わせてperspective_matrixを作り直す
+    pub fn set_rect(&mut self, rect: glium::Rect) {
+        self.rect = rect;
+        let aspect_ratio = rect.height.max(1) as f32 / rect.width.max(1) as f32;
+        let f = if 1.0.lt(&aspect_ratio) {
+            0.5
+        } else {
+            0.5 / aspect_ratio
+        };
+        self.perspective_matrix = cgmath::Matrix4::from([
+            [f * aspect_ratio, 0.0, 0.0, 0.0],
+            [0.0, f, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+    }
+
+    /// windowの座標 (左上が原点) -> 領域内の正規化デバイス座標 [-1, 1]
+    pub fn to_ndc(&self, position: &PhysicalPosition<f64>, framebuffer_height: u32) -> [f32; 2] {
+        rect_to_ndc(&self.rect, position, framebuffer_height)
+    }
+
+    /// windowの座標 -> 表示している断面上の2次元のtexture座標.
+    /// `model`は断面の描画に使うmodel行列 (perspective * view * modelの逆変換で求める)
+    pub fn to_slice_coords(
+        &self,
+        position: &PhysicalPosition<f64>,
+        framebuffer_height: u32,
+        model: cgmath::Matrix4<f32>,
+    ) -> Option<[f32; 2]> {
+        self.ndc_to_slice_coords(self.to_ndc(position, framebuffer_height), model)
+    }
+
+    fn ndc_to_slice_coords(
+        &self,
+        [x, y]: [f32; 2],
+        model: cgmath::Matrix4<f32>,
+    ) -> Option<[f32; 2]> {
+        let inverse = (self.perspective_matrix * self.view_matrix * model).inverse_transform()?;
+        // 断面は-1 - 1の正方形をtexture座標0 - 1に対応させて描いている
+        let p = inverse * cgmath::vec4(x, y, 0.0, 1.0);
+        Some([(p.x / p.w + 1.0) / 2.0, (p.y / p.w + 1.0) / 2.0])
+    }
+
+    /// windowの座標の下にある点を求める.
+    /// 表示中の断面に垂直な方向の位置は`current_pos` (imageの連続なvoxel座標).
+    /// 斜めの断面の場合は直交する断面上の点を`plane`で回転する
+    pub fn pick(
+        &self,
+        position: &PhysicalPosition<f64>,
+        framebuffer_height: u32,
+        image: &Image3D,
+        mask: Option<&Image3D>,
+        current_pos: [f32; 3],
+        plane: Option<&ObliquePlane>,
+    ) -> Option<Pick> {
+        let model = slice_model_matrix(Some(image), self.axis);
+        let coords = self.to_slice_coords(position, framebuffer_height, model)?;
+        let mut voxel = self.slice_to_voxel(coords, image, current_pos);
+        let mut world = image.voxel_to_world(voxel);
+        if let Some(plane) = plane {
+            world = plane.transform(world);
+            voxel = image.world_to_voxel(world);
+        }
+        Some(Pick {
+            world,
+            image_voxel: nearest_voxel(voxel, image.shape),
+            mask_voxel: mask.and_then(|mask| nearest_voxel(mask.world_to_voxel(world), mask.shape)),
+        })
+    }
+
+    /// 断面上の2次元のtexture座標 -> imageの連続なvoxel座標. 断面に垂直な方向は`current_pos`
+    fn slice_to_voxel(&self, coords: [f32; 2], image: &Image3D, current_pos: [f32; 3]) -> [f32; 3] {
+        let shape = [image.shape.0, image.shape.1, image.shape.2];
+        let mut voxel = current_pos;
+        for (t, axis) in coords.into_iter().zip(plane_axes(self.axis)) {
+            // texture座標tとvoxel index vの関係は t = (v + 0.5) / n
+            voxel[axis] = t * shape[axis] as f32 - 0.5;
+        }
+        voxel
+    }
+
+    /// 領域の左下, 右下, 左上の隅に表示されるimageの連続なvoxel座標 (直交する断面)
+    pub fn visible_corners(&self, image: &Image3D, current_pos: [f32; 3]) -> Option<[[f32; 3]; 3]> {
+        let model = slice_model_matrix(Some(image), self.axis);
+        let corner = |ndc| {
+            self.ndc_to_slice_coords(ndc, model)
+                .map(|coords| self.slice_to_voxel(coords, image, current_pos))
+        };
+        Some([
+            corner([-1.0, -1.0])?,
+            corner([1.0, -1.0])?,
+            corner([-1.0, 1.0])?,
+        ])
+    }
+
+    pub fn contains(&self, position: &PhysicalPosition<f64>, framebuffer_height: u32) -> bool {
+        self.to_ndc(position, framebuffer_height)
+            .iter()
+            .all(|x| (-1.0..=1.0).contains(x))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u32 = 900;
+    const HEIGHT: u32 = 600;
+
+    fn volume(shape: (u32, u32, u32), affine: [[f32; 4]; 4]) -> Image3D {
+        let len = (shape.0 * shape.1 * shape.2) as usize;
+        Image3D {
+            data: (0..len).map(|x| x as f32).collect(),
+            shape,
+            spacing: (affine[0][0].abs(), affine[1][1].abs(), affine[2][2].abs()),
+            frames: 1,
+            frame_interval: 0.0,
+            datatype: crate::io::DataType::F32,
+            scl_slope: 0.0,
+            scl_inter: 0.0,
+            affine,
+            qform_code: 0,
+            sform_code: 1,
+            format: None,
+            mipmaps: None,
+            lut: None,
+            chunked: None,
+            is_mask: false,
+        }
+    }
+
+    fn ct() -> Image3D {
+        volume(
+            (11, 21, 31),
+            [
+                [0.5, 0.0, 0.0, -10.0],
+                [0.0, 0.8, 0.0, 20.0],
+                [0.0, 0.0, 2.0, 5.0],
+                [0.0, 0.0, 0.0, 1.0],
+            ],
+        )
+    }
+
+    /// voxelの中心が描かれるwindowの座標 (左上が原点)
+    fn project(viewport: &Viewport, image: &Image3D, voxel: [u32; 3]) -> PhysicalPosition<f64> {
+        let shape = [image.shape.0, image.shape.1, image.shape.2];
+        let [u, v] = plane_axes(viewport.axis)
+            .map(|axis| (voxel[axis] as f32 + 0.5) / shape[axis] as f32 * 2.0 - 1.0);
+        let clip = viewport.perspective_matrix
+            * viewport.view_matrix
+            * slice_model_matrix(Some(image), viewport.axis)
+            * cgmath::vec4(u, v, 0.0, 1.0);
+        let rect = viewport.rect;
+        let x = (clip.x / clip.w + 1.0) / 2.0 * rect.width as f32 + rect.left as f32;
+        let y = (clip.y / clip.w + 1.0) / 2.0 * rect.height as f32 + rect.bottom as f32;
+        PhysicalPosition::new(x as f64, (HEIGHT as f32 - y) as f64)
+    }
+
+    fn assert_close(actual: [f32; 3], expected: [f32; 3]) {
+        for i in 0..3 {
+            assert!(
+                (actual[i] - expected[i]).abs() < 1e-3,
+                "{:?} != {:?}",
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn pick_center_of_single_viewport() {
+        let image = ct();
+        let viewport = Viewport::new(2, Layout::Single.split(WIDTH, HEIGHT)[0]);
+        let center = PhysicalPosition::new(WIDTH as f64 / 2.0, HEIGHT as f64 / 2.0);
+        let pick = viewport
+            .pick(&center, HEIGHT, &image, None, [0.0, 0.0, 7.0], None)
+            .unwrap();
+        assert_eq!(pick.image_voxel, Some([5, 10, 7]));
+        assert_close(
+            pick.world,
+            [-10.0 + 5.0 * 0.5, 20.0 + 10.0 * 0.8, 5.0 + 7.0 * 2.0],
+        );
+        assert_eq!(pick.mask_voxel, None);
+        assert_eq!(
+            image.value([5, 10, 7], 0),
+            Some((5 + 11 * (10 + 21 * 7)) as f32)
+        );
+    }
+
+    #[test]
+    fn pick_inverts_projection_in_every_pane() {
+        let image = ct();
+        let current_pos = [3, 17, 12];
+        for layout in [Layout::Single, Layout::TriPlanar, Layout::Quad] {
+            for (i, rect) in layout.split(WIDTH, HEIGHT).into_iter().enumerate() {
+                let mut viewport = Viewport::new(i as u32, rect);
+                // 平行移動とzoom
+                viewport.view_matrix =
+                    cgmath::Matrix4::from_translation(cgmath::vec3(0.2, -0.1, 0.0))
+                        * cgmath::Matrix4::from_scale(1.7);
+                for voxel in [[0, 0, 0], [10, 20, 30], [4, 9, 21]] {
+                    let mut expected = current_pos;
+                    for axis in plane_axes(viewport.axis) {
+                        expected[axis] = voxel[axis];
+                    }
+                    let position = project(&viewport, &image, expected);
+                    let pick = viewport
+                        .pick(
+                            &position,
+                            HEIGHT,
+                            &image,
+                            None,
+                            current_pos.map(|x| x as f32),
+                            None,
+                        )
+                        .unwrap();
+                    assert_eq!(pick.image_voxel, Some(expected), "{:?} axis {}", layout, i);
+                    assert_close(pick.world, image.voxel_to_world(expected.map(|x| x as f32)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pick_maps_mask_through_world() {
+        let image = ct();
+        // 解像度が半分でずれた位置のmask
+        let mask = volume(
+            (6, 11, 16),
+            [
+                [1.0, 0.0, 0.0, -10.0],
+                [0.0, 1.6, 0.0, 20.0],
+                [0.0, 0.0, 4.0, 5.0],
+                [0.0, 0.0, 0.0, 1.0],
+            ],
+        );
+        let viewport = Viewport::new(2, Layout::Single.split(WIDTH, HEIGHT)[0]);
+        let position = project(&viewport, &image, [8, 6, 10]);
+        let pick = viewport
+            .pick(
+                &position,
+                HEIGHT,
+                &image,
+                Some(&mask),
+                [0.0, 0.0, 10.0],
+                None,
+            )
+            .unwrap();
+        assert_eq!(pick.image_voxel, Some([8, 6, 10]));
+        assert_eq!(pick.mask_voxel, Some([4, 3, 5]));
+    }
+
+    #[test]
+    fn visible_corners_surround_center() {
+        let image = ct();
+        let mut viewport = Viewport::new(1, Layout::TriPlanar.split(WIDTH, HEIGHT)[1]);
+        viewport.view_matrix = cgmath::Matrix4::from_translation(cgmath::vec3(0.2, -0.1, 0.0))
+            * cgmath::Matrix4::from_scale(1.7);
+        let current_pos = [5.0, 10.0, 15.0];
+        let [p0, p1, p2] = viewport.visible_corners(&image, current_pos).unwrap();
+        let rect = viewport.rect;
+        let center = PhysicalPosition::new(
+            rect.left as f64 + rect.width as f64 / 2.0,
+            HEIGHT as f64 - rect.bottom as f64 - rect.height as f64 / 2.0,
+        );
+        let pick = viewport
+            .pick(&center, HEIGHT, &image, None, current_pos, None)
+            .unwrap();
+        // 右下と左上の隅の中点が領域の中心
+        let middle = [0, 1, 2].map(|i| (p1[i] + p2[i]) / 2.0);
+        assert_close(image.voxel_to_world(middle), pick.world);
+        // 横の辺はx軸, 縦の辺はz軸の方向
+        assert_close([p0[1], p1[1], p2[1]], [10.0; 3]);
+        assert!((p1[2] - p0[2]).abs() < 1e-3 && p1[0] > p0[0]);
+        assert!((p2[0] - p0[0]).abs() < 1e-3 && p2[2] > p0[2]);
+    }
+
+    #[test]
+    fn pick_outside_of_image() {
+        let image = ct();
+        let viewport = Viewport::new(1, Layout::TriPlanar.split(WIDTH, HEIGHT)[1]);
+        // 領域の左上の隅は画像の外
+        let position = PhysicalPosition::new(viewport.rect.left as f64 + 1.0, 1.0);
+        let pick = viewport
+            .pick(&position, HEIGHT, &image, None, [5.0, 10.0, 15.0], None)
+            .unwrap();
+        assert_eq!(pick.image_voxel, None);
+        // 表示中の断面の位置はcurrent_posのまま
+        assert!((pick.world[1] - image.voxel_to_world([0.0, 10.0, 0.0])[1]).abs() < 1e-3);
+    }
+
+    #[test]
+    fn pick_on_oblique_plane() {
+        let image = ct();
+        let current_pos = [5.0, 10.0, 7.0];
+        let center = image.voxel_to_world(current_pos);
+        // axialの断面をz軸のまわりに90度回転すると, x方向の点がy方向に移る
+        let mut plane = ObliquePlane::new(center);
+        plane.rotate(cgmath::Vector3::unit_z(), std::f32::consts::FRAC_PI_2);
+        let viewport = Viewport::new(2, Layout::Single.split(WIDTH, HEIGHT)[0]);
+        let position = project(&viewport, &image, [10, 10, 7]);
+        let pick = viewport
+            .pick(&position, HEIGHT, &image, None, current_pos, Some(&plane))
+            .unwrap();
+        assert_close(pick.world, [center[0], center[1] + 5.0 * 0.5, center[2]]);
+        assert_eq!(pick.image_voxel, Some([5, 13, 7]));
+
+        // 中心は回転しても動かない
+        let position = project(&viewport, &image, [5, 10, 7]);
+        let pick = viewport
+            .pick(&position, HEIGHT, &image, None, current_pos, Some(&plane))
+            .unwrap();
+        assert_close(pick.world, center);
+        assert_close(plane.direction(&image, 0).into(), [0.0, 1.0, 0.0]);
+    }
+}
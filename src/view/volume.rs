@@ -0,0 +1,369 @@
+use cgmath::prelude::*;
+use glium::glutin::surface::WindowSurface;
+use glium::Surface;
+use glium::{implement_vertex, uniform};
+use tracing::{error, info};
+
+use crate::shader;
+use crate::shader::ShaderSrc;
+
+// transfer functionを焼き込む1D textureの大きさ
+const TRANSFER_TEXTURE_SIZE: usize = 256;
+const MIN_ZOOM: f32 = 0.1;
+const MAX_ZOOM: f32 = 20.0;
+
+#[derive(Copy, Clone)]
+struct VolumeVertex {
+    position: [f32; 2],
+}
+implement_vertex!(VolumeVertex, position);
+
+/// 値 -> 色, 不透明度の区分線形な関数.
+/// 不透明度はvolumeの最長辺の1 voxelを進むあたりの値
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransferFunction {
+    // (値, RGBA). 値の昇順
+    pub points: Vec<(f32, [f32; 4])>,
+}
+
+impl TransferFunction {
+    /// `value`の色. 両端の外側は端の点の色
+    pub fn sample(&self, value: f32) -> [f32; 4] {
+        let (Some(first), Some(last)) = (self.points.first(), self.points.last()) else {
+            return [0.0; 4];
+        };
+        if value <= first.0 {
+            return first.1;
+        }
+        for pair in self.points.windows(2) {
+            let ((x0, c0), (x1, c1)) = (pair[0], pair[1]);
+            if value <= x1 {
+                let t = if x1 > x0 {
+                    (value - x0) / (x1 - x0)
+                } else {
+                    1.0
+                };
+                return [0, 1, 2, 3].map(|i| c0[i] + (c1[i] - c0[i]) * t);
+            }
+        }
+        last.1
+    }
+
+    /// 最初と最後の点の値の範囲
+    pub fn range(&self) -> (f32, f32) {
+        match (self.points.first(), self.points.last()) {
+            (Some(first), Some(last)) => (first.0, last.0),
+            _ => (0.0, 1.0),
+        }
+    }
+
+    /// 範囲を等間隔にsampleした1D texture
+    fn upload(
+        &self,
+        display: &glium::Display<WindowSurface>,
+    ) -> crate::io::Result<glium::texture::Texture1d> {
+        let (min, max) = self.range();
+        let colors: Vec<(f32, f32, f32, f32)> = (0..TRANSFER_TEXTURE_SIZE)
+            .map(|i| {
+                let value = min + (max - min) * i as f32 / (TRANSFER_TEXTURE_SIZE - 1) as f32;
+                let [r, g, b, a] = self.sample(value);
+                (r, g, b, a)
+            })
+            .collect();
+        glium::texture::Texture1d::with_format(
+            display,
+            colors,
+            glium::texture::UncompressedFloatFormat::F32F32F32F32,
+            glium::texture::MipmapsOption::NoMipmap,
+        )
+        .map_err(|e| crate::io::Error::TextureUpload(e.to_string()))
+    }
+}
+
+/// transfer functionの既定の設定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    CtBone,
+    SoftTissue,
+    /// 最大値投影. 濃淡はimageのwindowに従う
+    Mip,
+}
+
+impl Preset {
+    pub fn next(self) -> Self {
+        match self {
+            Preset::CtBone => Preset::SoftTissue,
+            Preset::SoftTissue => Preset::Mip,
+            Preset::Mip => Preset::CtBone,
+        }
+    }
+
+    /// CT値 (HU) に対するtransfer function
+    pub fn transfer_function(self) -> TransferFunction {
+        let points = match self {
+            Preset::CtBone => vec![
+                (-1000.0, [0.0, 0.0, 0.0, 0.0]),
+                (150.0, [0.75, 0.45, 0.3, 0.0]),
+                (300.0, [0.9, 0.8, 0.65, 0.15]),
+                (700.0, [1.0, 0.95, 0.85, 0.5]),
+                (3000.0, [1.0, 1.0, 1.0, 0.8]),
+            ],
+            Preset::SoftTissue => vec![
+                (-1000.0, [0.0, 0.0, 0.0, 0.0]),
+                (-200.0, [0.6, 0.3, 0.2, 0.0]),
+                (-50.0, [0.85, 0.5, 0.4, 0.02]),
+                (80.0, [0.95, 0.6, 0.5, 0.08]),
+                (300.0, [1.0, 0.95, 0.85, 0.4]),
+                (3000.0, [1.0, 1.0, 1.0, 0.6]),
+            ],
+            Preset::Mip => vec![(0.0, [1.0; 4]), (1.0, [1.0; 4])],
+        };
+        TransferFunction { points }
+    }
+}
+
+/// ray castingでvolume renderingするために描画側から渡すtexture
+pub struct VolumeLayers<'a> {
+    pub image: &'a glium::texture::Texture3d,
+    // textureの各軸の長さ (mm)
+    pub size: [f32; 3],
+    pub window_width: f32,
+    pub window_level: f32,
+    pub mask: Option<&'a glium::texture::Texture3d>,
+    // label -> 色. Noneの場合はlabelによらず赤
+    pub mask_lut: Option<&'a glium::texture::Texture1d>,
+    // imageのtexture座標 -> maskのtexture座標
+    pub mask_texture_transform: [[f32; 4]; 4],
+}
+
+/// 3D textureをray castingで描画する. arcballで回転し, transfer functionで色をつける.
+/// maskのlabelの境界は半透明の面として重ねる
+pub struct VolumeRenderer {
+    vertex_buffer: glium::VertexBuffer<VolumeVertex>,
+    indices: glium::index::NoIndices,
+    program: glium::Program,
+    preset: Preset,
+    transfer_function: TransferFunction,
+    transfer_texture: glium::texture::Texture1d,
+    // volume -> 視点の座標の回転 (arcball) とzoom
+    rotation: cgmath::Quaternion<f32>,
+    zoom: f32,
+    // drag中のcursorのarcball上の位置
+    prev_arcball: Option<cgmath::Vector3<f32>>,
+}
+
+impl VolumeRenderer {
+    pub fn new(
+        display: &glium::Display<WindowSurface>,
+    ) -> Result<Self, glium::ProgramCreationError> {
+        let shape = [[-1.0, -1.0], [1.0, -1.0], [1.0, 1.0], [-1.0, 1.0]]
+            .map(|position| VolumeVertex { position });
+        let vertex_buffer = glium::VertexBuffer::new(display, &shape).unwrap();
+        let indices = glium::index::NoIndices(glium::index::PrimitiveType::TriangleFan);
+        let program = shader!("volume").compile(display)?;
+        let preset = Preset::CtBone;
+        let transfer_function = preset.transfer_function();
+        let transfer_texture = transfer_function.upload(display).unwrap();
+        Ok(VolumeRenderer {
+            vertex_buffer,
+            indices,
+            program,
+            preset,
+            transfer_function,
+            transfer_texture,
+            rotation: Self::anterior_view(),
+            zoom: 1.0,
+            prev_arcball: None,
+        })
+    }
+
+    /// RASのvolumeを前から見る向き (上が頭側, 右が患者の左)
+    fn anterior_view() -> cgmath::Quaternion<f32> {
+        cgmath::Quaternion::from(cgmath::Matrix3::from_cols(
+            cgmath::vec3(-1.0, 0.0, 0.0),
+            cgmath::vec3(0.0, 0.0, 1.0),
+            cgmath::vec3(0.0, 1.0, 0.0),
+        ))
+    }
+
+    pub fn next_preset(&mut self, display: &glium::Display<WindowSurface>) {
+        self.preset = self.preset.next();
+        self.transfer_function = self.preset.transfer_function();
+        match self.transfer_function.upload(display) {
+            Ok(texture) => self.transfer_texture = texture,
+            Err(e) => error!("Failed to upload transfer function : {}", e),
+        }
+        info!("Volume rendering preset : {:?}", self.preset);
+    }
+
+    /// 回転とzoomを初期状態に戻す
+    pub fn reset_view(&mut self) {
+        self.rotation = Self::anterior_view();
+        self.zoom = 1.0;
+    }
+
+    /// 領域のaspect比を補正する係数 (短い辺の方向が-1 - 1)
+    fn aspect(rect: &glium::Rect) -> (f32, f32) {
+        let (width, height) = (rect.width.max(1) as f32, rect.height.max(1) as f32);
+        if width > height {
+            (height / width, 1.0)
+        } else {
+            (1.0, width / height)
+        }
+    }
+
+    /// 視点の座標 (zは手前が正) -> 正規化デバイス座標
+    fn projection_matrix(&self, rect: &glium::Rect) -> cgmath::Matrix4<f32> {
+        let (ax, ay) = Self::aspect(rect);
+        cgmath::Matrix4::from_nonuniform_scale(self.zoom * ax, self.zoom * ay, -0.5)
+    }
+
+    /// 正規化デバイス座標をarcballの球面上の点にする
+    fn arcball_point(rect: &glium::Rect, ndc: [f32; 2]) -> cgmath::Vector3<f32> {
+        let (ax, ay) = Self::aspect(rect);
+        let (x, y) = (ndc[0] / ax, ndc[1] / ay);
+        let d = x * x + y * y;
+        if d < 1.0 {
+            cgmath::vec3(x, y, (1.0 - d).sqrt())
+        } else {
+            cgmath::vec3(x, y, 0.0).normalize()
+        }
+    }
+
+    /// 左dragでvolumeを回転する. `ndc`は領域内のcursorの正規化デバイス座標
+    pub fn drag(&mut self, rect: &glium::Rect, ndc: [f32; 2]) {
+        let current = Self::arcball_point(rect, ndc);
+        if let Some(prev) = self.prev_arcball {
+            let rotation = cgmath::Quaternion::from_arc(prev, current, None);
+            self.rotation = (rotation * self.rotation).normalize();
+        }
+        self.prev_arcball = Some(current);
+    }
+
+    pub fn end_drag(&mut self) {
+        self.prev_arcball = None;
+    }
+
+    pub fn zoom(&mut self, scale: f32) {
+        self.zoom = (self.zoom * scale).clamp(MIN_ZOOM, MAX_ZOOM);
+    }
+
+    pub fn draw(
+        &self,
+        target: &mut glium::Frame,
+        rect: glium::Rect,
+        layers: &VolumeLayers,
+    ) -> Result<(), glium::DrawError> {
+        let shape = [
+            layers.image.get_width(),
+            layers.image.get_height().unwrap_or(1),
+            layers.image.get_depth().unwrap_or(1),
+        ];
+        // volumeの大きさ (mm) を最長辺が1になるように縮める
+        let longest = layers.size.iter().cloned().fold(f32::EPSILON, f32::max);
+        let extent = layers.size.map(|x| x / longest);
+        let max_voxels = shape.iter().cloned().max().unwrap_or(1).max(1);
+
+        let view_projection = self.projection_matrix(&rect) * cgmath::Matrix4::from(self.rotation);
+        let Some(inverse) = view_projection.inverse_transform() else {
+            return Ok(());
+        };
+        let inverse_view_projection: [[f32; 4]; 4] = inverse.into();
+        let linear = glium::uniforms::SamplerBehavior {
+            minify_filter: glium::uniforms::MinifySamplerFilter::Linear,
+            magnify_filter: glium::uniforms::MagnifySamplerFilter::Linear,
+            ..Default::default()
+        };
+        let nearest = glium::uniforms::SamplerBehavior {
+            minify_filter: glium::uniforms::MinifySamplerFilter::Nearest,
+            magnify_filter: glium::uniforms::MagnifySamplerFilter::Nearest,
+            ..Default::default()
+        };
+        let (transfer_min, transfer_max) = self.transfer_function.range();
+        let uniforms = uniform! {
+            inverse_view_projection: inverse_view_projection,
+            extent: extent,
+            step_size: 1.0 / max_voxels as f32,
+            voxel_size: shape.map(|x| 1.0 / x.max(1) as f32),
+            tex: glium::uniforms::Sampler(layers.image, linear),
+            transfer: glium::uniforms::Sampler(&self.transfer_texture, linear),
+            transfer_min: transfer_min,
+            transfer_max: transfer_max,
+            mip: self.preset == Preset::Mip,
+            window_width: layers.window_width,
+            window_level: layers.window_level,
+            has_mask: layers.mask.is_some(),
+            mask: glium::uniforms::Sampler(layers.mask.unwrap_or(layers.image), nearest),
+            use_mask_lut: layers.mask_lut.is_some(),
+            mask_lut: glium::uniforms::Sampler(
+                layers.mask_lut.unwrap_or(&self.transfer_texture),
+                nearest,
+            ),
+            mask_texture_transform: layers.mask_texture_transform,
+        };
+        target.clear(Some(&rect), Some((0.0, 0.0, 0.0, 1.0)), false, None, None);
+        target.draw(
+            &self.vertex_buffer,
+            self.indices,
+            &self.program,
+            &uniforms,
+            &glium::DrawParameters {
+                viewport: Some(rect),
+                ..Default::default()
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_color(actual: [f32; 4], expected: [f32; 4]) {
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!((a - e).abs() < 1e-5, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn transfer_function_interpolates_and_clamps() {
+        let function = TransferFunction {
+            points: vec![
+                (-100.0, [0.0, 0.0, 0.0, 0.0]),
+                (100.0, [1.0, 0.5, 0.0, 0.2]),
+                (300.0, [1.0, 1.0, 1.0, 1.0]),
+            ],
+        };
+        assert_eq!(function.range(), (-100.0, 300.0));
+        assert_color(function.sample(0.0), [0.5, 0.25, 0.0, 0.1]);
+        assert_color(function.sample(100.0), [1.0, 0.5, 0.0, 0.2]);
+        assert_color(function.sample(250.0), [1.0, 0.875, 0.75, 0.8]);
+        // 範囲の外側は端の点の色
+        assert_color(function.sample(-1000.0), [0.0, 0.0, 0.0, 0.0]);
+        assert_color(function.sample(1000.0), [1.0, 1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn transfer_function_duplicate_values_make_a_step() {
+        let function = TransferFunction {
+            points: vec![
+                (0.0, [0.0, 0.0, 0.0, 0.0]),
+                (10.0, [0.2, 0.2, 0.2, 0.2]),
+                (10.0, [1.0, 0.0, 0.0, 1.0]),
+                (20.0, [0.0, 0.0, 1.0, 1.0]),
+            ],
+        };
+        assert_color(function.sample(5.0), [0.1, 0.1, 0.1, 0.1]);
+        // 同じ値の点では手前の区間の色, 少しでも超えると次の区間の色になる
+        assert_color(function.sample(10.0), [0.2, 0.2, 0.2, 0.2]);
+        assert_color(function.sample(10.001), [0.9999, 0.0, 0.0001, 1.0]);
+        assert_color(function.sample(15.0), [0.5, 0.0, 0.5, 1.0]);
+    }
+
+    #[test]
+    fn empty_transfer_function() {
+        let function = TransferFunction { points: vec![] };
+        assert_eq!(function.range(), (0.0, 1.0));
+        assert_eq!(function.sample(0.5), [0.0; 4]);
+    }
+}